@@ -0,0 +1,277 @@
+//! Tracking clicks on shares, and when shares with an idle ttl were last followed. Both are held in memory and written
+//! to the database in batches on a timer, so that a busy link does not cost a write on every click. The webhook
+//! deliveries announcing clicks are queued in the same batches.
+use crate::common::get_time_seconds;
+use crate::config::Config;
+use crate::database::{self, Click, DatabaseError, SharesDbConn};
+use crate::events::ShareEvent;
+use crate::fallback::Unavailable;
+use crate::url_id::UrlID;
+use crate::webhooks;
+use rocket::fairing::{self, Fairing, Info, Kind};
+use rocket::tokio::{self, time::Duration};
+use rocket::{Build, Orbit, Rocket, Shutdown};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// How often accesses are written to the database if `access_flush_interval` is not configured, in seconds.
+const DEFAULT_FLUSH_INTERVAL_SECONDS: i64 = 60;
+
+/// How often pending clicks and accesses are written to the database, in seconds. With an interval of 0 they are
+/// written as they happen.
+pub fn flush_interval(config: Option<&Config>) -> i64 {
+    config
+        .and_then(|c| c.access_flush_interval)
+        .map(|t| t.0)
+        .unwrap_or(DEFAULT_FLUSH_INTERVAL_SECONDS)
+}
+
+/// Everything waiting to be written to the database.
+#[derive(Default)]
+struct Pending {
+    accesses: HashMap<i64, i64>,
+    clicks: Vec<Click>,
+    events: Vec<ShareEvent>,
+}
+
+/// The clicks and last access times of shares which have not yet been written to the database. Clones share the same
+/// pending writes.
+#[derive(Clone)]
+pub struct AccessTracker {
+    pending: Arc<Mutex<Pending>>,
+    interval: i64,
+}
+
+impl AccessTracker {
+    pub fn new(interval: i64) -> Self {
+        AccessTracker {
+            pending: Arc::new(Mutex::new(Pending::default())),
+            interval,
+        }
+    }
+
+    /// Record that a share was followed at `now`.
+    pub fn record(&self, id: i64, now: i64) {
+        let mut pending = self.pending.lock().unwrap();
+        let last = pending.accesses.entry(id).or_insert(now);
+        *last = (*last).max(now);
+    }
+
+    /// Record a click on a share, and the event to send to webhooks subscribed to it.
+    pub fn record_click(
+        &self,
+        share: &UrlID,
+        variant: Option<usize>,
+        fallback: Option<Unavailable>,
+        event: ShareEvent,
+    ) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.clicks.push(Click {
+            share_id: *share.get_id(),
+            ts: get_time_seconds(),
+            variant,
+            fallback,
+        });
+        pending.events.push(event);
+    }
+
+    /// Bring the last access time of a share up to date with any access not yet written to the database.
+    pub fn refresh(&self, share: UrlID) -> UrlID {
+        match self.pending.lock().unwrap().accesses.get(share.get_id()) {
+            Some(last) => share.touch(*last),
+            None => share,
+        }
+    }
+
+    /// Count the clicks which were redirected to a share's destination, including those not yet written to the
+    /// database.
+    pub async fn count_redirects(
+        &self,
+        conn: &SharesDbConn,
+        share_id: i64,
+    ) -> Result<i64, DatabaseError> {
+        let pending = self
+            .pending
+            .lock()
+            .unwrap()
+            .clicks
+            .iter()
+            .filter(|c| c.share_id == share_id && c.fallback.is_none())
+            .count() as i64;
+        Ok(database::count_redirects(conn, share_id).await? + pending)
+    }
+
+    /// Write pending clicks and accesses immediately if no flush interval is configured. Otherwise they are left to
+    /// the timer.
+    pub async fn flush_if_immediate(&self, conn: &SharesDbConn) -> Result<(), DatabaseError> {
+        match self.interval {
+            0 => self.flush(conn).await,
+            _ => Ok(()),
+        }
+    }
+
+    /// Write every pending click and access to the database, then queue webhook deliveries for the clicks.
+    pub async fn flush(&self, conn: &SharesDbConn) -> Result<(), DatabaseError> {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        if !pending.clicks.is_empty() {
+            database::record_clicks(conn, pending.clicks).await?;
+        }
+        if !pending.accesses.is_empty() {
+            database::record_accesses(conn, pending.accesses).await?;
+        }
+        webhooks::emit_all(conn, &pending.events).await;
+        Ok(())
+    }
+}
+
+/// Write pending clicks and accesses every `interval` seconds until shutdown.
+async fn run(tracker: AccessTracker, conn: SharesDbConn, mut shutdown: Shutdown) {
+    let interval = Duration::from_secs(tracker.interval as u64);
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {}
+            _ = &mut shutdown => return,
+        }
+        if let Err(e) = tracker.flush(&conn).await {
+            tracing::error!(error = %e, "failed to record share clicks and accesses");
+        }
+    }
+}
+
+/// Manages the [`AccessTracker`], writes pending clicks and accesses to the database on a timer once the server has
+/// launched, and writes any left on shutdown.
+pub struct AccessFairing;
+
+#[rocket::async_trait]
+impl Fairing for AccessFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Access Tracker",
+            kind: Kind::Ignite | Kind::Liftoff | Kind::Shutdown,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        let interval = flush_interval(rocket.state::<Config>());
+        Ok(rocket.manage(AccessTracker::new(interval)))
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let tracker = match rocket.state::<AccessTracker>() {
+            Some(tracker) if tracker.interval > 0 => tracker.clone(),
+            _ => return,
+        };
+        let conn = match SharesDbConn::get_one(rocket).await {
+            Some(conn) => conn,
+            None => {
+                tracing::error!(
+                    "failed to start recording share clicks, no database connection available"
+                );
+                return;
+            }
+        };
+        tokio::spawn(run(tracker, conn, rocket.shutdown()));
+    }
+
+    async fn on_shutdown(&self, rocket: &Rocket<Orbit>) {
+        let (tracker, conn) = match (
+            rocket.state::<AccessTracker>(),
+            SharesDbConn::get_one(rocket).await,
+        ) {
+            (Some(tracker), Some(conn)) => (tracker, conn),
+            _ => return,
+        };
+        if let Err(e) = tracker.flush(&conn).await {
+            tracing::error!(error = %e, "failed to record share clicks and accesses on shutdown");
+        }
+    }
+}
+
+#[rocket::async_test]
+async fn test_clicks_are_batched() {
+    use rocket::http::ContentType;
+    let (client, dir) =
+        crate::test_async_client_with(|f| f.merge(("access_flush_interval", 1))).await;
+    let link = client
+        .post("/shorten")
+        .header(ContentType::JSON)
+        .body(r#"{"url":"https://a.example","max_clicks":2,"fallback_url":"https://b.example"}"#)
+        .dispatch()
+        .await
+        .into_string()
+        .await
+        .unwrap();
+    let token = link.rsplit('/').next().unwrap().to_owned();
+    let mut locations = Vec::new();
+    for _ in 0..3 {
+        let res = client.get(format!("/{}", token)).dispatch().await;
+        locations.push(res.headers().get_one("Location").unwrap().to_owned());
+    }
+    assert_eq!(
+        locations,
+        [
+            "https://a.example",
+            "https://a.example",
+            "https://b.example"
+        ]
+    );
+
+    let c =
+        rocket_sync_db_pools::rusqlite::Connection::open(dir.path().join("database.db")).unwrap();
+    let count = || -> i64 {
+        c.query_row("SELECT COUNT(*) FROM clicks;", [], |row| row.get(0))
+            .unwrap()
+    };
+    assert_eq!(count(), 0);
+    // The timer writes them within about a second, allow it plenty more on a loaded machine.
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while count() < 3 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("the clicks are written by the timer");
+    assert_eq!(count(), 3);
+}
+
+#[test]
+fn test_sliding_expiry() {
+    use rocket::http::{ContentType, Status};
+    let (client, dir) = crate::test_client_with(|f| f.merge(("access_flush_interval", 0)));
+    let link = client
+        .post("/shorten")
+        .header(ContentType::JSON)
+        .body(r#"{"url":"https://a.example","idle_ttl":"30d"}"#)
+        .dispatch()
+        .into_string()
+        .unwrap();
+    let token = link.rsplit('/').next().unwrap().to_owned();
+    let c =
+        rocket_sync_db_pools::rusqlite::Connection::open(dir.path().join("database.db")).unwrap();
+    let long_ago = crate::common::get_time_seconds() - 31 * 86400;
+    let age = |t: i64| {
+        c.execute(
+            "UPDATE shares SET crt = ?1, last_access = ?1;",
+            rocket_sync_db_pools::rusqlite::params![t],
+        )
+        .unwrap()
+    };
+
+    age(long_ago + 2 * 86400);
+    assert_eq!(
+        client.get(format!("/{}", token)).dispatch().status(),
+        Status::SeeOther
+    );
+    let last_access: i64 = c
+        .query_row("SELECT last_access FROM shares;", [], |row| row.get(0))
+        .unwrap();
+    assert!(last_access > long_ago + 30 * 86400);
+
+    age(long_ago);
+    assert_eq!(
+        client.get(format!("/{}", token)).dispatch().status(),
+        Status::NotFound
+    );
+    let metrics = client.get("/metrics").dispatch().into_string().unwrap();
+    assert!(metrics.contains("url_shortener_shares_active 0"));
+}
@@ -0,0 +1,161 @@
+//! Endpoints for operators of the server, mounted under `/admin` and guarded by the admin key.
+use crate::audit::{self, Action, Actor, AuditEntry, AuditQuery};
+use crate::auth::Admin;
+use crate::common::get_time_seconds;
+use crate::database::SharesDbConn;
+use crate::domains::RequestDomain;
+use crate::expiry;
+use crate::transfer::{self, Conflict, Format, ImportReport};
+use crate::url_id::token_to_number;
+use rocket::data::{Data, Limits, ToByteUnit};
+use rocket::http::{ContentType, Status};
+use rocket::serde::json::Json;
+use std::net::IpAddr;
+
+/// How many audit log entries are listed if no limit is given.
+const DEFAULT_AUDIT_PAGE_SIZE: i64 = 50;
+
+/// The most audit log entries which may be listed at once, larger limits are reduced to this.
+const MAX_AUDIT_PAGE_SIZE: i64 = 500;
+
+/// Export every share in the database.
+/// ```
+/// GET /admin/export?format=<jsonl|csv>
+/// ```
+#[get("/export?<format>")]
+async fn export_shares(
+    _admin: Admin,
+    format: Format,
+    conn: SharesDbConn,
+) -> Result<(ContentType, String), (Status, String)> {
+    let body = transfer::export(&conn, format).await?;
+    Ok((format.content_type(), body))
+}
+
+/// Import shares exported from this or another instance, preserving their ids.
+/// ```
+/// POST /admin/import?format=<jsonl|csv>&conflict=<skip|overwrite|fail>
+/// ```
+#[post("/import?<format>&<conflict>", data = "<data>")]
+async fn import_shares(
+    admin: Admin,
+    ip: Option<IpAddr>,
+    format: Format,
+    conflict: Option<Conflict>,
+    data: Data<'_>,
+    limits: &Limits,
+    conn: SharesDbConn,
+) -> Result<Json<ImportReport>, (Status, String)> {
+    let limit = limits.get("import").unwrap_or_else(|| 16.mebibytes());
+    let input = match data.open(limit).into_string().await {
+        Ok(s) if s.is_complete() => s.into_inner(),
+        Ok(_) => return Err((Status::PayloadTooLarge, "import too large".into())),
+        Err(e) => return Err((Status::InternalServerError, e.to_string())),
+    };
+    let conflict = conflict.unwrap_or(Conflict::Fail);
+    let actor = Actor::new(Some(&admin.0), ip);
+    let report = transfer::import(&conn, &input, format, conflict, actor).await?;
+    Ok(Json(report))
+}
+
+/// List changes made to shares, newest first. Every filter is optional, and `since` and `until` accept the same
+/// formats as `exp` when creating a share, and `token` is looked up on the domain the request is made to. Pass the id
+/// of the last entry listed as `before` to read further back.
+/// ```
+/// GET /admin/audit?token=<token>&actor=<owner>&action=<create|update|delete|disable|enable|revert|restore|purge>
+///     &since=<time>&until=<time>&before=<id>&limit=<1-500, default 50>
+/// ```
+#[get("/audit?<token>&<actor>&<action>&<since>&<until>&<before>&<limit>")]
+#[allow(clippy::too_many_arguments)]
+async fn audit_log(
+    _admin: Admin,
+    token: Option<String>,
+    actor: Option<String>,
+    action: Option<Action>,
+    since: Option<String>,
+    until: Option<String>,
+    before: Option<i64>,
+    limit: Option<i64>,
+    host: RequestDomain,
+    conn: SharesDbConn,
+) -> Result<Json<Vec<AuditEntry>>, (Status, String)> {
+    let now = get_time_seconds();
+    let time = |input: &str| expiry::parse_time(input, now).map_err(|e| (Status::BadRequest, e));
+    let token = match token {
+        Some(token) => Some(
+            token_to_number(&token)
+                .map(|n| (host.0, n))
+                .ok_or_else(|| {
+                    (
+                        Status::BadRequest,
+                        format!("'{}' is not a valid token", token),
+                    )
+                })?,
+        ),
+        None => None,
+    };
+    let query = AuditQuery {
+        token,
+        actor,
+        action,
+        since: since.as_deref().map(time).transpose()?,
+        until: until.as_deref().map(time).transpose()?,
+        before_id: before,
+    };
+    let limit = limit
+        .unwrap_or(DEFAULT_AUDIT_PAGE_SIZE)
+        .clamp(1, MAX_AUDIT_PAGE_SIZE);
+    Ok(Json(audit::find_entries(&conn, query, limit).await?))
+}
+
+/// All of the admin routes, to be mounted under `/admin`.
+pub fn routes() -> Vec<rocket::Route> {
+    routes![export_shares, import_shares, audit_log]
+}
+
+#[test]
+fn test_import_conflict_policies() {
+    use rocket::http::Header;
+    let (client, _dir) = crate::test_client();
+    let auth = || Header::new("Authorization", "Bearer admin");
+    let input = "{\"id\":5,\"exp\":100,\"crt\":1,\"url\":\"https://a.example\"}\n\
+                 {\"id\":9,\"exp\":200,\"crt\":2,\"url\":\"https://b.example\"}\n";
+    let import = |conflict: &str| {
+        client
+            .post(format!("/admin/import?format=jsonl&conflict={}", conflict))
+            .header(auth())
+            .body(input)
+            .dispatch()
+    };
+
+    let res = import("fail");
+    assert_eq!(res.status(), Status::Ok);
+    assert_eq!(
+        res.into_string().unwrap(),
+        r#"{"inserted":2,"overwritten":0,"skipped":0}"#
+    );
+    assert_eq!(import("fail").status(), Status::Conflict);
+    assert!(import("skip")
+        .into_string()
+        .unwrap()
+        .contains(r#""skipped":2"#));
+    assert!(import("overwrite")
+        .into_string()
+        .unwrap()
+        .contains(r#""overwritten":2"#));
+
+    let csv = client
+        .get("/admin/export?format=csv")
+        .header(auth())
+        .dispatch()
+        .into_string()
+        .unwrap();
+    let exported = transfer::decode(&csv, Format::Csv).unwrap();
+    assert_eq!(exported.len(), 2);
+    assert_eq!(exported[0].get_dest_url(), "https://a.example");
+    assert_eq!(exported[1].get_exp(), Some(200));
+    assert_eq!(
+        client.get("/admin/export?format=csv").dispatch().status(),
+        Status::Unauthorized
+    );
+}
@@ -0,0 +1,1009 @@
+//! The json api used to manage and inspect shares, mounted under `/api` and authenticated with an api key. Owners may
+//! act on their own shares, while shares in a workspace may be read by its viewers and changed by its editors.
+use crate::access::AccessTracker;
+use crate::audit::Actor;
+use crate::auth::Principal;
+use crate::common::get_time_seconds;
+use crate::config::Config;
+use crate::database::{self, Cursor, Order, Search, SharesDbConn, SortBy};
+use crate::domains::RequestDomain;
+use crate::events::{Event, EventBus, ShareEvent};
+use crate::expiry;
+use crate::history::{self, ShareVersion};
+use crate::rules::Rules;
+use crate::search;
+use crate::tags::Tags;
+use crate::url_id::{self, token_to_number, UrlID};
+use crate::workspaces::Role;
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::State;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+/// Find the share a token refers to on the domain the request was made to, provided the principal holds the `required`
+/// role over it. Shares in the trash are reported missing. Shares the principal may not act on are reported as
+/// forbidden rather than missing, as tokens are not secret.
+async fn find_managed(
+    conn: &SharesDbConn,
+    principal: &Principal,
+    host: RequestDomain,
+    token: &str,
+    required: Role,
+) -> Result<UrlID, (Status, String)> {
+    match find_managed_or_trashed(conn, principal, host, token, required).await? {
+        share if share.is_deleted() => Err(not_found(token)),
+        share => Ok(share),
+    }
+}
+
+/// As [`find_managed`], but also finding shares in the trash.
+async fn find_managed_or_trashed(
+    conn: &SharesDbConn,
+    principal: &Principal,
+    host: RequestDomain,
+    token: &str,
+    required: Role,
+) -> Result<UrlID, (Status, String)> {
+    let number = token_to_number(token).ok_or_else(|| not_found(token))?;
+    let share = Search::Token(host.0, number)
+        .find_share(conn)
+        .await?
+        .ok_or_else(|| not_found(token))?;
+    if !principal.permits(&share, required) {
+        return Err((
+            Status::Forbidden,
+            "the provided bearer token lacks permission".into(),
+        ));
+    }
+    Ok(share)
+}
+
+fn not_found(token: &str) -> (Status, String) {
+    (Status::NotFound, format!("no share exists for '{}'", token))
+}
+
+/// Deserialize a field which may be explicitly cleared, distinguishing an absent field (None) from null (Some(None)).
+/// Use alongside `#[serde(default)]`.
+fn deserialize_nullable<'de, D, T>(d: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(d).map(Some)
+}
+
+/// The changes to make to a share. Absent fields are left as they are, and a null optional field clears it.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ShareUpdate {
+    #[serde(default)]
+    url: Option<String>,
+    #[serde(default, deserialize_with = "expiry::deserialize_time_update")]
+    exp: Option<Option<i64>>,
+    #[serde(default, deserialize_with = "expiry::deserialize_time_update")]
+    nbf: Option<Option<i64>>,
+    #[serde(default, deserialize_with = "expiry::deserialize_duration_update")]
+    idle_ttl: Option<Option<i64>>,
+    #[serde(default, deserialize_with = "deserialize_nullable")]
+    fallback_url: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_nullable")]
+    max_clicks: Option<Option<i64>>,
+    #[serde(default)]
+    disabled: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_nullable")]
+    title: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_nullable")]
+    notes: Option<Option<String>>,
+    #[serde(default)]
+    tags: Option<Tags>,
+}
+
+/// Update a share the caller may edit, returning the updated share.
+/// ```JSON
+/// PATCH /api/shares/<token>
+/// {
+///     url: String (optional)
+///     exp: Integer | String | null (optional, in the same formats as when creating a share, null to never expire)
+///     nbf: Integer | String | null (optional, null to resolve immediately)
+///     idle_ttl: Integer | String | null (optional, null to stop the expiry sliding)
+///     fallback_url: String | null (optional, null to use the server's default fallback)
+///     max_clicks: Integer | null (optional, null to allow unlimited clicks)
+///     disabled: Boolean (optional, disabled links redirect to their fallback)
+///     title: String | null (optional)
+///     notes: String | null (optional)
+///     tags: [String] (optional, replaces every tag of the share)
+/// }
+/// ```
+#[patch("/shares/<token>", data = "<update>")]
+#[allow(clippy::too_many_arguments)]
+async fn update_share(
+    token: &str,
+    host: RequestDomain,
+    update: Json<ShareUpdate>,
+    principal: Principal,
+    ip: Option<IpAddr>,
+    config: &State<Config>,
+    events: &State<EventBus>,
+    conn: SharesDbConn,
+) -> Result<Json<UrlID>, (Status, String)> {
+    let mut share = find_managed(&conn, &principal, host, token, Role::Editor).await?;
+    let update = update.into_inner();
+    if let Some(url) = update.url {
+        share = share.set_url(url);
+    }
+    if let Some(exp) = update.exp {
+        expiry::check_max_ttl(exp, get_time_seconds(), config)?;
+        share = share.set_exp(exp);
+    }
+    if let Some(nbf) = update.nbf {
+        share = share.set_nbf(nbf);
+    }
+    if let Some(idle_ttl) = update.idle_ttl {
+        share = share.set_idle_ttl(idle_ttl);
+    }
+    if let Some(fallback_url) = update.fallback_url {
+        share = share.set_fallback_url(fallback_url);
+    }
+    if let Some(max_clicks) = update.max_clicks {
+        share = share.set_max_clicks(max_clicks);
+    }
+    if let Some(disabled) = update.disabled {
+        share = share.set_disabled(disabled);
+    }
+    if let Some(title) = update.title {
+        share = share.set_title(title);
+    }
+    if let Some(notes) = update.notes {
+        share = share.set_notes(notes);
+    }
+    if let Some(tags) = update.tags {
+        share = share.set_tags(tags);
+    }
+    share.validate()?;
+    let actor = Actor::new(Some(&principal), ip);
+    let share = database::update_database(&conn, Search::Id(*share.get_id()), share, actor).await?;
+    let event = ShareEvent::new(Event::Updated, &share, serde_json::json!({}));
+    events.publish(&conn, event).await;
+    Ok(Json(share))
+}
+
+/// Move a share the caller may edit to the trash. It responds 410 until restored, and is permanently removed once
+/// it has been in the trash for longer than the server's `trash_retention`.
+/// ```
+/// DELETE /api/shares/<token>
+/// ```
+#[delete("/shares/<token>")]
+async fn delete_share(
+    token: &str,
+    host: RequestDomain,
+    principal: Principal,
+    ip: Option<IpAddr>,
+    events: &State<EventBus>,
+    conn: SharesDbConn,
+) -> Result<Status, (Status, String)> {
+    let share = find_managed(&conn, &principal, host, token, Role::Editor).await?;
+    let actor = Actor::new(Some(&principal), ip);
+    let share = database::remove_from_database(&conn, Search::Id(*share.get_id()), actor).await?;
+    let event = ShareEvent::new(Event::Deleted, &share, serde_json::json!({}));
+    events.publish(&conn, event).await;
+    Ok(Status::NoContent)
+}
+
+/// Take a share the caller may edit back out of the trash, returning the restored share.
+/// ```
+/// POST /api/shares/<token>/restore
+/// ```
+#[post("/shares/<token>/restore")]
+async fn restore_share(
+    token: &str,
+    host: RequestDomain,
+    principal: Principal,
+    ip: Option<IpAddr>,
+    events: &State<EventBus>,
+    conn: SharesDbConn,
+) -> Result<Json<UrlID>, (Status, String)> {
+    let share = find_managed_or_trashed(&conn, &principal, host, token, Role::Editor).await?;
+    if !share.is_deleted() {
+        return Err((
+            Status::Conflict,
+            format!("the share for '{}' is not in the trash", token),
+        ));
+    }
+    let actor = Actor::new(Some(&principal), ip);
+    let share = database::restore_share(&conn, Search::Id(*share.get_id()), actor).await?;
+    let event = ShareEvent::new(Event::Restored, &share, serde_json::json!({}));
+    events.publish(&conn, event).await;
+    Ok(Json(share))
+}
+
+/// List every version of a share the caller may view, newest first.
+/// ```
+/// GET /api/shares/<token>/history
+/// ```
+#[get("/shares/<token>/history")]
+async fn share_history(
+    token: &str,
+    host: RequestDomain,
+    principal: Principal,
+    conn: SharesDbConn,
+) -> Result<Json<Vec<ShareVersion>>, (Status, String)> {
+    let share = find_managed(&conn, &principal, host, token, Role::Viewer).await?;
+    let versions = history::find_versions(&conn, *share.get_id()).await?;
+    Ok(Json(versions))
+}
+
+/// Revert a share the caller may edit to the destination and settings it had at an earlier version, returning the
+/// reverted share. The revert is itself recorded as a new version. Versions whose expiry lies beyond the server's
+/// `max_ttl` may not be reverted to.
+/// ```
+/// POST /api/shares/<token>/history/<version>/revert
+/// ```
+#[post("/shares/<token>/history/<version>/revert")]
+#[allow(clippy::too_many_arguments)]
+async fn revert_share(
+    token: &str,
+    host: RequestDomain,
+    version: i64,
+    principal: Principal,
+    ip: Option<IpAddr>,
+    config: &State<Config>,
+    events: &State<EventBus>,
+    conn: SharesDbConn,
+) -> Result<Json<UrlID>, (Status, String)> {
+    let share = find_managed(&conn, &principal, host, token, Role::Editor).await?;
+    let earlier = history::find_version(&conn, *share.get_id(), version)
+        .await?
+        .ok_or_else(|| {
+            (
+                Status::NotFound,
+                format!("the share for '{}' has no version {}", token, version),
+            )
+        })?;
+    let share = share.with_settings_of(earlier.share().clone());
+    share.validate()?;
+    expiry::check_max_ttl(share.get_exp(), get_time_seconds(), config)?;
+    let actor = Actor::new(Some(&principal), ip);
+    let id = *share.get_id();
+    let share = database::revert_to_version(&conn, Search::Id(id), share, version, actor).await?;
+    let event = ShareEvent::new(Event::Updated, &share, serde_json::json!({}));
+    events.publish(&conn, event).await;
+    Ok(Json(share))
+}
+
+/// The clicks a single variant of a split share has received.
+#[derive(Debug, Serialize)]
+struct VariantStats {
+    url: String,
+    weight: u32,
+    clicks: i64,
+}
+
+/// The clicks a share has received, broken down by variant if the share has several destinations.
+/// Clicks sent to the share's fallback are counted separately, by why the share was unavailable.
+#[derive(Debug, Serialize)]
+struct ShareStats {
+    clicks: i64,
+    variants: Vec<VariantStats>,
+    fallbacks: std::collections::BTreeMap<String, i64>,
+}
+
+/// Get the click statistics of a share the caller may view. Clicks not yet written to the database are written first.
+/// ```
+/// GET /api/shares/<token>/stats
+/// ```
+#[get("/shares/<token>/stats")]
+async fn share_stats(
+    token: &str,
+    host: RequestDomain,
+    principal: Principal,
+    access: &State<AccessTracker>,
+    conn: SharesDbConn,
+) -> Result<Json<ShareStats>, (Status, String)> {
+    let share = find_managed(&conn, &principal, host, token, Role::Viewer).await?;
+    access.flush(&conn).await?;
+    let counts = database::count_clicks(&conn, *share.get_id()).await?;
+    let fallbacks = database::count_fallbacks(&conn, *share.get_id()).await?;
+    let clicks_for = |index: usize| {
+        counts
+            .iter()
+            .filter(|(v, _)| *v == Some(index as i64))
+            .map(|(_, n)| n)
+            .sum()
+    };
+    Ok(Json(ShareStats {
+        clicks: counts.iter().map(|(_, n)| n).sum(),
+        variants: share
+            .get_variants()
+            .iter()
+            .enumerate()
+            .map(|(i, v)| VariantStats {
+                url: v.url.clone(),
+                weight: v.weight,
+                clicks: clicks_for(i),
+            })
+            .collect(),
+        fallbacks: fallbacks.into_iter().collect(),
+    }))
+}
+
+/// Get the routing rules of a share the caller may view.
+/// ```
+/// GET /api/shares/<token>/rules
+/// ```
+#[get("/shares/<token>/rules")]
+async fn get_rules(
+    token: &str,
+    host: RequestDomain,
+    principal: Principal,
+    conn: SharesDbConn,
+) -> Result<Json<Rules>, (Status, String)> {
+    let share = find_managed(&conn, &principal, host, token, Role::Viewer).await?;
+    Ok(Json(share.get_rules().clone()))
+}
+
+/// Replace the routing rules of a share the caller may edit, returning the rules saved.
+/// Rules are validated before saving, and an empty list removes every rule.
+/// ```
+/// PUT /api/shares/<token>/rules
+/// [{ url: String, os, languages, referrers, schedule }]
+/// ```
+#[put("/shares/<token>/rules", data = "<rules>")]
+async fn put_rules(
+    token: &str,
+    host: RequestDomain,
+    rules: Json<Rules>,
+    principal: Principal,
+    ip: Option<IpAddr>,
+    events: &State<EventBus>,
+    conn: SharesDbConn,
+) -> Result<Json<Rules>, (Status, String)> {
+    let share = find_managed(&conn, &principal, host, token, Role::Editor).await?;
+    let rules = rules.into_inner();
+    url_id::validate_rules(
+        &rules,
+        share.get_passthrough_query() || share.get_passthrough_path(),
+    )?;
+    let share = share.set_rules(rules.clone());
+    let actor = Actor::new(Some(&principal), ip);
+    let share = database::update_database(&conn, Search::Id(*share.get_id()), share, actor).await?;
+    let event = ShareEvent::new(Event::Updated, &share, serde_json::json!({}));
+    events.publish(&conn, event).await;
+    Ok(Json(rules))
+}
+
+/// How many shares are listed at once if no limit is given.
+const DEFAULT_PAGE_SIZE: i64 = 50;
+
+/// The largest page of shares which may be requested at once, larger limits are reduced to this.
+const MAX_PAGE_SIZE: i64 = 500;
+
+/// Whether to list shares which are still active, those which have expired, or those in the trash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromFormField)]
+enum ShareStatus {
+    #[field(value = "active")]
+    Active,
+    #[field(value = "expired")]
+    Expired,
+    #[field(value = "deleted")]
+    Deleted,
+}
+
+/// The direction shares are listed in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, FromFormField)]
+enum Direction {
+    #[field(value = "asc")]
+    Asc,
+    #[default]
+    #[field(value = "desc")]
+    Desc,
+}
+
+/// The filters of a listing of shares.
+#[derive(Debug)]
+struct ShareQuery {
+    tag: Option<String>,
+    owner: Option<String>,
+    created_after: Option<String>,
+    created_before: Option<String>,
+    status: Option<ShareStatus>,
+    domain: Option<String>,
+    workspace: Option<i64>,
+}
+
+/// The shares a principal may see: those of `workspace` if one is given, which requires being a member of it,
+/// otherwise their own shares outside of any workspace, or every share for admins.
+fn visible_shares(
+    principal: &Principal,
+    workspace: Option<i64>,
+) -> Result<Vec<Search>, (Status, String)> {
+    match workspace {
+        Some(id) if principal.holds(id, Role::Viewer) => Ok(vec![Search::Workspace(Some(id))]),
+        Some(_) => Err((
+            Status::Forbidden,
+            "the provided bearer token lacks permission".into(),
+        )),
+        None if principal.admin => Ok(Vec::new()),
+        None => Ok(vec![
+            Search::Owner(principal.owner.clone()),
+            Search::Workspace(None),
+        ]),
+    }
+}
+
+impl ShareQuery {
+    /// Build the search for this listing. Callers other than admins only ever see their own shares, or those of a
+    /// workspace they are a member of, in which they may filter by any owner.
+    fn search(&self, principal: &Principal, now: i64) -> Result<Search, (Status, String)> {
+        let mut filters = visible_shares(principal, self.workspace)?;
+        match (&self.owner, principal.admin || self.workspace.is_some()) {
+            (Some(owner), true) => filters.push(Search::Owner(owner.clone())),
+            (Some(owner), false) if *owner != principal.owner => {
+                return Err((
+                    Status::Forbidden,
+                    "the provided bearer token lacks permission".into(),
+                ))
+            }
+            _ => {}
+        }
+        let time =
+            |input: &str| expiry::parse_time(input, now).map_err(|e| (Status::BadRequest, e));
+        if let Some(tag) = &self.tag {
+            filters.push(Search::Tag(tag.clone()));
+        }
+        if let Some(after) = &self.created_after {
+            filters.push(Search::CreatedAfter(time(after)?));
+        }
+        if let Some(before) = &self.created_before {
+            filters.push(Search::CreatedBefore(time(before)?));
+        }
+        match self.status {
+            Some(ShareStatus::Active) => filters.push(Search::Active(now)),
+            Some(ShareStatus::Expired) => filters.push(Search::Expired(now)),
+            Some(ShareStatus::Deleted) | None => {}
+        }
+        filters.push(Search::Deleted(self.status == Some(ShareStatus::Deleted)));
+        if let Some(domain) = &self.domain {
+            filters.push(Search::Domain(domain.clone()));
+        }
+        Ok(Search::All(filters))
+    }
+}
+
+/// A share as it appears in a listing, alongside its shortened link.
+#[derive(Debug, Serialize)]
+struct ListedShare {
+    link: String,
+    #[serde(flatten)]
+    share: UrlID,
+}
+
+/// A page of shares, with the cursor to pass to fetch the next page if there is one.
+#[derive(Debug, Serialize)]
+struct ShareListing {
+    shares: Vec<ListedShare>,
+    next_cursor: Option<String>,
+}
+
+/// List the caller's shares, the shares of a workspace they are a member of, or any shares for admins. Every filter is
+/// optional and they may be combined. `created_after` and `created_before` accept the same formats as `exp` when
+/// creating a share.
+/// ```
+/// GET /api/shares?workspace=<id>&tag=<tag>&owner=<owner>&created_after=<time>&created_before=<time>
+///     &status=<active|expired|deleted>&domain=<domain>&sort=<created|expires|destination>&order=<asc|desc>
+///     &limit=<1-500, default 50>&cursor=<next_cursor>
+/// ```
+#[get(
+    "/shares?<workspace>&<tag>&<owner>&<created_after>&<created_before>&<status>&<domain>&<sort>&<order>&<limit>&<cursor>"
+)]
+#[allow(clippy::too_many_arguments)]
+async fn list_shares(
+    workspace: Option<i64>,
+    tag: Option<String>,
+    owner: Option<String>,
+    created_after: Option<String>,
+    created_before: Option<String>,
+    status: Option<ShareStatus>,
+    domain: Option<String>,
+    sort: Option<SortBy>,
+    order: Option<Direction>,
+    limit: Option<i64>,
+    cursor: Option<String>,
+    principal: Principal,
+    conn: SharesDbConn,
+) -> Result<Json<ShareListing>, (Status, String)> {
+    let query = ShareQuery {
+        tag,
+        owner,
+        created_after,
+        created_before,
+        status,
+        domain,
+        workspace,
+    };
+    let search = query.search(&principal, get_time_seconds())?;
+    let cursor = match &cursor {
+        Some(c) => Some(
+            Cursor::decode(c).ok_or_else(|| (Status::BadRequest, "invalid cursor".to_string()))?,
+        ),
+        None => None,
+    };
+    let order = Order {
+        sort: sort.unwrap_or_default(),
+        descending: order.unwrap_or_default() == Direction::Desc,
+    };
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let (shares, next) = search.find_page(&conn, order, limit, cursor).await?;
+    Ok(Json(ShareListing {
+        shares: shares
+            .into_iter()
+            .map(|share| ListedShare {
+                link: share.get_shortened_link(),
+                share,
+            })
+            .collect(),
+        next_cursor: next.map(|c| c.encode()),
+    }))
+}
+
+/// Shares matching a search, best matches first.
+#[derive(Debug, Serialize)]
+struct SearchResults {
+    shares: Vec<ListedShare>,
+}
+
+/// Search the caller's shares, the shares of a workspace they are a member of, or any shares for admins, by the words
+/// in their destination, title, notes and tags. Every word must match, and words match any word they are the start of.
+/// ```
+/// GET /api/search?q=<text>&workspace=<id>&limit=<1-500, default 50>
+/// ```
+#[get("/search?<q>&<workspace>&<limit>")]
+async fn search_shares(
+    q: &str,
+    workspace: Option<i64>,
+    limit: Option<i64>,
+    principal: Principal,
+    conn: SharesDbConn,
+) -> Result<Json<SearchResults>, (Status, String)> {
+    let query = search::to_match_query(q).ok_or_else(|| {
+        (
+            Status::BadRequest,
+            "the search must contain at least one word".to_string(),
+        )
+    })?;
+    let mut filters = visible_shares(&principal, workspace)?;
+    filters.push(Search::Deleted(false));
+    let filter = Search::All(filters);
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let shares = search::find_matches(&conn, query, filter, limit).await?;
+    Ok(Json(SearchResults {
+        shares: shares
+            .into_iter()
+            .map(|share| ListedShare {
+                link: share.get_shortened_link(),
+                share,
+            })
+            .collect(),
+    }))
+}
+
+/// All of the api routes, to be mounted under `/api`.
+pub fn routes() -> Vec<rocket::Route> {
+    routes![
+        list_shares,
+        search_shares,
+        update_share,
+        delete_share,
+        restore_share,
+        share_history,
+        revert_share,
+        share_stats,
+        get_rules,
+        put_rules
+    ]
+}
+
+#[test]
+fn test_rules_routes() {
+    use rocket::http::{ContentType, Header};
+    let (client, dir) = crate::test_client();
+    let c =
+        rocket_sync_db_pools::rusqlite::Connection::open(dir.path().join("database.db")).unwrap();
+    crate::insert_api_keys(
+        &c,
+        &[("alice-key", "alice", false), ("bob-key", "bob", false)],
+    );
+    let link = client
+        .post("/shorten")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer alice-key"))
+        .body(r#"{"url":"https://a.example"}"#)
+        .dispatch()
+        .into_string()
+        .unwrap();
+    let token = link.rsplit('/').next().unwrap().to_owned();
+    let put = |key: &str, body: &str| {
+        client
+            .put(format!("/api/shares/{}/rules", token))
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", format!("Bearer {}", key)))
+            .body(body)
+            .dispatch()
+            .status()
+    };
+    let android = r#"[{"url":"https://a.example/android","os":["android"]}]"#;
+    assert_eq!(put("bob-key", android), Status::Forbidden);
+    assert_eq!(
+        put("alice-key", r#"[{"url":"https://a.example/x"}]"#),
+        Status::BadRequest
+    );
+    assert_eq!(put("alice-key", android), Status::Ok);
+
+    let follow = |ua: &str| {
+        let res = client
+            .get(format!("/{}", token))
+            .header(Header::new("User-Agent", ua.to_owned()))
+            .dispatch();
+        res.headers().get_one("Location").map(str::to_owned)
+    };
+    assert_eq!(
+        follow("Mozilla/5.0 (Linux; Android 13)").as_deref(),
+        Some("https://a.example/android")
+    );
+    assert_eq!(
+        follow("Mozilla/5.0 (Windows NT 10.0)").as_deref(),
+        Some("https://a.example")
+    );
+
+    let rules = client
+        .get(format!("/api/shares/{}/rules", token))
+        .header(Header::new("Authorization", "Bearer admin"))
+        .dispatch()
+        .into_string()
+        .unwrap();
+    assert_eq!(rules, android.replace(' ', ""));
+}
+
+/// Answer unknown api paths with an api error, rather than falling through to the share redirects.
+#[catch(404)]
+fn unknown_endpoint(req: &rocket::Request) -> (Status, String) {
+    (
+        Status::NotFound,
+        format!("no api endpoint exists at '{}'", req.uri()),
+    )
+}
+
+/// The api catchers, to be registered under `/api`.
+pub fn catchers() -> Vec<rocket::Catcher> {
+    catchers![unknown_endpoint]
+}
+
+#[test]
+fn test_unknown_endpoint() {
+    let (client, _dir) = crate::test_client();
+    for path in ["/api/unknown", "/api/shares/abcdefg/unknown"] {
+        let response = client.get(path).dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(
+            response.into_string().unwrap(),
+            format!("no api endpoint exists at '{}'", path)
+        );
+    }
+}
+
+#[test]
+fn test_update_share() {
+    use rocket::http::{ContentType, Header};
+    let (client, _dir) = crate::test_client_with(|f| f.merge(("max_ttl", "30d")));
+    let now = get_time_seconds();
+    let create = |body: &str| {
+        client
+            .post("/shorten")
+            .header(ContentType::JSON)
+            .body(body)
+            .dispatch()
+    };
+    let link = create(r#"{"url":"https://a.example"}"#)
+        .into_string()
+        .unwrap();
+    let token = link.rsplit('/').next().unwrap().to_owned();
+    assert_eq!(
+        create(r#"{"url":"https://a.example","exp":"P31D"}"#).status(),
+        Status::BadRequest
+    );
+
+    let update = |body: &str| {
+        client
+            .patch(format!("/api/shares/{}", token))
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", "Bearer admin"))
+            .body(body)
+            .dispatch()
+    };
+    let share: serde_json::Value = update(r#"{"exp":"7d","url":"https://b.example"}"#)
+        .into_json()
+        .unwrap();
+    let exp = share["exp"].as_i64().unwrap();
+    assert!((now + 7 * 86400..now + 7 * 86400 + 5).contains(&exp));
+    assert_eq!(share["url"], "https://b.example");
+    // The response is the share as stored.
+    let share: serde_json::Value = update(r#"{"tags":[" b ","a","a",""]}"#)
+        .into_json()
+        .unwrap();
+    assert_eq!(share["tags"], serde_json::json!(["a", "b"]));
+    let mut listing: serde_json::Value = client
+        .get("/api/shares")
+        .header(Header::new("Authorization", "Bearer admin"))
+        .dispatch()
+        .into_json()
+        .unwrap();
+    listing["shares"][0].as_object_mut().unwrap().remove("link");
+    assert_eq!(share, listing["shares"][0]);
+
+    assert_eq!(update(r#"{"exp":null}"#).status(), Status::BadRequest);
+    assert_eq!(update(r#"{"exp":"2001-01-01"}"#).status(), Status::Ok);
+    assert_eq!(
+        update(r#"{"nbf":"2001-02-01T00:00:00Z"}"#).status(),
+        Status::BadRequest
+    );
+    assert_eq!(
+        update(r#"{"exp":"soon"}"#).status(),
+        Status::UnprocessableEntity
+    );
+}
+
+#[test]
+fn test_list_shares() {
+    use rocket::http::{ContentType, Header};
+    let (client, dir) = crate::test_client();
+    let c =
+        rocket_sync_db_pools::rusqlite::Connection::open(dir.path().join("database.db")).unwrap();
+    crate::insert_api_keys(&c, &[("alice-key", "alice", false)]);
+    let create = |key: &str, body: &str| {
+        let link = client
+            .post("/shorten")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", format!("Bearer {}", key)))
+            .body(body)
+            .dispatch()
+            .into_string()
+            .unwrap();
+        link.rsplit('/').next().unwrap().to_owned()
+    };
+    let list = |key: &str, query: &str| -> serde_json::Value {
+        client
+            .get(format!("/api/shares?{}", query))
+            .header(Header::new("Authorization", format!("Bearer {}", key)))
+            .dispatch()
+            .into_json()
+            .unwrap()
+    };
+    let urls = |listing: &serde_json::Value| -> Vec<String> {
+        listing["shares"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|s| s["url"].as_str().unwrap().to_owned())
+            .collect()
+    };
+
+    create(
+        "alice-key",
+        r#"{"url":"https://b.example/1","tags":["launch"],"title":"Launch"}"#,
+    );
+    create(
+        "alice-key",
+        r#"{"url":"https://www.a.example/2","tags":["launch","beta"]}"#,
+    );
+    let old = create("alice-key", r#"{"url":"https://c.example/3","exp":1}"#);
+    create("admin", r#"{"url":"https://a.example/4"}"#);
+
+    let all = list("alice-key", "");
+    assert_eq!(
+        urls(&all),
+        [
+            "https://c.example/3",
+            "https://www.a.example/2",
+            "https://b.example/1"
+        ]
+    );
+    assert_eq!(all["shares"][2]["title"], "Launch");
+    assert_eq!(
+        all["shares"][1]["tags"],
+        serde_json::json!(["beta", "launch"])
+    );
+    // Tokens carry random padding after the delimiter, so only the id portion is stable.
+    let id_part = old.split(crate::url_id::DELIM_CHAR).next().unwrap();
+    assert!(all["shares"][0]["link"]
+        .as_str()
+        .unwrap()
+        .contains(&format!("/{}", id_part)));
+
+    assert_eq!(
+        urls(&list("alice-key", "tag=launch&sort=destination&order=asc")),
+        ["https://b.example/1", "https://www.a.example/2"]
+    );
+    assert_eq!(
+        urls(&list("alice-key", "status=expired")),
+        ["https://c.example/3"]
+    );
+    assert_eq!(
+        urls(&list(
+            "admin",
+            "domain=a.example&sort=destination&order=asc"
+        )),
+        ["https://a.example/4", "https://www.a.example/2"]
+    );
+    assert_eq!(
+        urls(&list("admin", "owner=alice&created_before=1")).len(),
+        0
+    );
+
+    let first = list("admin", "limit=3");
+    assert_eq!(urls(&first).len(), 3);
+    let cursor = first["next_cursor"].as_str().unwrap();
+    let second = list("admin", &format!("limit=3&cursor={}", cursor));
+    assert_eq!(urls(&second), ["https://b.example/1"]);
+    assert!(second["next_cursor"].is_null());
+
+    let res = client
+        .get("/api/shares?owner=admin")
+        .header(Header::new("Authorization", "Bearer alice-key"))
+        .dispatch();
+    assert_eq!(res.status(), Status::Forbidden);
+    let res = client
+        .get("/api/shares?cursor=nonsense")
+        .header(Header::new("Authorization", "Bearer admin"))
+        .dispatch();
+    assert_eq!(res.status(), Status::BadRequest);
+}
+
+#[test]
+fn test_search_shares() {
+    use rocket::http::{ContentType, Header};
+    let (client, dir) = crate::test_client();
+    let c =
+        rocket_sync_db_pools::rusqlite::Connection::open(dir.path().join("database.db")).unwrap();
+    crate::insert_api_keys(&c, &[("alice-key", "alice", false)]);
+    let create = |key: &str, body: &str| {
+        let link = client
+            .post("/shorten")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", format!("Bearer {}", key)))
+            .body(body)
+            .dispatch()
+            .into_string()
+            .unwrap();
+        link.rsplit('/').next().unwrap().to_owned()
+    };
+    let search = |key: &str, q: &str| -> Vec<String> {
+        let results: serde_json::Value = client
+            .get(format!("/api/search?q={}", q))
+            .header(Header::new("Authorization", format!("Bearer {}", key)))
+            .dispatch()
+            .into_json()
+            .unwrap();
+        results["shares"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|s| s["url"].as_str().unwrap().to_owned())
+            .collect()
+    };
+
+    create(
+        "alice-key",
+        r#"{"url":"https://docs.example/d/123","title":"Q3 planning doc"}"#,
+    );
+    create(
+        "alice-key",
+        r#"{"url":"https://docs.example/q3-planning-notes","tags":["archive"]}"#,
+    );
+    let token = create(
+        "alice-key",
+        r#"{"url":"https://wiki.example/roadmap","notes":"replaces the old planning page"}"#,
+    );
+    create("admin", r#"{"url":"https://admin.example/q3-planning"}"#);
+
+    assert_eq!(
+        search("alice-key", "q3%20plan"),
+        [
+            "https://docs.example/d/123",
+            "https://docs.example/q3-planning-notes"
+        ]
+    );
+    assert_eq!(
+        search("alice-key", "archive"),
+        ["https://docs.example/q3-planning-notes"]
+    );
+    assert_eq!(search("admin", "q3").len(), 3);
+
+    client
+        .patch(format!("/api/shares/{}", token))
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer alice-key"))
+        .body(r#"{"notes":null,"title":"Roadmap"}"#)
+        .dispatch();
+    assert_eq!(search("alice-key", "planning").len(), 2);
+    assert_eq!(
+        search("alice-key", "roadmap"),
+        ["https://wiki.example/roadmap"]
+    );
+
+    let res = client
+        .get("/api/search?q=%22%22")
+        .header(Header::new("Authorization", "Bearer alice-key"))
+        .dispatch();
+    assert_eq!(res.status(), Status::BadRequest);
+}
+
+#[test]
+fn test_soft_delete() {
+    use rocket::http::{ContentType, Header};
+    let (client, dir) = crate::test_client();
+    let c =
+        rocket_sync_db_pools::rusqlite::Connection::open(dir.path().join("database.db")).unwrap();
+    crate::insert_api_keys(
+        &c,
+        &[("alice-key", "alice", false), ("bob-key", "bob", false)],
+    );
+    let auth = |key: &str| Header::new("Authorization", format!("Bearer {}", key));
+    let link = client
+        .post("/shorten")
+        .header(ContentType::JSON)
+        .header(auth("alice-key"))
+        .body(r#"{"url":"https://a.example","title":"printed flyer"}"#)
+        .dispatch()
+        .into_string()
+        .unwrap();
+    let token = link.rsplit('/').next().unwrap().to_owned();
+    let listed = |status: &str| {
+        let listing: serde_json::Value = client
+            .get(format!("/api/shares?status={}", status))
+            .header(auth("alice-key"))
+            .dispatch()
+            .into_json()
+            .unwrap();
+        listing["shares"].as_array().unwrap().len()
+    };
+    let delete = |key: &str| {
+        client
+            .delete(format!("/api/shares/{}", token))
+            .header(auth(key))
+            .dispatch()
+            .status()
+    };
+
+    assert_eq!(delete("bob-key"), Status::Forbidden);
+    assert_eq!(delete("alice-key"), Status::NoContent);
+    assert_eq!(delete("alice-key"), Status::NotFound);
+    assert_eq!(
+        client.get(format!("/{}", token)).dispatch().status(),
+        Status::Gone
+    );
+    assert_eq!(listed("active"), 0);
+    assert_eq!(listed("deleted"), 1);
+    let found: serde_json::Value = client
+        .get("/api/search?q=flyer")
+        .header(auth("alice-key"))
+        .dispatch()
+        .into_json()
+        .unwrap();
+    assert_eq!(found["shares"].as_array().unwrap().len(), 0);
+
+    let res = client
+        .post(format!("/api/shares/{}/restore", token))
+        .header(auth("alice-key"))
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    assert_eq!(
+        client.get(format!("/{}", token)).dispatch().status(),
+        Status::SeeOther
+    );
+    assert_eq!(listed("deleted"), 0);
+    let res = client
+        .post(format!("/api/shares/{}/restore", token))
+        .header(auth("alice-key"))
+        .dispatch();
+    assert_eq!(res.status(), Status::Conflict);
+}
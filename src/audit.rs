@@ -0,0 +1,315 @@
+//! An append-only log of every change made to shares, recording who made it, from where, and the share before and
+//! after. Entries are written in the same transaction as the change they describe, and triggers refuse any attempt
+//! to alter or remove them.
+use crate::auth::Principal;
+use crate::database::{DatabaseError, FromDatabase, SharesDbConn};
+use crate::url_id::UrlID;
+use rocket_sync_db_pools::rusqlite::types::{ToSql, ToSqlOutput, Value};
+use rocket_sync_db_pools::rusqlite::{self, params};
+use serde::Serialize;
+use std::net::IpAddr;
+
+/// The kinds of change recorded in the audit log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, FromFormField)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    #[field(value = "create")]
+    Create,
+    #[field(value = "update")]
+    Update,
+    #[field(value = "delete")]
+    Delete,
+    /// An update disabling the share.
+    #[field(value = "disable")]
+    Disable,
+    /// An update enabling a disabled share.
+    #[field(value = "enable")]
+    Enable,
+    /// An update reverting the share to an earlier version.
+    #[field(value = "revert")]
+    Revert,
+    /// A share being taken back out of the trash.
+    #[field(value = "restore")]
+    Restore,
+    /// A share being permanently removed, after being deleted or expiring.
+    #[field(value = "purge")]
+    Purge,
+}
+
+impl Action {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Create => "create",
+            Action::Update => "update",
+            Action::Delete => "delete",
+            Action::Disable => "disable",
+            Action::Enable => "enable",
+            Action::Revert => "revert",
+            Action::Restore => "restore",
+            Action::Purge => "purge",
+        }
+    }
+
+    /// Classify a change from `before` to `after`, singling out changes to whether the share is disabled.
+    pub fn of_update(before: &UrlID, after: &UrlID) -> Action {
+        match (before.get_disabled(), after.get_disabled()) {
+            (false, true) => Action::Disable,
+            (true, false) => Action::Enable,
+            _ => Action::Update,
+        }
+    }
+}
+
+impl ToSql for Action {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+/// Who made a change, and from where.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Actor {
+    /// The id of the api key used, if one was.
+    pub key_id: Option<i64>,
+    /// The owner of the key used, `admin` for the configured admin key, or `cli` for the command line.
+    /// None for anonymous requests.
+    pub name: Option<String>,
+    pub ip: Option<IpAddr>,
+}
+
+impl Actor {
+    /// The actor behind a request, authenticated as `principal` if it gave an api key.
+    pub fn new(principal: Option<&Principal>, ip: Option<IpAddr>) -> Self {
+        Actor {
+            key_id: principal.and_then(|p| p.key_id),
+            name: principal.map(|p| p.owner.clone()),
+            ip,
+        }
+    }
+
+    /// The operator running the command line tool.
+    pub fn cli() -> Self {
+        Actor {
+            key_id: None,
+            name: Some("cli".into()),
+            ip: None,
+        }
+    }
+
+    /// The background job emptying the trash.
+    pub fn trash_purge() -> Self {
+        Actor {
+            key_id: None,
+            name: Some("trash-purge".into()),
+            ip: None,
+        }
+    }
+}
+
+/// Serialize a share as recorded in the log.
+fn snapshot(share: Option<&UrlID>) -> Result<Option<String>, rusqlite::Error> {
+    share
+        .map(serde_json::to_string)
+        .transpose()
+        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+}
+
+/// Append an entry to the audit log. `before` is None for shares being created, and `after` for those being deleted.
+pub fn record(
+    c: &rusqlite::Connection,
+    action: Action,
+    share_id: i64,
+    actor: &Actor,
+    before: Option<&UrlID>,
+    after: Option<&UrlID>,
+) -> Result<(), rusqlite::Error> {
+    c.prepare_cached(
+        "INSERT INTO audit_log (ts, action, share_id, actor_key, actor, ip, before, after)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8);",
+    )?
+    .execute(params![
+        crate::common::get_time_seconds(),
+        action,
+        share_id,
+        actor.key_id,
+        actor.name,
+        actor.ip.map(|ip| ip.to_string()),
+        snapshot(before)?,
+        snapshot(after)?
+    ])?;
+    Ok(())
+}
+
+/// An entry in the audit log.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEntry {
+    id: i64,
+    ts: i64,
+    action: String,
+    share_id: i64,
+    actor_key: Option<i64>,
+    actor: Option<String>,
+    ip: Option<String>,
+    before: Option<serde_json::Value>,
+    after: Option<serde_json::Value>,
+}
+
+impl FromDatabase for AuditEntry {
+    type Error = rusqlite::Error;
+    fn from_database(row: &rusqlite::Row<'_>) -> Result<AuditEntry, rusqlite::Error> {
+        let parse = |column: &str| -> Result<Option<serde_json::Value>, rusqlite::Error> {
+            let value: Option<String> = row.get(column)?;
+            Ok(value.and_then(|v| serde_json::from_str(&v).ok()))
+        };
+        Ok(AuditEntry {
+            id: row.get("id")?,
+            ts: row.get("ts")?,
+            action: row.get("action")?,
+            share_id: row.get("share_id")?,
+            actor_key: row.get("actor_key")?,
+            actor: row.get("actor")?,
+            ip: row.get("ip")?,
+            before: parse("before")?,
+            after: parse("after")?,
+        })
+    }
+}
+
+/// Which audit log entries to list. Every filter is optional.
+#[derive(Debug, Clone, Default)]
+pub struct AuditQuery {
+    /// The domain and token number of the one share to list entries for, which may have since been purged.
+    pub token: Option<(Option<String>, i64)>,
+    pub actor: Option<String>,
+    pub action: Option<Action>,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    /// Only list entries older than this entry, to page through the log.
+    pub before_id: Option<i64>,
+}
+
+/// List the entries matching a query, newest first.
+pub async fn find_entries(
+    conn: &SharesDbConn,
+    query: AuditQuery,
+    limit: i64,
+) -> Result<Vec<AuditEntry>, DatabaseError> {
+    let result = conn
+        .run_timed(move |c| {
+            let mut values: Vec<Value> = Vec::new();
+            let mut conditions = vec!["1".to_owned()];
+            if let Some((domain, token)) = query.token {
+                values.push(domain.unwrap_or_default().into());
+                values.push(token.into());
+                // Shares which have been purged are only found in the snapshot taken as they were purged. Those
+                // recorded before shares had a token of their own used their id.
+                conditions.push(format!(
+                    "share_id IN (
+                        SELECT id FROM shares WHERE COALESCE(domain, '') = ?{0} AND token = ?{1}
+                        UNION SELECT share_id FROM audit_log WHERE action = 'purge'
+                            AND COALESCE(json_extract(before, '$.domain'), '') = ?{0}
+                            AND COALESCE(NULLIF(json_extract(before, '$.token'), 0), share_id) = ?{1}
+                    )",
+                    values.len() - 1,
+                    values.len()
+                ));
+            }
+            let mut filter = |column: &str, op: &str, value: Value| {
+                values.push(value);
+                conditions.push(format!("{} {} ?{}", column, op, values.len()));
+            };
+            if let Some(actor) = query.actor {
+                filter("actor", "=", actor.into());
+            }
+            if let Some(action) = query.action {
+                filter("action", "=", action.as_str().to_owned().into());
+            }
+            if let Some(since) = query.since {
+                filter("ts", ">=", since.into());
+            }
+            if let Some(until) = query.until {
+                filter("ts", "<", until.into());
+            }
+            if let Some(id) = query.before_id {
+                filter("id", "<", id.into());
+            }
+            values.push(limit.into());
+            c.prepare(&format!(
+                "SELECT * FROM audit_log WHERE {} ORDER BY id DESC LIMIT ?{};",
+                conditions.join(" AND "),
+                values.len()
+            ))
+            .and_then(|mut res| {
+                res.query_map(
+                    rusqlite::params_from_iter(values),
+                    AuditEntry::from_database,
+                )?
+                .collect()
+            })
+        })
+        .await?;
+    Ok(result)
+}
+
+#[test]
+fn test_changes_are_audited() {
+    use rocket::http::{ContentType, Header, Status};
+    let (client, dir) = crate::test_client();
+    let c = rusqlite::Connection::open(dir.path().join("database.db")).unwrap();
+    crate::insert_api_keys(&c, &[("alice-key", "alice", false)]);
+    let alice = || Header::new("Authorization", "Bearer alice-key");
+    let link = client
+        .post("/shorten")
+        .header(ContentType::JSON)
+        .header(alice())
+        .remote("203.0.113.7:5000".parse().unwrap())
+        .body(r#"{"url":"https://a.example"}"#)
+        .dispatch()
+        .into_string()
+        .unwrap();
+    let token = link.rsplit('/').next().unwrap().to_owned();
+    for update in [r#"{"disabled":true}"#, r#"{"title":"Launch"}"#] {
+        let res = client
+            .patch(format!("/api/shares/{}", token))
+            .header(ContentType::JSON)
+            .header(alice())
+            .body(update)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+    }
+    let audit = |query: &str, key: &str| {
+        client
+            .get(format!("/admin/audit?{}", query))
+            .header(Header::new("Authorization", format!("Bearer {}", key)))
+            .dispatch()
+    };
+    assert_eq!(audit("", "alice-key").status(), Status::Forbidden);
+
+    let entries: serde_json::Value = audit(&format!("token={}", token), "admin")
+        .into_json()
+        .unwrap();
+    let actions: Vec<&str> = entries
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["action"].as_str().unwrap())
+        .collect();
+    assert_eq!(actions, ["update", "disable", "create"]);
+    assert_eq!(entries[2]["actor"], "alice");
+    assert_eq!(entries[2]["ip"], "203.0.113.7");
+    assert!(entries[2]["before"].is_null());
+    assert_eq!(entries[0]["before"]["title"], serde_json::Value::Null);
+    assert_eq!(entries[0]["after"]["title"], "Launch");
+
+    let entries: serde_json::Value = audit("action=disable&actor=alice", "admin")
+        .into_json()
+        .unwrap();
+    assert_eq!(entries.as_array().unwrap().len(), 1);
+    assert_eq!(entries[0]["before"]["disabled"], false);
+    assert_eq!(entries[0]["after"]["disabled"], true);
+
+    assert!(c.execute("DELETE FROM audit_log;", []).is_err());
+    assert!(c
+        .execute("UPDATE audit_log SET actor = 'mallory';", [])
+        .is_err());
+}
@@ -0,0 +1,240 @@
+//! Api keys, and the request guards used to restrict access to the management endpoints. Bearer JWTs are verified
+//! by [`crate::jwt`].
+use crate::config::Config;
+use crate::database::{self, FromDatabase, SharesDbConn};
+use crate::jwt::BearerJwt;
+use crate::url_id::UrlID;
+use crate::workspaces::{self, Role};
+use rand::Rng;
+use rocket::http::Status;
+use rocket::outcome::Outcome::*;
+use rocket::request::{self, FromRequest, Request};
+use rocket_sync_db_pools::rusqlite;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use subtle::ConstantTimeEq;
+
+/// The length of a newly generated api key, in chars.
+const API_KEY_LENGTH: usize = 40;
+
+/// An enum representing the reasons a request may fail authentication.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    Missing,
+    Invalid,
+    Forbidden,
+    InvalidJwt(String),
+    DatabaseError(String),
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AuthError::Missing => f.write_str("no bearer token was provided with the request"),
+            AuthError::Invalid => f.write_str("the provided bearer token is not valid"),
+            AuthError::Forbidden => f.write_str("the provided bearer token lacks permission"),
+            AuthError::InvalidJwt(e) => write!(f, "the provided bearer token is not valid: {}", e),
+            AuthError::DatabaseError(e) => f.write_str(e),
+        }
+    }
+}
+
+/// An api key, as stored in the database. Only a hash of the secret is kept, so the secret itself cannot be shown
+/// again once the key has been created.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ApiKey {
+    pub id: i64,
+    /// Who the key belongs to, recorded against any share created with it.
+    pub owner: String,
+    /// Whether the key may access the admin endpoints, and act on shares belonging to others.
+    pub admin: bool,
+    /// When the key was created.
+    pub crt: i64,
+}
+
+impl ApiKey {
+    /// Generate a new random secret for an api key.
+    pub fn generate_secret() -> String {
+        let mut rng = rand::thread_rng();
+        (0..API_KEY_LENGTH)
+            .map(|_| crate::url_id::ALPHABET[rng.gen_range(0..crate::url_id::ALPHABET.len())])
+            .collect()
+    }
+}
+
+/// A newly created api key, alongside the secret to present as a bearer token. This is the only time it is shown.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct NewApiKey {
+    #[serde(flatten)]
+    pub key: ApiKey,
+    pub secret: String,
+}
+
+/// Hash an api key's secret, as it is stored and looked up in the database.
+pub fn hash_key(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+impl FromDatabase for ApiKey {
+    type Error = rusqlite::Error;
+    fn from_database(row: &rusqlite::Row<'_>) -> Result<ApiKey, rusqlite::Error> {
+        Ok(ApiKey {
+            id: row.get("id")?,
+            owner: row.get("owner")?,
+            admin: row.get("admin")?,
+            crt: row.get("crt")?,
+        })
+    }
+}
+
+/// Extract the token from an `Authorization: Bearer <token>` header, if present.
+pub fn bearer_token<'r>(req: &'r Request<'_>) -> Option<&'r str> {
+    req.headers()
+        .get_one("Authorization")
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// The identity a request is acting as, established from its bearer token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal {
+    /// The id of the api key used, or None if the configured admin key or a JWT was used.
+    pub key_id: Option<i64>,
+    pub owner: String,
+    pub admin: bool,
+    /// The role held in each workspace the principal is a member of, by workspace id.
+    pub workspaces: BTreeMap<i64, Role>,
+}
+
+impl Principal {
+    /// The role this principal holds in a workspace, if any. Admins hold every role in every workspace.
+    pub fn role_in(&self, workspace: i64) -> Option<Role> {
+        match self.admin {
+            true => Some(Role::Admin),
+            false => self.workspaces.get(&workspace).copied(),
+        }
+    }
+
+    /// Whether this principal holds at least the `required` role in a workspace.
+    pub fn holds(&self, workspace: i64, required: Role) -> bool {
+        self.role_in(workspace).is_some_and(|role| role >= required)
+    }
+
+    /// Whether this principal may act on the given share with the `required` role. Shares in a workspace require that
+    /// role in the workspace, while owners may do anything with their other shares, as may admins with any share.
+    pub fn permits(&self, share: &UrlID, required: Role) -> bool {
+        match share.get_workspace() {
+            Some(workspace) => self.holds(workspace, required),
+            None => self.admin || share.get_owner() == Some(self.owner.as_str()),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Principal {
+    type Error = AuthError;
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let token = match bearer_token(req) {
+            Some(t) => t,
+            None => return Failure((Status::Unauthorized, AuthError::Missing)),
+        };
+        let admin_key = req
+            .rocket()
+            .state::<Config>()
+            .and_then(|c| c.admin_key.as_deref());
+        if admin_key.is_some_and(|k| bool::from(k.as_bytes().ct_eq(token.as_bytes()))) {
+            return Success(Principal {
+                key_id: None,
+                owner: "admin".into(),
+                admin: true,
+                workspaces: BTreeMap::new(),
+            });
+        }
+        let conn = match req.guard::<SharesDbConn>().await {
+            Success(conn) => conn,
+            _ => {
+                return Failure((
+                    Status::ServiceUnavailable,
+                    AuthError::DatabaseError("failed to connect to the database".into()),
+                ))
+            }
+        };
+        let (key_id, owner, admin, granted) = match req.guard::<BearerJwt>().await {
+            Success(BearerJwt(identity)) => {
+                (None, identity.owner, identity.admin, identity.workspaces)
+            }
+            Failure(f) => return Failure(f),
+            Forward(_) => match database::find_api_key(&conn, token.to_owned()).await {
+                Ok(Some(key)) => (Some(key.id), key.owner, key.admin, BTreeMap::new()),
+                Ok(None) => return Failure((Status::Unauthorized, AuthError::Invalid)),
+                Err(e) => {
+                    return Failure((
+                        Status::InternalServerError,
+                        AuthError::DatabaseError(e.to_string()),
+                    ))
+                }
+            },
+        };
+        match workspaces::find_roles(&conn, owner.clone()).await {
+            Ok(mut workspaces) => {
+                // Roles granted by a token's groups add to those held through membership.
+                for (workspace, role) in granted {
+                    let held = workspaces.entry(workspace).or_insert(role);
+                    *held = role.max(*held);
+                }
+                Success(Principal {
+                    key_id,
+                    owner,
+                    admin,
+                    workspaces,
+                })
+            }
+            Err(e) => Failure((
+                Status::InternalServerError,
+                AuthError::DatabaseError(e.to_string()),
+            )),
+        }
+    }
+}
+
+/// A request guard which only succeeds if the request carries the configured admin key, or an admin api key.
+pub struct Admin(pub Principal);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = AuthError;
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match req.guard::<Principal>().await {
+            Success(p) if p.admin => Success(Admin(p)),
+            Success(_) => Failure((Status::Forbidden, AuthError::Forbidden)),
+            Failure(f) => Failure(f),
+            Forward(f) => Forward(f),
+        }
+    }
+}
+
+#[test]
+fn test_admin_guard() {
+    use rocket::http::Header;
+    let (client, dir) = crate::test_client();
+    let c = rusqlite::Connection::open(dir.path().join("database.db")).unwrap();
+    crate::insert_api_keys(
+        &c,
+        &[("user-key", "bob", false), ("admin-key", "alice", true)],
+    );
+    let export = |key: &str| {
+        client
+            .get("/admin/export?format=jsonl")
+            .header(Header::new("Authorization", format!("Bearer {}", key)))
+            .dispatch()
+            .status()
+    };
+    assert_eq!(export("admin"), Status::Ok);
+    assert_eq!(export("admin-key"), Status::Ok);
+    assert_eq!(export("user-key"), Status::Forbidden);
+    assert_eq!(export("nonsense"), Status::Unauthorized);
+}
@@ -0,0 +1,482 @@
+//! The command line interface of the server binary. With no subcommand the web server is launched,
+//! the remaining subcommands operate directly on the configured database.
+use crate::access;
+use crate::audit::Actor;
+use crate::auth::{ApiKey, NewApiKey};
+use crate::common::get_time_seconds;
+use crate::config::Config;
+use crate::database::{self, Order, Search, SharesDbConn, SortBy};
+use crate::domains;
+use crate::events::{Event, ShareEvent};
+use crate::expiry;
+use crate::passthrough::QueryPrecedence;
+use crate::redirect::RedirectType;
+use crate::split::{Variant, Variants};
+use crate::tags::Tags;
+use crate::transfer::{self, Conflict, Format};
+use crate::trash;
+use crate::url_id::{token_to_number, UncommittedUrlID, UrlID};
+use crate::webhooks;
+use clap::{Parser, Subcommand};
+use rocket::figment::Figment;
+use serde::Serialize;
+use std::path::PathBuf;
+
+/// A simple url shortener.
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Print results as json rather than human readable text.
+    #[arg(long, global = true)]
+    pub json: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// The subcommands of the binary. Only one is ever parsed, so the size of the largest does not matter.
+#[derive(Debug, Subcommand)]
+#[allow(clippy::large_enum_variant)]
+pub enum Command {
+    /// Launch the web server (the default).
+    Serve,
+    /// Create or upgrade the database schema.
+    Migrate,
+    /// Shorten a url.
+    Create {
+        /// The destination, which may be omitted if variants are given.
+        #[arg(default_value = "")]
+        url: String,
+        /// When the share expires, in seconds since the unix epoch, as an ISO-8601 timestamp, or a duration such as 7d.
+        #[arg(long, value_parser = parse_time)]
+        exp: Option<i64>,
+        /// When the share goes live, in the same formats as --exp.
+        #[arg(long, value_parser = parse_time)]
+        nbf: Option<i64>,
+        /// Expire the share once it goes unused for this long, in seconds or as a duration such as 30d.
+        #[arg(long, value_parser = expiry::parse_ttl)]
+        idle_ttl: Option<i64>,
+        /// The owner to record against the share.
+        #[arg(long)]
+        owner: Option<String>,
+        /// The status code to redirect with: 301, 302, 303, 307 or 308.
+        #[arg(long, value_parser = parse_redirect_type)]
+        redirect_type: Option<RedirectType>,
+        /// Merge the query string of requests into the destination.
+        #[arg(long)]
+        passthrough_query: bool,
+        /// Append trailing path segments of requests to the destination.
+        #[arg(long)]
+        passthrough_path: bool,
+        /// Keep the destination's value when a query parameter appears in both.
+        #[arg(long)]
+        prefer_destination_query: bool,
+        /// Split clicks between several destinations, given as `<weight>:<url>`. May be repeated.
+        #[arg(long = "variant", value_parser = parse_variant)]
+        variants: Vec<Variant>,
+        /// Remember which variant each visitor was sent to.
+        #[arg(long)]
+        sticky: bool,
+        /// Where to send visitors once the share is expired, exhausted or disabled.
+        #[arg(long)]
+        fallback_url: Option<String>,
+        /// Exhaust the share after this many clicks.
+        #[arg(long)]
+        max_clicks: Option<i64>,
+        /// A short description of the share.
+        #[arg(long)]
+        title: Option<String>,
+        /// Free text notes to keep about the share.
+        #[arg(long)]
+        notes: Option<String>,
+        /// Tag the share. May be repeated.
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+        /// Serve the share on this configured domain, rather than the server's own.
+        #[arg(long)]
+        domain: Option<String>,
+    },
+    /// Show the share a token resolves to.
+    Get {
+        token: String,
+        /// The domain the token was issued on, if not the server's own.
+        #[arg(long)]
+        domain: Option<String>,
+    },
+    /// Move the share a token resolves to into the trash.
+    Delete {
+        token: String,
+        /// The domain the token was issued on, if not the server's own.
+        #[arg(long)]
+        domain: Option<String>,
+    },
+    /// Take the share a token resolves to back out of the trash.
+    Restore {
+        token: String,
+        /// The domain the token was issued on, if not the server's own.
+        #[arg(long)]
+        domain: Option<String>,
+    },
+    /// List shares, newest first.
+    List {
+        #[arg(long)]
+        owner: Option<String>,
+        /// Only list shares carrying this tag.
+        #[arg(long)]
+        tag: Option<String>,
+        #[arg(long, default_value_t = 50)]
+        limit: i64,
+    },
+    /// Permanently remove every expired share. Shares which went idle within the last `access_flush_interval` are kept,
+    /// as a running server may not yet have written their latest access.
+    PurgeExpired,
+    /// Permanently remove every share which has been in the trash for longer than the configured retention.
+    PurgeDeleted,
+    /// Manage api keys.
+    #[command(subcommand)]
+    Keys(KeysCommand),
+    /// Export every share to a file, or stdout if no file is given.
+    Export {
+        #[arg(long, value_enum, default_value = "jsonl")]
+        format: Format,
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Import shares from a file, preserving their ids.
+    Import {
+        #[arg(long, value_enum, default_value = "jsonl")]
+        format: Format,
+        #[arg(long, value_enum, default_value = "fail")]
+        conflict: Conflict,
+        file: PathBuf,
+    },
+}
+
+/// Parse a redirect type from its status code.
+fn parse_redirect_type(code: &str) -> Result<RedirectType, String> {
+    RedirectType::try_from(code.parse::<u16>().map_err(|e| e.to_string())?)
+}
+
+/// Parse a time in any of the formats accepted by the api, durations being measured from now.
+fn parse_time(input: &str) -> Result<i64, String> {
+    expiry::parse_time(input, get_time_seconds())
+}
+
+/// Parse a variant given as `<weight>:<url>`.
+fn parse_variant(input: &str) -> Result<Variant, String> {
+    let (weight, url) = input
+        .split_once(':')
+        .ok_or_else(|| "expected a variant of the form <weight>:<url>".to_string())?;
+    Ok(Variant {
+        url: url.to_owned(),
+        weight: weight
+            .parse()
+            .map_err(|e| format!("invalid weight: {}", e))?,
+    })
+}
+
+/// The subcommands of `keys`.
+#[derive(Debug, Subcommand)]
+pub enum KeysCommand {
+    /// List every api key. Their secrets are not stored, so cannot be listed.
+    List,
+    /// Create a new api key, printing its secret. This is the only time the secret is shown.
+    Create {
+        owner: String,
+        /// Allow the key to access the admin endpoints.
+        #[arg(long)]
+        admin: bool,
+    },
+    /// Revoke an api key by id.
+    Revoke { id: i64 },
+}
+
+/// A share as printed by the command line, alongside its token and link.
+#[derive(Serialize)]
+struct ShareOutput<'a> {
+    token: String,
+    link: String,
+    #[serde(flatten)]
+    share: &'a UrlID,
+}
+
+impl<'a> From<&'a UrlID> for ShareOutput<'a> {
+    fn from(share: &'a UrlID) -> Self {
+        ShareOutput {
+            token: share.generate_token(),
+            link: share.get_shortened_link(),
+            share,
+        }
+    }
+}
+
+impl std::fmt::Display for ShareOutput<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let exp = match self.share.get_exp() {
+            None => "never".to_string(),
+            Some(exp) => exp.to_string(),
+        };
+        write!(
+            f,
+            "{}\t{}\t{} (created {}, expires {}, owner {})",
+            self.share.get_id(),
+            self.link,
+            self.share.get_dest_url(),
+            self.share.get_crt(),
+            exp,
+            self.share.get_owner().unwrap_or("-"),
+        )
+    }
+}
+
+/// Print a value either as json, or using its human readable form.
+fn print<T: Serialize + std::fmt::Display>(json: bool, value: &T) {
+    if json {
+        println!("{}", serde_json::to_string(value).unwrap());
+    } else {
+        println!("{}", value);
+    }
+}
+
+/// Print a list of values either as a json array, or one per line.
+fn print_all<T: Serialize + std::fmt::Display>(json: bool, values: &[T]) {
+    if json {
+        println!("{}", serde_json::to_string(values).unwrap());
+    } else {
+        for value in values {
+            println!("{}", value);
+        }
+    }
+}
+
+impl std::fmt::Display for ApiKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}\t{}{}",
+            self.id,
+            self.owner,
+            if self.admin { "\t(admin)" } else { "" }
+        )
+    }
+}
+
+impl std::fmt::Display for NewApiKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}\t{}", self.key, self.secret)
+    }
+}
+
+/// Ignite rocket (without launching the server) so that we may borrow a connection to the configured database.
+async fn connect(figment: &Figment) -> Result<SharesDbConn, String> {
+    let rocket = crate::build(figment.clone())
+        .ignite()
+        .await
+        .map_err(|e| e.to_string())?;
+    SharesDbConn::get_one(&rocket)
+        .await
+        .ok_or_else(|| "unable to connect to the database".to_string())
+}
+
+/// Find the share a token refers to on `domain`, or the server's own domain if None, or fail with a message suitable
+/// for the user.
+async fn find(conn: &SharesDbConn, token: &str, domain: Option<&str>) -> Result<UrlID, String> {
+    let number =
+        token_to_number(token).ok_or_else(|| format!("'{}' is not a valid token", token))?;
+    Search::Token(domain.map(domains::normalize), number)
+        .find_share(conn)
+        .await?
+        .ok_or_else(|| format!("no share exists for '{}'", token))
+}
+
+/// Run a subcommand other than `serve` to completion.
+pub async fn run(command: Command, json: bool) -> Result<(), String> {
+    run_with(&rocket::Config::figment(), command, json).await
+}
+
+/// Run a subcommand against the database and settings of the given configuration.
+async fn run_with(figment: &Figment, command: Command, json: bool) -> Result<(), String> {
+    let conn = connect(figment).await?;
+    match command {
+        Command::Serve => unreachable!("serve is handled by main"),
+        Command::Migrate => {
+            let version = database::setup(&conn).await?;
+            print(json, &version);
+        }
+        Command::Create {
+            url,
+            exp,
+            nbf,
+            idle_ttl,
+            owner,
+            redirect_type,
+            passthrough_query,
+            passthrough_path,
+            prefer_destination_query,
+            variants,
+            sticky,
+            fallback_url,
+            max_clicks,
+            title,
+            notes,
+            tags,
+            domain,
+        } => {
+            let precedence = match prefer_destination_query {
+                true => QueryPrecedence::Destination,
+                false => QueryPrecedence::Incoming,
+            };
+            let config: Config = figment.extract().map_err(|e| e.to_string())?;
+            let domain = match domain {
+                Some(host) => domains::lookup(&config, &host)
+                    .map_err(|e| e.to_string())?
+                    .map(|d| domains::normalize(&d.host)),
+                None => None,
+            };
+            let mut share = UncommittedUrlID::new(url, exp)
+                .set_nbf(nbf)
+                .set_idle_ttl(idle_ttl)
+                .set_owner(owner)
+                .set_redirect_type(redirect_type)
+                .set_passthrough(passthrough_query, passthrough_path, precedence)
+                .set_variants(Variants::new(variants), sticky)
+                .set_fallback(fallback_url, max_clicks)
+                .set_description(title, notes, Tags::new(tags))
+                .set_domain(domain);
+            share.apply_ttl(&config)?;
+            share.validate()?;
+            let share = database::add_to_database(&conn, share, Actor::cli()).await?;
+            print(json, &ShareOutput::from(&share));
+        }
+        Command::Get { token, domain } => {
+            let share = find(&conn, &token, domain.as_deref()).await?;
+            print(json, &ShareOutput::from(&share));
+        }
+        Command::Delete { token, domain } => {
+            let share = find(&conn, &token, domain.as_deref()).await?;
+            let share =
+                database::remove_from_database(&conn, Search::Id(*share.get_id()), Actor::cli())
+                    .await?;
+            let event = ShareEvent::new(Event::Deleted, &share, serde_json::json!({}));
+            webhooks::emit(&conn, &event).await;
+            print(json, &ShareOutput::from(&share));
+        }
+        Command::Restore { token, domain } => {
+            let share = find(&conn, &token, domain.as_deref()).await?;
+            let share =
+                database::restore_share(&conn, Search::Id(*share.get_id()), Actor::cli()).await?;
+            let event = ShareEvent::new(Event::Restored, &share, serde_json::json!({}));
+            webhooks::emit(&conn, &event).await;
+            print(json, &ShareOutput::from(&share));
+        }
+        Command::List { owner, tag, limit } => {
+            let search = Search::All(
+                owner
+                    .map(Search::Owner)
+                    .into_iter()
+                    .chain(tag.map(Search::Tag))
+                    .chain([Search::Deleted(false)])
+                    .collect(),
+            );
+            let order = Order {
+                sort: SortBy::Created,
+                descending: true,
+            };
+            let (shares, _) = search.find_page(&conn, order, limit, None).await?;
+            let shares: Vec<ShareOutput> = shares.iter().map(ShareOutput::from).collect();
+            print_all(json, &shares);
+        }
+        Command::PurgeExpired => {
+            let config: Config = figment.extract().map_err(|e| e.to_string())?;
+            let cutoff = get_time_seconds().saturating_sub(access::flush_interval(Some(&config)));
+            let removed = database::purge_expired(&conn, cutoff, Actor::cli()).await?;
+            print(json, &removed);
+        }
+        Command::PurgeDeleted => {
+            let config: Config = figment.extract().map_err(|e| e.to_string())?;
+            let cutoff = get_time_seconds().saturating_sub(trash::retention(Some(&config)));
+            let removed = database::purge_deleted(&conn, cutoff, Actor::cli()).await?;
+            print(json, &removed);
+        }
+        Command::Keys(KeysCommand::List) => {
+            print_all(json, &database::list_api_keys(&conn).await?);
+        }
+        Command::Keys(KeysCommand::Create { owner, admin }) => {
+            let secret = ApiKey::generate_secret();
+            let key = database::add_api_key(&conn, secret.clone(), owner, admin).await?;
+            print(json, &NewApiKey { key, secret });
+        }
+        Command::Keys(KeysCommand::Revoke { id }) => {
+            database::remove_api_key(&conn, id).await?;
+            print(json, &id);
+        }
+        Command::Export { format, output } => {
+            let body = transfer::export(&conn, format).await?;
+            match output {
+                Some(path) => std::fs::write(path, body).map_err(|e| e.to_string())?,
+                None => print!("{}", body),
+            }
+        }
+        Command::Import {
+            format,
+            conflict,
+            file,
+        } => {
+            let input = std::fs::read_to_string(file).map_err(|e| e.to_string())?;
+            let report = transfer::import(&conn, &input, format, conflict, Actor::cli()).await?;
+            if json {
+                println!("{}", serde_json::to_string(&report).unwrap());
+            } else {
+                println!(
+                    "inserted {}, overwritten {}, skipped {}",
+                    report.inserted, report.overwritten, report.skipped
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[rocket::async_test]
+async fn test_cli() {
+    let dir = tempfile::tempdir().unwrap();
+    let figment = crate::test_figment(&dir);
+    let run = |args: &[&str]| {
+        let cli = Cli::try_parse_from(["url-shortener"].iter().chain(args)).unwrap();
+        let figment = figment.clone();
+        async move { run_with(&figment, cli.command.unwrap(), cli.json).await }
+    };
+
+    // A database with only the original schema, holding a share.
+    let c =
+        rocket_sync_db_pools::rusqlite::Connection::open(dir.path().join("database.db")).unwrap();
+    c.execute_batch(database::MIGRATIONS[0]).unwrap();
+    c.execute_batch(
+        "INSERT INTO shares (id, exp, crt, url) VALUES (1, 9223372036854775807, 0, 'https://a.example');
+         PRAGMA user_version = 1;",
+    )
+    .unwrap();
+    drop(c);
+    run(&["migrate"]).await.unwrap();
+    let conn = connect(&figment).await.unwrap();
+    let version: usize = conn
+        .run(|c| c.query_row("PRAGMA user_version;", [], |row| row.get(0)))
+        .await
+        .unwrap();
+    assert_eq!(version, database::MIGRATIONS.len());
+    let token = crate::url_id::number_to_token(1);
+    let share = find(&conn, &token, None).await.unwrap();
+    assert_eq!(share.get_dest_url(), "https://a.example");
+    run(&["get", &token]).await.unwrap();
+    assert!(run(&["get", "zzzzzz"]).await.is_err());
+
+    run(&["--json", "keys", "create", "alice", "--admin"])
+        .await
+        .unwrap();
+    let keys = database::list_api_keys(&conn).await.unwrap();
+    assert_eq!(keys.len(), 1);
+    assert_eq!((keys[0].owner.as_str(), keys[0].admin), ("alice", true));
+    let id = keys[0].id.to_string();
+    run(&["keys", "revoke", &id]).await.unwrap();
+    assert!(database::list_api_keys(&conn).await.unwrap().is_empty());
+    assert!(run(&["keys", "revoke", &id]).await.is_err());
+}
@@ -0,0 +1,75 @@
+//! Server configuration, read from `Rocket.toml` or `ROCKET_` prefixed environment variables.
+use crate::domains::DomainConfig;
+use crate::expiry::Ttl;
+use crate::jwt::JwtConfig;
+use crate::redirect::RedirectType;
+use serde::Deserialize;
+use std::path::PathBuf;
+
+/// Settings specific to the url shortener, extracted from the same figment as rocket's own configuration.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Config {
+    /// The key required to access the admin endpoints. If unset, the admin endpoints will always refuse access.
+    #[serde(default)]
+    pub admin_key: Option<String>,
+    /// Which logs to emit, as a tracing filter directive such as `info` or `url_shortener=debug`. Defaults to `info`.
+    /// Named so as not to collide with rocket's own `log_level`, which only affects rocket's logger.
+    #[serde(default)]
+    pub log_filter: Option<String>,
+    /// How log lines are formatted.
+    #[serde(default)]
+    pub log_format: LogFormat,
+    /// The status code used to redirect links created without choosing one. Defaults to 303.
+    #[serde(default)]
+    pub default_redirect_type: RedirectType,
+    /// An html page served in place of links scheduled to go live in the future. If unset such links respond 404.
+    #[serde(default)]
+    pub coming_soon_page: Option<PathBuf>,
+    /// How long shares created without an expiry last, in seconds or as a duration such as `30d`. If unset they never expire.
+    #[serde(default)]
+    pub default_ttl: Option<Ttl>,
+    /// The furthest in the future a share may expire, in seconds or as a duration. If unset shares may never expire.
+    #[serde(default)]
+    pub max_ttl: Option<Ttl>,
+    /// How often clicks and the last access times of shares with an idle ttl are written to the database. Defaults to 60
+    /// seconds, and 0 writes them as they happen.
+    #[serde(default)]
+    pub access_flush_interval: Option<Ttl>,
+    /// Where visitors of an expired, exhausted or disabled share are sent if it has no fallback of its own.
+    /// If unset such shares respond 404.
+    #[serde(default)]
+    pub default_fallback_url: Option<String>,
+    /// How long after a first failed attempt a webhook delivery is retried, in seconds or as a duration, doubling
+    /// with each further failure up to an hour. Defaults to 10 seconds.
+    #[serde(default)]
+    pub webhook_retry_base: Option<Ttl>,
+    /// How many times a webhook delivery is attempted before it is given up on. Defaults to 8.
+    #[serde(default)]
+    pub webhook_max_attempts: Option<u32>,
+    /// Whether webhooks may be delivered to loopback, private and link-local addresses. Only meant for local
+    /// development, as it lets anyone able to create a webhook reach services only the server can see.
+    #[serde(default)]
+    pub allow_private_webhooks: bool,
+    /// How long deleted shares are kept in the trash before being permanently removed, in seconds or as a duration.
+    /// Defaults to 30 days.
+    #[serde(default)]
+    pub trash_retention: Option<Ttl>,
+    /// Further domains links are served on, each with its own set of links, beside the server's own.
+    #[serde(default)]
+    pub domains: Vec<DomainConfig>,
+    /// How bearer JWTs from an identity provider are verified and mapped onto principals. If unset only api keys are
+    /// accepted.
+    #[serde(default)]
+    pub jwt: Option<JwtConfig>,
+}
+
+/// The formats logs may be written in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One json object per line.
+    #[default]
+    Json,
+    /// Human readable text.
+    Text,
+}
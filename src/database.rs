@@ -1,13 +1,37 @@
 //! Functions and structs needed for interaction with the sqlite database.
+use crate::audit::{self, Action, Actor};
+use crate::auth::ApiKey;
+use crate::common::get_time_seconds;
+use crate::fallback::Unavailable;
+use crate::history;
+use crate::metrics;
+use crate::search;
+use crate::tags;
+use crate::url_id::destination_host;
 use crate::url_id::{UncommittedUrlID, UrlID, UrlIDError};
 use rocket_sync_db_pools::database;
-use rocket_sync_db_pools::rusqlite::{self, params};
+use rocket_sync_db_pools::rusqlite::types::Value;
+use rocket_sync_db_pools::rusqlite::{self, params, OptionalExtension};
 
 /// A shared database
 #[doc(hidden)]
 #[database("sqlite_shares")]
 pub struct SharesDbConn(rusqlite::Connection);
 
+impl SharesDbConn {
+    /// Run a closure against the database connection, recording how long it took in the metrics.
+    pub async fn run_timed<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut rusqlite::Connection) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let start = std::time::Instant::now();
+        let result = self.run(f).await;
+        metrics::record_db_duration(start.elapsed());
+        result
+    }
+}
+
 /// An enum representing the database error states
 #[derive(Debug)]
 pub enum DatabaseError {
@@ -16,6 +40,21 @@ pub enum DatabaseError {
     UnableToContact,
     SqlError(String),
     InsertError(String),
+    AlreadyExists(i64),
+}
+
+impl DatabaseError {
+    /// The name of this variant, as used to label metrics.
+    pub fn variant(&self) -> &'static str {
+        match self {
+            DatabaseError::UrlIDError(_) => "UrlIDError",
+            DatabaseError::DoesNotExist => "DoesNotExist",
+            DatabaseError::UnableToContact => "UnableToContact",
+            DatabaseError::SqlError(_) => "SqlError",
+            DatabaseError::InsertError(_) => "InsertError",
+            DatabaseError::AlreadyExists(_) => "AlreadyExists",
+        }
+    }
 }
 
 impl From<rusqlite::Error> for DatabaseError {
@@ -41,6 +80,9 @@ impl From<DatabaseError> for String {
                 "an error occurred attempting to add a new share to the database: {}",
                 s
             ),
+            DatabaseError::AlreadyExists(id) => {
+                format!("a share with id {} already exists in the database", id)
+            }
         }
     }
 }
@@ -62,6 +104,10 @@ impl std::fmt::Display for DatabaseError {
                 "an error occurred attempting to add a new share to the database: {}",
                 s
             )),
+            DatabaseError::AlreadyExists(id) => f.write_str(&format!(
+                "a share with id {} already exists in the database",
+                id
+            )),
         }
     }
 }
@@ -74,24 +120,89 @@ impl From<DatabaseError> for UrlIDError {
 
 impl std::convert::From<DatabaseError> for (rocket::http::Status, std::string::String) {
     fn from(err: DatabaseError) -> (rocket::http::Status, std::string::String) {
-        (rocket::http::Status::new(500), err.to_string())
+        if !matches!(err, DatabaseError::UrlIDError(_)) {
+            metrics::record_error("DatabaseError", err.variant());
+        }
+        match err {
+            DatabaseError::DoesNotExist => (rocket::http::Status::new(404), err.to_string()),
+            DatabaseError::AlreadyExists(_) => (rocket::http::Status::new(409), err.to_string()),
+            DatabaseError::UrlIDError(e) => e.into(),
+            _ => (rocket::http::Status::new(500), err.to_string()),
+        }
     }
 }
 
-/// Specifies how to search for items in the database, with three options.
+/// Specifies how to search for shares in the database. Searches may be combined with [`Search::All`].
 pub enum Search {
     Id(i64),
+    /// The share issued the token encoding the given number on the given domain, or the server's own if None.
+    Token(Option<String>, i64),
     #[allow(dead_code)]
     Url(String),
+    /// Shares belonging to the given owner.
+    Owner(String),
+    /// Shares carrying the given tag.
+    Tag(String),
+    /// Shares created at or after the given time.
+    CreatedAfter(i64),
+    /// Shares created before the given time.
+    CreatedBefore(i64),
+    /// Shares which had expired by the given time.
+    Expired(i64),
+    /// Shares which had not expired by the given time.
+    Active(i64),
+    /// Shares redirecting to the given domain, or any of its subdomains.
+    Domain(String),
+    /// Shares which are in the trash if true, or those which are not if false.
+    Deleted(bool),
+    /// Shares belonging to the given workspace, or to no workspace if None.
+    Workspace(Option<i64>),
+    /// Shares matching every one of the given searches, or every share if there are none.
+    All(Vec<Search>),
 }
 
 impl Search {
-    /// Based on which variant of the enum you are using, generates the search term required to interface with the sqlite database.
-    // Note, if adding to this function in the future, ensure to add '' around strings.
-    fn get_search_term(self) -> String {
+    /// Based on which variant of the enum you are using, generates the condition required to interface with the sqlite database.
+    /// Values are never formatted into the condition, they are pushed onto `values` and referred to by their position.
+    pub fn get_search_term(self, values: &mut Vec<Value>) -> String {
+        let mut bind = |value: Value| {
+            values.push(value);
+            format!("?{}", values.len())
+        };
         match self {
-            Search::Id(s) => format!("{} = {}", "id", s),
-            Search::Url(s) => format!("{} = '{}'", "url", s),
+            Search::Id(id) => format!("id = {}", bind(id.into())),
+            Search::Token(domain, number) => format!(
+                "COALESCE(domain, '') = {} AND token = {}",
+                bind(domain.unwrap_or_default().into()),
+                bind(number.into())
+            ),
+            Search::Url(url) => format!("url = {}", bind(url.into())),
+            Search::Owner(owner) => format!("owner = {}", bind(owner.into())),
+            Search::Tag(tag) => format!(
+                "id IN (SELECT share_tags.share_id FROM share_tags JOIN tags ON tags.id = share_tags.tag_id WHERE tags.name = {})",
+                bind(tag.into())
+            ),
+            Search::CreatedAfter(t) => format!("crt >= {}", bind(t.into())),
+            Search::CreatedBefore(t) => format!("crt < {}", bind(t.into())),
+            Search::Expired(now) => EXPIRED.replace("?1", &bind(now.into())),
+            Search::Active(now) => format!("NOT {}", EXPIRED.replace("?1", &bind(now.into()))),
+            Search::Domain(domain) => {
+                let domain = bind(domain.to_ascii_lowercase().into());
+                format!(
+                    "(dest_host = {0} OR substr(dest_host, -length({0}) - 1) = '.' || {0})",
+                    domain
+                )
+            }
+            Search::Deleted(true) => "deleted_at IS NOT NULL".to_string(),
+            Search::Deleted(false) => "deleted_at IS NULL".to_string(),
+            Search::Workspace(Some(id)) => format!("workspace_id = {}", bind(id.into())),
+            Search::Workspace(None) => "workspace_id IS NULL".to_string(),
+            Search::All(searches) if searches.is_empty() => "1".to_string(),
+            Search::All(searches) => searches
+                .into_iter()
+                .map(|s| format!("({})", s.get_search_term(values)))
+                .collect::<Vec<_>>()
+                .join(" AND "),
         }
     }
     ///Run a search, returns the first result it finds in the database, or a DatabaseError if something goes wrong.
@@ -102,6 +213,140 @@ impl Search {
         }
         Ok(Some(search_result[0].clone())) //Assume first result is correct, user will use search::id() variant if exactness is important.
     }
+
+    /// Run a search, returning up to `limit` shares in the given order starting after `after`,
+    /// along with the cursor to continue from if there are more.
+    pub async fn find_page(
+        self,
+        conn: &SharesDbConn,
+        order: Order,
+        limit: i64,
+        after: Option<Cursor>,
+    ) -> Result<(Vec<UrlID>, Option<Cursor>), DatabaseError> {
+        let mut values = Vec::new();
+        let mut condition = self.get_search_term(&mut values);
+        let key = order.sort.key();
+        let (cmp, direction) = match order.descending {
+            true => ("<", "DESC"),
+            false => (">", "ASC"),
+        };
+        if let Some(cursor) = after {
+            values.push(cursor.key);
+            let key_value = values.len();
+            values.push(cursor.id.into());
+            condition = format!(
+                "({}) AND ({2} {1} ?{3} OR ({2} = ?{3} AND id {1} ?{4}))",
+                condition,
+                cmp,
+                key,
+                key_value,
+                key_value + 1
+            );
+        }
+        values.push((limit + 1).into());
+        let sql = format!(
+            "SELECT {0} FROM shares WHERE {1} ORDER BY {2} {3}, id {3} LIMIT ?{4};",
+            SHARE_COLUMNS,
+            condition,
+            key,
+            direction,
+            values.len()
+        );
+        let mut shares: Vec<UrlID> = conn
+            .run_timed(move |c| {
+                c.prepare(&sql).and_then(|mut res| {
+                    res.query_map(rusqlite::params_from_iter(values), UrlID::from_database)?
+                        .collect()
+                })
+            })
+            .await?;
+        let next = match shares.len() as i64 > limit {
+            true => {
+                shares.truncate(limit as usize);
+                shares.last().map(|s| order.sort.cursor(s))
+            }
+            false => None,
+        };
+        Ok((shares, next))
+    }
+}
+
+/// The orders shares may be listed in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, FromFormField)]
+pub enum SortBy {
+    /// When the share was created.
+    #[default]
+    #[field(value = "created")]
+    Created,
+    /// When the share expires, shares which never expire coming last.
+    #[field(value = "expires")]
+    Expires,
+    /// The destination url.
+    #[field(value = "destination")]
+    Destination,
+}
+
+impl SortBy {
+    /// The expression shares are ordered by.
+    fn key(&self) -> &'static str {
+        match self {
+            SortBy::Created => "crt",
+            SortBy::Expires => "COALESCE(exp, 9223372036854775807)",
+            SortBy::Destination => "url",
+        }
+    }
+
+    /// The cursor continuing a listing after the given share.
+    fn cursor(&self, share: &UrlID) -> Cursor {
+        let key = match self {
+            SortBy::Created => Value::Integer(*share.get_crt()),
+            SortBy::Expires => Value::Integer(share.get_exp().unwrap_or(i64::MAX)),
+            SortBy::Destination => Value::Text(share.get_dest_url().to_owned()),
+        };
+        Cursor {
+            key,
+            id: *share.get_id(),
+        }
+    }
+}
+
+/// How a listing of shares is ordered. Ties are broken by id, so every share has a distinct position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Order {
+    pub sort: SortBy,
+    pub descending: bool,
+}
+
+/// The position of the last share in a page of a listing, from which the next page continues.
+/// Passed to clients as an opaque string, which is only meaningful with the order it was created for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cursor {
+    key: Value,
+    id: i64,
+}
+
+impl Cursor {
+    /// Encode this cursor for a client to send back.
+    pub fn encode(&self) -> String {
+        let json = match &self.key {
+            Value::Text(key) => serde_json::json!([self.id, key]),
+            Value::Integer(key) => serde_json::json!([self.id, key]),
+            _ => serde_json::json!([self.id]),
+        };
+        base64::encode_config(json.to_string(), base64::URL_SAFE_NO_PAD)
+    }
+
+    /// Decode a cursor sent by a client.
+    pub fn decode(input: &str) -> Option<Cursor> {
+        let json = base64::decode_config(input, base64::URL_SAFE_NO_PAD).ok()?;
+        let (id, key): (i64, serde_json::Value) = serde_json::from_slice(&json).ok()?;
+        let key = match key {
+            serde_json::Value::String(s) => Value::Text(s),
+            serde_json::Value::Number(n) => Value::Integer(n.as_i64()?),
+            _ => return None,
+        };
+        Some(Cursor { key, id })
+    }
 }
 
 /// Implementing a trait means that your struct can be parsed from a database row, or return an error.
@@ -110,62 +355,363 @@ pub trait FromDatabase: Sized {
     fn from_database(data: &rocket_sync_db_pools::rusqlite::Row<'_>) -> Result<Self, Self::Error>;
 }
 
-/// Setup the database. Creates the table(s) required if they do not already exist in the database.db file.
-pub async fn setup(conn: &SharesDbConn) -> Result<(), DatabaseError> {
-    conn.run(|c| {
-        c.execute(
-            "CREATE TABLE IF NOT EXISTS shares (
-            id INTEGER PRIMARY KEY,
-            exp BIGINT NOT NULL,
-            crt BIGINT INT NOT NULL,
-            url TEXT NOT NULL
-        );",
-            [],
-        )
+/// The schema migrations, applied in order. After applying the migration at index `n` the schema is at version `n + 1`.
+pub(crate) const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS shares (
+        id INTEGER PRIMARY KEY,
+        exp BIGINT NOT NULL,
+        crt BIGINT INT NOT NULL,
+        url TEXT NOT NULL
+    );",
+    "CREATE TABLE api_keys (
+        id INTEGER PRIMARY KEY,
+        key_hash TEXT NOT NULL UNIQUE,
+        owner TEXT NOT NULL,
+        admin INTEGER NOT NULL DEFAULT 0,
+        crt BIGINT NOT NULL
+    );
+    ALTER TABLE shares ADD COLUMN owner TEXT;",
+    "ALTER TABLE shares ADD COLUMN redirect_type INTEGER;",
+    "ALTER TABLE shares ADD COLUMN passthrough_query INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE shares ADD COLUMN passthrough_path INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE shares ADD COLUMN query_precedence TEXT NOT NULL DEFAULT 'incoming';",
+    "UPDATE shares SET url = replace(replace(url, '{', '{{'), '}', '}}');",
+    "ALTER TABLE shares ADD COLUMN variants TEXT;
+    ALTER TABLE shares ADD COLUMN sticky INTEGER NOT NULL DEFAULT 0;
+    CREATE TABLE clicks (
+        id INTEGER PRIMARY KEY,
+        share_id INTEGER NOT NULL,
+        ts BIGINT NOT NULL,
+        variant INTEGER
+    );
+    CREATE INDEX clicks_share_id ON clicks (share_id);",
+    "ALTER TABLE shares ADD COLUMN rules TEXT;",
+    "ALTER TABLE shares ADD COLUMN nbf BIGINT;",
+    "CREATE TABLE shares_new (
+        id INTEGER PRIMARY KEY,
+        exp BIGINT,
+        crt BIGINT NOT NULL,
+        url TEXT NOT NULL,
+        owner TEXT,
+        redirect_type INTEGER,
+        passthrough_query INTEGER NOT NULL DEFAULT 0,
+        passthrough_path INTEGER NOT NULL DEFAULT 0,
+        query_precedence TEXT NOT NULL DEFAULT 'incoming',
+        variants TEXT,
+        sticky INTEGER NOT NULL DEFAULT 0,
+        rules TEXT,
+        nbf BIGINT
+    );
+    INSERT INTO shares_new (id, exp, crt, url, owner, redirect_type, passthrough_query, passthrough_path, query_precedence, variants, sticky, rules, nbf)
+        SELECT id, NULLIF(exp, 9223372036854775807), crt, url, owner, redirect_type, passthrough_query, passthrough_path, query_precedence, variants, sticky, rules, nbf
+        FROM shares;
+    DROP TABLE shares;
+    ALTER TABLE shares_new RENAME TO shares;",
+    "ALTER TABLE shares ADD COLUMN idle_ttl BIGINT;
+    ALTER TABLE shares ADD COLUMN last_access BIGINT;",
+    "ALTER TABLE shares ADD COLUMN fallback_url TEXT;
+    ALTER TABLE shares ADD COLUMN max_clicks BIGINT;
+    ALTER TABLE shares ADD COLUMN disabled INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE clicks ADD COLUMN fallback TEXT;",
+    "ALTER TABLE shares ADD COLUMN title TEXT;
+    ALTER TABLE shares ADD COLUMN notes TEXT;
+    ALTER TABLE shares ADD COLUMN dest_host TEXT;
+    CREATE INDEX shares_crt ON shares (crt);
+    CREATE INDEX shares_dest_host ON shares (dest_host);
+    CREATE TABLE tags (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE
+    );
+    CREATE TABLE share_tags (
+        share_id INTEGER NOT NULL,
+        tag_id INTEGER NOT NULL,
+        PRIMARY KEY (share_id, tag_id)
+    );
+    CREATE INDEX share_tags_tag_id ON share_tags (tag_id);",
+    "CREATE VIRTUAL TABLE shares_fts USING fts5(url, title, notes, tags);
+    INSERT INTO shares_fts (rowid, url, title, notes, tags)
+        SELECT id, url, title, notes, (
+            SELECT group_concat(tags.name, ' ') FROM share_tags JOIN tags ON tags.id = share_tags.tag_id
+            WHERE share_tags.share_id = shares.id
+        ) FROM shares;",
+    "CREATE TABLE webhooks (
+        id INTEGER PRIMARY KEY,
+        url TEXT NOT NULL,
+        events TEXT NOT NULL,
+        secret TEXT NOT NULL,
+        owner TEXT NOT NULL,
+        all_shares INTEGER NOT NULL DEFAULT 0,
+        crt BIGINT NOT NULL
+    );
+    CREATE TABLE webhook_deliveries (
+        id INTEGER PRIMARY KEY,
+        webhook_id INTEGER NOT NULL,
+        event TEXT NOT NULL,
+        payload TEXT NOT NULL,
+        status TEXT NOT NULL DEFAULT 'pending',
+        attempts INTEGER NOT NULL DEFAULT 0,
+        next_attempt BIGINT NOT NULL,
+        response_code INTEGER,
+        error TEXT,
+        crt BIGINT NOT NULL,
+        delivered_at BIGINT
+    );
+    CREATE INDEX webhook_deliveries_due ON webhook_deliveries (status, next_attempt);
+    CREATE INDEX webhook_deliveries_webhook_id ON webhook_deliveries (webhook_id);",
+    "CREATE TABLE audit_log (
+        id INTEGER PRIMARY KEY,
+        ts BIGINT NOT NULL,
+        action TEXT NOT NULL,
+        share_id INTEGER NOT NULL,
+        actor_key INTEGER,
+        actor TEXT,
+        ip TEXT,
+        before TEXT,
+        after TEXT
+    );
+    CREATE INDEX audit_log_share_id ON audit_log (share_id);
+    CREATE INDEX audit_log_ts ON audit_log (ts);
+    CREATE TRIGGER audit_log_no_update BEFORE UPDATE ON audit_log
+        BEGIN SELECT RAISE(ABORT, 'the audit log is append-only'); END;
+    CREATE TRIGGER audit_log_no_delete BEFORE DELETE ON audit_log
+        BEGIN SELECT RAISE(ABORT, 'the audit log is append-only'); END;",
+    "ALTER TABLE shares ADD COLUMN deleted_at BIGINT;
+    CREATE INDEX shares_deleted_at ON shares (deleted_at);",
+    "CREATE TABLE shares_new (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        exp BIGINT,
+        crt BIGINT NOT NULL,
+        url TEXT NOT NULL,
+        owner TEXT,
+        redirect_type INTEGER,
+        passthrough_query INTEGER NOT NULL DEFAULT 0,
+        passthrough_path INTEGER NOT NULL DEFAULT 0,
+        query_precedence TEXT NOT NULL DEFAULT 'incoming',
+        variants TEXT,
+        sticky INTEGER NOT NULL DEFAULT 0,
+        rules TEXT,
+        nbf BIGINT,
+        idle_ttl BIGINT,
+        last_access BIGINT,
+        fallback_url TEXT,
+        max_clicks BIGINT,
+        disabled INTEGER NOT NULL DEFAULT 0,
+        title TEXT,
+        notes TEXT,
+        dest_host TEXT,
+        deleted_at BIGINT
+    );
+    INSERT INTO shares_new (id, exp, crt, url, owner, redirect_type, passthrough_query, passthrough_path, query_precedence, variants, sticky, rules, nbf, idle_ttl, last_access, fallback_url, max_clicks, disabled, title, notes, dest_host, deleted_at)
+        SELECT id, exp, crt, url, owner, redirect_type, passthrough_query, passthrough_path, query_precedence, variants, sticky, rules, nbf, idle_ttl, last_access, fallback_url, max_clicks, disabled, title, notes, dest_host, deleted_at
+        FROM shares;
+    DROP TABLE shares;
+    ALTER TABLE shares_new RENAME TO shares;
+    CREATE INDEX shares_crt ON shares (crt);
+    CREATE INDEX shares_dest_host ON shares (dest_host);
+    CREATE INDEX shares_deleted_at ON shares (deleted_at);
+    DELETE FROM sqlite_sequence WHERE name = 'shares';
+    INSERT INTO sqlite_sequence (name, seq) SELECT 'shares', MAX(
+        (SELECT COALESCE(MAX(id), 0) FROM shares),
+        (SELECT COALESCE(MAX(share_id), 0) FROM audit_log)
+    );",
+    "CREATE TABLE share_versions (
+        share_id INTEGER NOT NULL,
+        version INTEGER NOT NULL,
+        ts BIGINT NOT NULL,
+        actor TEXT,
+        actor_key INTEGER,
+        reverted_from INTEGER,
+        share TEXT NOT NULL,
+        PRIMARY KEY (share_id, version)
+    );",
+    "ALTER TABLE shares ADD COLUMN domain TEXT;
+    ALTER TABLE shares ADD COLUMN token INTEGER NOT NULL DEFAULT 0;
+    UPDATE shares SET token = id;
+    CREATE UNIQUE INDEX shares_domain_token ON shares (COALESCE(domain, ''), token);
+    CREATE TABLE token_sequences (
+        domain TEXT PRIMARY KEY,
+        last INTEGER NOT NULL
+    );
+    INSERT INTO token_sequences (domain, last)
+        SELECT '', COALESCE((SELECT seq FROM sqlite_sequence WHERE name = 'shares'), 0);",
+    "CREATE TABLE workspaces (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE,
+        crt BIGINT NOT NULL
+    );
+    CREATE TABLE workspace_members (
+        workspace_id INTEGER NOT NULL,
+        owner TEXT NOT NULL,
+        role TEXT NOT NULL,
+        PRIMARY KEY (workspace_id, owner)
+    );
+    CREATE INDEX workspace_members_owner ON workspace_members (owner);
+    ALTER TABLE shares ADD COLUMN workspace_id INTEGER;
+    CREATE INDEX shares_workspace_id ON shares (workspace_id);",
+];
+
+/// The migration escaping the braces in the destinations of existing shares, which were stored before braces could
+/// form placeholders.
+#[cfg(test)]
+const BRACE_MIGRATION: usize = 4;
+
+/// The migration adding `dest_host`, which is filled in for existing shares once it has run.
+const DEST_HOST_MIGRATION: usize = 11;
+
+/// The migration rebuilding shares so that the ids of permanently removed shares are never handed out again, as their
+/// tokens may still be in circulation. The next id starts past any share recorded in the audit log.
+#[cfg(test)]
+const AUTOINCREMENT_MIGRATION: usize = 16;
+
+/// The columns selected for a share: every column of the shares table, and its tags as a json array.
+pub const SHARE_COLUMNS: &str = "shares.*, (
+    SELECT json_group_array(name) FROM (
+        SELECT tags.name FROM share_tags JOIN tags ON tags.id = share_tags.tag_id
+        WHERE share_tags.share_id = shares.id ORDER BY tags.name
+    )
+) AS tags";
+
+/// The condition matching shares which had expired by `?1`, either passing their expiry or going unused for longer than their idle ttl.
+const EXPIRED: &str =
+    "((exp IS NOT NULL AND exp < ?1) OR (idle_ttl IS NOT NULL AND COALESCE(last_access, crt) + idle_ttl < ?1))";
+
+/// The schema version this build of the server expects the database to be at.
+pub const SCHEMA_VERSION: i64 = MIGRATIONS.len() as i64;
+
+/// Get the schema version the database is currently at.
+pub fn schema_version(c: &rusqlite::Connection) -> Result<i64, rusqlite::Error> {
+    c.query_row("PRAGMA user_version;", [], |row| row.get(0))
+}
+
+/// Setup the database. Applies any migrations which have not yet been run against the database.db file, returning the resulting schema version.
+pub async fn setup(conn: &SharesDbConn) -> Result<i64, DatabaseError> {
+    conn.run_timed(|c| -> Result<i64, rusqlite::Error> {
+        let tx = c.transaction()?;
+        let current = schema_version(&tx)?;
+        if current >= SCHEMA_VERSION {
+            return Ok(current);
+        }
+        for migration in MIGRATIONS.iter().skip(current as usize) {
+            tx.execute_batch(migration)?;
+        }
+        if current as usize <= DEST_HOST_MIGRATION {
+            fill_dest_hosts(&tx)?;
+        }
+        tx.execute_batch(&format!("PRAGMA user_version = {};", SCHEMA_VERSION))?;
+        tx.commit()?;
+        Ok(SCHEMA_VERSION)
     })
-    .await?;
+    .await
+    .map_err(DatabaseError::from)
+}
+
+/// Record the destination host of every share, which must be derived from its url outside of sql.
+fn fill_dest_hosts(c: &rusqlite::Connection) -> Result<(), rusqlite::Error> {
+    let urls: Vec<(i64, String)> = c
+        .prepare("SELECT id, url FROM shares;")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+    let mut update = c.prepare("UPDATE shares SET dest_host = ?2 WHERE id = ?1;")?;
+    for (id, url) in urls {
+        update.execute(params![id, destination_host(&url)])?;
+    }
     Ok(())
 }
 
+/// Take the next token number on `domain`, or the server's own domain if None. Each domain counts its tokens
+/// separately, and never hands out a number again, even once the share holding it has been purged.
+fn next_token(c: &rusqlite::Connection, domain: Option<&str>) -> Result<i64, rusqlite::Error> {
+    c.execute(
+        "INSERT INTO token_sequences (domain, last) VALUES (?1, 1)
+        ON CONFLICT (domain) DO UPDATE SET last = last + 1;",
+        params![domain.unwrap_or_default()],
+    )?;
+    c.query_row(
+        "SELECT last FROM token_sequences WHERE domain = ?1;",
+        params![domain.unwrap_or_default()],
+        |row| row.get(0),
+    )
+}
+
+/// Record that the token number `token` has been taken on `domain`, so that it is never handed out again.
+fn claim_token(
+    c: &rusqlite::Connection,
+    domain: Option<&str>,
+    token: i64,
+) -> Result<(), rusqlite::Error> {
+    c.execute(
+        "INSERT INTO token_sequences (domain, last) VALUES (?1, ?2)
+        ON CONFLICT (domain) DO UPDATE SET last = MAX(last, excluded.last);",
+        params![domain.unwrap_or_default(), token],
+    )?;
+    Ok(())
+}
+
+/// Find the id of the share other than `id` holding the token number `token` on `domain`, if there is one.
+pub fn token_holder(
+    c: &rusqlite::Connection,
+    domain: Option<&str>,
+    token: i64,
+    id: i64,
+) -> Result<Option<i64>, rusqlite::Error> {
+    c.query_row(
+        "SELECT id FROM shares WHERE COALESCE(domain, '') = ?1 AND token = ?2 AND id != ?3;",
+        params![domain.unwrap_or_default(), token, id],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
 /// Attempts to add a new share to the database. If successful, will return the added share (importantly) with an ID!
+/// The creation is recorded in the audit log against `actor`.
 pub async fn add_to_database(
     conn: &SharesDbConn,
     data: UncommittedUrlID,
+    actor: Actor,
 ) -> Result<UrlID, DatabaseError> {
-    let response: UrlID = conn.run(move |c| -> Result<UrlID, DatabaseError> {
-        let tx = c.transaction().unwrap();
-        tx.execute("
-            INSERT INTO shares (exp, crt, url)
-            VALUES (?1, ?2, ?3);
-        ", params![
-            data.get_exp(), data.get_crt(), data.get_dest_url()
-        ]).expect("failed to create share in db!");
-        let result_data: Vec<UrlID> = tx.prepare("SELECT * FROM shares ORDER BY id DESC LIMIT 1;")
-        .and_then(|mut res: rusqlite::Statement| -> std::result::Result<Vec<UrlID>, rusqlite::Error> {
-            res.query_map([], |row| {
-                UrlID::from_database(row)
-            }).unwrap().collect()
-        }).unwrap();
-        tx.commit().unwrap();
-        //TODO Implement error handling here, lots of unwrap statements which could panic. At min should be exchanged for expect statements, or preferably proper handling.
-
-        Ok(result_data[0].clone())
-    }).await?;
+    let response = conn
+        .run_timed(move |c| -> Result<UrlID, rusqlite::Error> {
+            let tx = c.transaction()?;
+            let token = next_token(&tx, data.get_domain())?;
+            tx.execute("
+                INSERT INTO shares (exp, crt, url, owner, redirect_type, passthrough_query, passthrough_path, query_precedence, variants, sticky, rules, nbf, idle_ttl, fallback_url, max_clicks, disabled, title, notes, dest_host, domain, workspace_id, token)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22);
+            ", params![
+                data.get_exp(), data.get_crt(), data.get_dest_url(), data.get_owner(), data.get_redirect_type(),
+                data.get_passthrough_query(), data.get_passthrough_path(), data.get_query_precedence(),
+                data.get_variants(), data.get_sticky(), data.get_rules(), data.get_nbf(),
+                data.get_idle_ttl(), data.get_fallback_url(), data.get_max_clicks(), data.get_disabled(),
+                data.get_title(), data.get_notes(), destination_host(data.get_dest_url()), data.get_domain(),
+                data.get_workspace(), token
+            ])?;
+            let id = tx.last_insert_rowid();
+            tags::set_share_tags(&tx, id, data.get_tags())?;
+            search::reindex_share(&tx, id)?;
+            let created = find_by_id(&tx, id)?.ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+            audit::record(&tx, Action::Create, id, &actor, None, Some(&created))?;
+            history::record_version(&tx, &created, None, &actor, None)?;
+            tx.commit()?;
+            Ok(created)
+        })
+        .await?;
 
+    metrics::METRICS.shares_created.inc();
     Ok(response)
 }
 
 /// This is a non-public function, utilised by Search.find_share(). It will search a database, matching against criteria. It returns a vec of possible elements which may match the query.
 async fn search_database(conn: &SharesDbConn, search: Search) -> Result<Vec<UrlID>, DatabaseError> {
     let result = conn
-        .run(move |c| {
+        .run_timed(move |c| {
+            let mut values = Vec::new();
+            let condition = search.get_search_term(&mut values);
             c.prepare(&format!(
-                "Select * FROM shares WHERE {};",
-                search.get_search_term()
+                "Select {} FROM shares WHERE {};",
+                SHARE_COLUMNS, condition
             ))
             .and_then(
                 |mut res: rusqlite::Statement| -> std::result::Result<Vec<UrlID>, rusqlite::Error> {
-                    res.query_map([], |row| UrlID::from_database(row))
+                    res.query_map(rusqlite::params_from_iter(values), UrlID::from_database)
                         .unwrap()
                         .collect()
                 },
@@ -175,63 +721,656 @@ async fn search_database(conn: &SharesDbConn, search: Search) -> Result<Vec<UrlI
     Ok(result)
 }
 
-/// Update an element in the database, replacing it with a new element.
-#[allow(dead_code)]
+/// Update an element in the database, replacing it with a new element. The change is recorded as a new version in the
+/// share's history, and in the audit log against `actor`.
 pub async fn update_database(
     conn: &SharesDbConn,
     search: Search,
     new_share: UrlID,
-) -> Result<(), DatabaseError> {
+    actor: Actor,
+) -> Result<UrlID, DatabaseError> {
+    write_update(conn, search, new_share, actor, None).await
+}
+
+/// Replace a share with `new_share`, made from an earlier `version` of it. Recorded as a new version in its history
+/// and as a revert in the audit log against `actor`.
+pub async fn revert_to_version(
+    conn: &SharesDbConn,
+    search: Search,
+    new_share: UrlID,
+    version: i64,
+    actor: Actor,
+) -> Result<UrlID, DatabaseError> {
+    write_update(conn, search, new_share, actor, Some(version)).await
+}
+
+/// Write an update to a share, recording it in the share's history and the audit log. Returns the share as written,
+/// with the columns derived from it.
+async fn write_update(
+    conn: &SharesDbConn,
+    search: Search,
+    new_share: UrlID,
+    actor: Actor,
+    reverted_from: Option<i64>,
+) -> Result<UrlID, DatabaseError> {
     let search_result = match search.find_share(conn).await? {
         Some(s) => s,
         None => return Err(DatabaseError::DoesNotExist),
     };
 
-    conn.run(move |c| {
-        //SAFETY: As we are searching by ID to update a share, we shouldn't ever update more than one UrlID at a time.
-        c.execute(
-            "
+    let updated = conn
+        .run_timed(move |c| -> Result<UrlID, rusqlite::Error> {
+            let tx = c.transaction()?;
+            //SAFETY: As we are searching by ID to update a share, we shouldn't ever update more than one UrlID at a time.
+            tx.execute(
+                "
             UPDATE shares
             SET exp = ?1,
                 crt = ?2,
-                url = ?3
+                url = ?3,
+                owner = ?4,
+                redirect_type = ?5,
+                passthrough_query = ?6,
+                passthrough_path = ?7,
+                query_precedence = ?8,
+                variants = ?9,
+                sticky = ?10,
+                rules = ?11,
+                nbf = ?12,
+                idle_ttl = ?13,
+                fallback_url = ?14,
+                max_clicks = ?15,
+                disabled = ?16,
+                title = ?17,
+                notes = ?18,
+                dest_host = ?19
             WHERE
-                id = ?6
+                id = ?20
             ;
         ",
-            params![
-                new_share.get_exp(),
-                new_share.get_crt(),
-                new_share.get_dest_url(),
-                search_result.get_id()
-            ],
-        )
-    })
-    .await?;
-    Ok(())
+                params![
+                    new_share.get_exp(),
+                    new_share.get_crt(),
+                    new_share.get_dest_url(),
+                    new_share.get_owner(),
+                    new_share.get_redirect_type(),
+                    new_share.get_passthrough_query(),
+                    new_share.get_passthrough_path(),
+                    new_share.get_query_precedence(),
+                    new_share.get_variants(),
+                    new_share.get_sticky(),
+                    new_share.get_rules(),
+                    new_share.get_nbf(),
+                    new_share.get_idle_ttl(),
+                    new_share.get_fallback_url(),
+                    new_share.get_max_clicks(),
+                    new_share.get_disabled(),
+                    new_share.get_title(),
+                    new_share.get_notes(),
+                    destination_host(new_share.get_dest_url()),
+                    search_result.get_id()
+                ],
+            )?;
+            tags::set_share_tags(&tx, *search_result.get_id(), new_share.get_tags())?;
+            search::reindex_share(&tx, *search_result.get_id())?;
+            let updated = find_by_id(&tx, *search_result.get_id())?
+                .ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+            let action = match reverted_from {
+                Some(_) => Action::Revert,
+                None => Action::of_update(&search_result, &new_share),
+            };
+            audit::record(
+                &tx,
+                action,
+                *search_result.get_id(),
+                &actor,
+                Some(&search_result),
+                Some(&updated),
+            )?;
+            history::record_version(&tx, &updated, Some(&search_result), &actor, reverted_from)?;
+            tx.commit()?;
+            Ok(updated)
+        })
+        .await?;
+    Ok(updated)
 }
 
-/// Remove a share from the database.
-// WARNING: AS THIS FUNCTION IS UNUSED, IT IS ALSO UNTESTED.
-#[allow(dead_code)]
+/// Move a share to the trash, where it stops resolving until it is restored or purged. Responds DoesNotExist if the
+/// share is already in the trash. The deletion is recorded in the audit log against `actor`.
 pub async fn remove_from_database(
     conn: &SharesDbConn,
     search: Search,
-) -> Result<(), DatabaseError> {
+    actor: Actor,
+) -> Result<UrlID, DatabaseError> {
     let search_result = match search.find_share(conn).await? {
-        Some(s) => s,
-        None => return Err(DatabaseError::DoesNotExist),
+        Some(s) if !s.is_deleted() => s,
+        _ => return Err(DatabaseError::DoesNotExist),
     };
-    conn.run(move |c| {
-        c.execute(
-            "
-        DELETE FROM shares
-        WHERE
-            id = ?1
-        ",
-            params![search_result.get_id()],
-        )
+    set_deleted_at(
+        conn,
+        search_result,
+        Some(get_time_seconds()),
+        Action::Delete,
+        actor,
+    )
+    .await
+}
+
+/// Take a share back out of the trash, recording its restoration in the audit log against `actor`.
+/// Responds DoesNotExist if the share is not in the trash.
+pub async fn restore_share(
+    conn: &SharesDbConn,
+    search: Search,
+    actor: Actor,
+) -> Result<UrlID, DatabaseError> {
+    let search_result = match search.find_share(conn).await? {
+        Some(s) if s.is_deleted() => s,
+        _ => return Err(DatabaseError::DoesNotExist),
+    };
+    set_deleted_at(conn, search_result, None, Action::Restore, actor).await
+}
+
+/// Move a share into or out of the trash, returning it as updated.
+async fn set_deleted_at(
+    conn: &SharesDbConn,
+    share: UrlID,
+    deleted_at: Option<i64>,
+    action: Action,
+    actor: Actor,
+) -> Result<UrlID, DatabaseError> {
+    let updated = conn
+        .run_timed(move |c| -> Result<Option<UrlID>, rusqlite::Error> {
+            let tx = c.transaction()?;
+            tx.execute(
+                "UPDATE shares SET deleted_at = ?1 WHERE id = ?2;",
+                params![deleted_at, share.get_id()],
+            )?;
+            let updated = find_by_id(&tx, *share.get_id())?;
+            audit::record(
+                &tx,
+                action,
+                *share.get_id(),
+                &actor,
+                Some(&share),
+                updated.as_ref(),
+            )?;
+            tx.commit()?;
+            Ok(updated)
+        })
+        .await?;
+    updated.ok_or(DatabaseError::DoesNotExist)
+}
+
+/// Permanently remove every share moved to the trash at or before `cutoff`, along with its clicks and tags,
+/// returning how many were removed. Each removal is recorded in the audit log against `actor`.
+pub async fn purge_deleted(
+    conn: &SharesDbConn,
+    cutoff: i64,
+    actor: Actor,
+) -> Result<usize, DatabaseError> {
+    let removed = conn
+        .run_timed(move |c| -> Result<usize, rusqlite::Error> {
+            let c = c.transaction()?;
+            let trashed: Vec<UrlID> = c
+                .prepare(&format!(
+                    "SELECT {} FROM shares WHERE deleted_at <= ?1;",
+                    SHARE_COLUMNS
+                ))?
+                .query_map(params![cutoff], UrlID::from_database)?
+                .collect::<Result<_, _>>()?;
+            for share in &trashed {
+                audit::record(
+                    &c,
+                    Action::Purge,
+                    *share.get_id(),
+                    &actor,
+                    Some(share),
+                    None,
+                )?;
+            }
+            let removed = c.execute(
+                "DELETE FROM shares WHERE deleted_at <= ?1;",
+                params![cutoff],
+            )?;
+            remove_orphans(&c)?;
+            c.commit()?;
+            Ok(removed)
+        })
+        .await?;
+    Ok(removed)
+}
+
+/// Remove the clicks, tags, versions and search index entries of shares which no longer exist.
+fn remove_orphans(c: &rusqlite::Connection) -> Result<(), rusqlite::Error> {
+    c.execute(
+        "DELETE FROM clicks WHERE share_id NOT IN (SELECT id FROM shares);",
+        [],
+    )?;
+    c.execute(
+        "DELETE FROM share_tags WHERE share_id NOT IN (SELECT id FROM shares);",
+        [],
+    )?;
+    c.execute(
+        "DELETE FROM share_versions WHERE share_id NOT IN (SELECT id FROM shares);",
+        [],
+    )?;
+    search::remove_orphans(c)
+}
+
+/// Read the share with the given id, if it exists.
+pub fn find_by_id(c: &rusqlite::Connection, id: i64) -> Result<Option<UrlID>, rusqlite::Error> {
+    c.query_row(
+        &format!("SELECT {} FROM shares WHERE id = ?1;", SHARE_COLUMNS),
+        params![id],
+        UrlID::from_database,
+    )
+    .optional()
+}
+
+/// Insert a share into the database keeping its existing id and token, used when restoring shares from a backup.
+/// If `replace` is set any share already holding that id will be overwritten. The token must not be held by another
+/// share on the same domain, see [`token_holder`]. Recorded in the audit log against `actor`.
+pub fn insert_share_with_id(
+    c: &rusqlite::Connection,
+    share: &UrlID,
+    replace: bool,
+    actor: &Actor,
+) -> Result<(), rusqlite::Error> {
+    let before = match replace {
+        true => find_by_id(c, *share.get_id())?,
+        false => None,
+    };
+    let verb = if replace {
+        "INSERT OR REPLACE"
+    } else {
+        "INSERT"
+    };
+    c.execute(
+        &format!(
+            "{} INTO shares (id, exp, crt, url, owner, redirect_type, passthrough_query, passthrough_path, query_precedence, variants, sticky, rules, nbf, idle_ttl, last_access, fallback_url, max_clicks, disabled, title, notes, dest_host, deleted_at, domain, workspace_id, token)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25);",
+            verb
+        ),
+        params![
+            share.get_id(),
+            share.get_exp(),
+            share.get_crt(),
+            share.get_dest_url(),
+            share.get_owner(),
+            share.get_redirect_type(),
+            share.get_passthrough_query(),
+            share.get_passthrough_path(),
+            share.get_query_precedence(),
+            share.get_variants(),
+            share.get_sticky(),
+            share.get_rules(),
+            share.get_nbf(),
+            share.get_idle_ttl(),
+            share.get_last_access(),
+            share.get_fallback_url(),
+            share.get_max_clicks(),
+            share.get_disabled(),
+            share.get_title(),
+            share.get_notes(),
+            destination_host(share.get_dest_url()),
+            share.get_deleted_at(),
+            share.get_domain(),
+            share.get_workspace(),
+            share.get_token()
+        ],
+    )?;
+    claim_token(c, share.get_domain(), share.get_token())?;
+    tags::set_share_tags(c, *share.get_id(), share.get_tags())?;
+    search::reindex_share(c, *share.get_id())?;
+    let action = match &before {
+        Some(before) => Action::of_update(before, share),
+        None => Action::Create,
+    };
+    let after = find_by_id(c, *share.get_id())?;
+    audit::record(
+        c,
+        action,
+        *share.get_id(),
+        actor,
+        before.as_ref(),
+        after.as_ref(),
+    )?;
+    match &after {
+        Some(after) => history::record_version(c, after, before.as_ref(), actor, None),
+        None => Ok(()),
+    }
+}
+
+/// Check whether a share with the given id exists in the database.
+pub fn share_exists(c: &rusqlite::Connection, id: i64) -> Result<bool, rusqlite::Error> {
+    c.query_row(
+        "SELECT EXISTS(SELECT 1 FROM shares WHERE id = ?1);",
+        params![id],
+        |row| row.get(0),
+    )
+}
+
+/// Return every share in the database, ordered by id.
+pub async fn all_shares(conn: &SharesDbConn) -> Result<Vec<UrlID>, DatabaseError> {
+    let result = conn
+        .run_timed(|c| {
+            c.prepare(&format!(
+                "SELECT {} FROM shares ORDER BY id ASC;",
+                SHARE_COLUMNS
+            ))
+            .and_then(|mut res| res.query_map([], UrlID::from_database)?.collect())
+        })
+        .await?;
+    Ok(result)
+}
+
+/// Permanently remove every share which expired before the given time, including those unused for longer than their idle ttl,
+/// returning how many were removed. Each removal is recorded in the audit log against `actor`.
+pub async fn purge_expired(
+    conn: &SharesDbConn,
+    now: i64,
+    actor: Actor,
+) -> Result<usize, DatabaseError> {
+    let removed = conn
+        .run_timed(move |c| -> Result<usize, rusqlite::Error> {
+            let c = c.transaction()?;
+            let expired: Vec<UrlID> = c
+                .prepare(&format!(
+                    "SELECT {} FROM shares WHERE {};",
+                    SHARE_COLUMNS, EXPIRED
+                ))?
+                .query_map(params![now], UrlID::from_database)?
+                .collect::<Result<_, _>>()?;
+            for share in &expired {
+                audit::record(
+                    &c,
+                    Action::Purge,
+                    *share.get_id(),
+                    &actor,
+                    Some(share),
+                    None,
+                )?;
+            }
+            let removed = c.execute(
+                &format!("DELETE FROM shares WHERE {};", EXPIRED),
+                params![now],
+            )?;
+            remove_orphans(&c)?;
+            c.commit()?;
+            Ok(removed)
+        })
+        .await?;
+    Ok(removed)
+}
+
+/// Store a new api key in the database, keeping only a hash of its secret, returning it with its id.
+pub async fn add_api_key(
+    conn: &SharesDbConn,
+    key: String,
+    owner: String,
+    admin: bool,
+) -> Result<ApiKey, DatabaseError> {
+    let result = conn
+        .run_timed(move |c| -> Result<ApiKey, rusqlite::Error> {
+            c.execute(
+                "INSERT INTO api_keys (key_hash, owner, admin, crt) VALUES (?1, ?2, ?3, ?4);",
+                params![
+                    crate::auth::hash_key(&key),
+                    owner,
+                    admin,
+                    get_time_seconds()
+                ],
+            )?;
+            c.query_row(
+                "SELECT * FROM api_keys WHERE id = ?1;",
+                params![c.last_insert_rowid()],
+                ApiKey::from_database,
+            )
+        })
+        .await?;
+    Ok(result)
+}
+
+/// Find the api key matching the given secret, if any.
+pub async fn find_api_key(
+    conn: &SharesDbConn,
+    key: String,
+) -> Result<Option<ApiKey>, DatabaseError> {
+    let result = conn
+        .run_timed(move |c| {
+            c.query_row(
+                "SELECT * FROM api_keys WHERE key_hash = ?1;",
+                params![crate::auth::hash_key(&key)],
+                ApiKey::from_database,
+            )
+            .optional()
+        })
+        .await?;
+    Ok(result)
+}
+
+/// List every api key in the database.
+pub async fn list_api_keys(conn: &SharesDbConn) -> Result<Vec<ApiKey>, DatabaseError> {
+    let result = conn
+        .run_timed(|c| {
+            c.prepare("SELECT * FROM api_keys ORDER BY id ASC;")
+                .and_then(|mut res| res.query_map([], ApiKey::from_database)?.collect())
+        })
+        .await?;
+    Ok(result)
+}
+
+/// Revoke an api key by removing it from the database.
+pub async fn remove_api_key(conn: &SharesDbConn, id: i64) -> Result<(), DatabaseError> {
+    let removed = conn
+        .run_timed(move |c| c.execute("DELETE FROM api_keys WHERE id = ?1;", params![id]))
+        .await?;
+    if removed == 0 {
+        return Err(DatabaseError::DoesNotExist);
+    }
+    Ok(())
+}
+
+/// Count the shares in the database, returning both the total and those which have not expired by `now`. Shares in the
+/// trash are not counted.
+pub async fn count_shares(conn: &SharesDbConn, now: i64) -> Result<(i64, i64), DatabaseError> {
+    let result = conn
+        .run_timed(move |c| {
+            c.query_row(
+                &format!(
+                    "SELECT COUNT(*), COALESCE(SUM(NOT {}), 0) FROM shares WHERE deleted_at IS NULL;",
+                    EXPIRED
+                ),
+                params![now],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+        })
+        .await?;
+    Ok(result)
+}
+
+/// Record when shares were last followed, given as a map of share id to access time. Written in a single transaction,
+/// and never moving a share's last access backwards.
+pub async fn record_accesses(
+    conn: &SharesDbConn,
+    accesses: std::collections::HashMap<i64, i64>,
+) -> Result<(), DatabaseError> {
+    conn.run_timed(move |c| -> Result<(), rusqlite::Error> {
+        let tx = c.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "UPDATE shares SET last_access = MAX(COALESCE(last_access, 0), ?2) WHERE id = ?1;",
+            )?;
+            for (id, at) in accesses {
+                stmt.execute(params![id, at])?;
+            }
+        }
+        tx.commit()
+    })
+    .await?;
+    Ok(())
+}
+
+/// A click on a share, along with the variant the visitor was sent to if the share has several destinations, or why
+/// they were sent to its fallback instead.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Click {
+    pub share_id: i64,
+    pub ts: i64,
+    pub variant: Option<usize>,
+    pub fallback: Option<Unavailable>,
+}
+
+/// Record a batch of clicks in a single transaction.
+pub async fn record_clicks(conn: &SharesDbConn, clicks: Vec<Click>) -> Result<(), DatabaseError> {
+    conn.run_timed(move |c| -> Result<(), rusqlite::Error> {
+        let tx = c.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO clicks (share_id, ts, variant, fallback) VALUES (?1, ?2, ?3, ?4);",
+            )?;
+            for click in clicks {
+                let variant = click.variant.map(|v| v as i64);
+                stmt.execute(params![click.share_id, click.ts, variant, click.fallback])?;
+            }
+        }
+        tx.commit()
     })
     .await?;
     Ok(())
 }
+
+/// Count the clicks which were redirected to a share's destination, excluding those sent to its fallback.
+pub async fn count_redirects(conn: &SharesDbConn, share_id: i64) -> Result<i64, DatabaseError> {
+    let result = conn
+        .run_timed(move |c| {
+            c.query_row(
+                "SELECT COUNT(*) FROM clicks WHERE share_id = ?1 AND fallback IS NULL;",
+                params![share_id],
+                |row| row.get(0),
+            )
+        })
+        .await?;
+    Ok(result)
+}
+
+/// Count the clicks on a share redirected to its destination, grouped by the variant visitors were sent to
+/// (None for shares with a single destination).
+pub async fn count_clicks(
+    conn: &SharesDbConn,
+    share_id: i64,
+) -> Result<Vec<(Option<i64>, i64)>, DatabaseError> {
+    let result = conn
+        .run_timed(move |c| {
+            c.prepare(
+                "SELECT variant, COUNT(*) FROM clicks WHERE share_id = ?1 AND fallback IS NULL GROUP BY variant ORDER BY variant;",
+            )
+            .and_then(|mut res| {
+                res.query_map(params![share_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect()
+            })
+        })
+        .await?;
+    Ok(result)
+}
+
+/// Count the clicks on a share sent to its fallback, grouped by why the share was unavailable.
+pub async fn count_fallbacks(
+    conn: &SharesDbConn,
+    share_id: i64,
+) -> Result<Vec<(String, i64)>, DatabaseError> {
+    let result = conn
+        .run_timed(move |c| {
+            c.prepare(
+                "SELECT fallback, COUNT(*) FROM clicks WHERE share_id = ?1 AND fallback IS NOT NULL GROUP BY fallback ORDER BY fallback;",
+            )
+            .and_then(|mut res| {
+                res.query_map(params![share_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect()
+            })
+        })
+        .await?;
+    Ok(result)
+}
+
+#[test]
+fn test_migrations_clear_never_expiring_sentinel() {
+    // The migration making exp nullable.
+    const NULLABLE_EXP: usize = 8;
+    let c = rusqlite::Connection::open_in_memory().unwrap();
+    for migration in &MIGRATIONS[..NULLABLE_EXP] {
+        c.execute_batch(migration).unwrap();
+    }
+    c.execute_batch(
+        "INSERT INTO shares (id, exp, crt, url) VALUES (1, 9223372036854775807, 0, 'https://a.example');
+         INSERT INTO shares (id, exp, crt, url) VALUES (2, 100, 0, 'https://b.example');",
+    )
+    .unwrap();
+    c.execute_batch(MIGRATIONS[NULLABLE_EXP]).unwrap();
+    let exps: Vec<Option<i64>> = c
+        .prepare("SELECT exp FROM shares ORDER BY id;")
+        .unwrap()
+        .query_map([], |row| row.get(0))
+        .unwrap()
+        .collect::<Result<_, _>>()
+        .unwrap();
+    assert_eq!(exps, vec![None, Some(100)]);
+}
+
+#[test]
+fn test_migrations_escape_braces() {
+    let dir = tempfile::tempdir().unwrap();
+    let c = rusqlite::Connection::open(dir.path().join("database.db")).unwrap();
+    for migration in &MIGRATIONS[..BRACE_MIGRATION] {
+        c.execute_batch(migration).unwrap();
+    }
+    c.execute_batch(&format!(
+        "INSERT INTO shares (id, exp, crt, url) VALUES (1, 9223372036854775807, 0, 'https://a.example/{{1}}?q={{term}}&json={{{{}}}}');
+         PRAGMA user_version = {};",
+        BRACE_MIGRATION
+    ))
+    .unwrap();
+    drop(c);
+    let client =
+        rocket::local::blocking::Client::tracked(crate::build(crate::test_figment(&dir))).unwrap();
+    assert_eq!(
+        client.get("/setup").dispatch().status(),
+        rocket::http::Status::Ok
+    );
+    let res = client
+        .get(format!("/{}", crate::url_id::number_to_token(1)))
+        .dispatch();
+    assert_eq!(
+        res.headers().get_one("Location"),
+        Some("https://a.example/{1}?q={term}&json={{}}")
+    );
+}
+
+#[test]
+fn test_migrations_never_reuse_ids() {
+    let c = rusqlite::Connection::open_in_memory().unwrap();
+    for migration in &MIGRATIONS[..AUTOINCREMENT_MIGRATION] {
+        c.execute_batch(migration).unwrap();
+    }
+    // Share 3 was purged, leaving only its audit log entry behind.
+    c.execute_batch(
+        "INSERT INTO shares (id, crt, url, deleted_at) VALUES (1, 0, 'https://a.example', 5);
+         INSERT INTO shares (id, crt, url) VALUES (2, 0, 'https://b.example');
+         INSERT INTO audit_log (ts, action, share_id) VALUES (0, 'purge', 3);",
+    )
+    .unwrap();
+    c.execute_batch(MIGRATIONS[AUTOINCREMENT_MIGRATION])
+        .unwrap();
+    let deleted_at: Option<i64> = c
+        .query_row("SELECT deleted_at FROM shares WHERE id = 1;", [], |row| {
+            row.get(0)
+        })
+        .unwrap();
+    assert_eq!(deleted_at, Some(5));
+    c.execute_batch(
+        "DELETE FROM shares WHERE id = 2;
+         INSERT INTO shares (crt, url) VALUES (0, 'https://c.example');",
+    )
+    .unwrap();
+    assert_eq!(c.last_insert_rowid(), 4);
+}
@@ -0,0 +1,226 @@
+//! Serving links on several domains from one deployment. Each share belongs to the domain it was created on and only
+//! resolves there, so hosts such as `go.example.com` and `s.brand.io` keep separate sets of links. Shares without a
+//! domain belong to the server's own domain, [`crate::SERVER_DOMAIN`], as do requests to any host not configured.
+//!
+//! Every domain has a token space of its own: tokens are numbered from one on each domain, so `go.example.com/abc`
+//! and `s.brand.io/abc` may be different links. Shares keep an id unique across every domain, which is what the
+//! rest of the database refers to them by.
+use crate::auth::Principal;
+use crate::config::Config;
+use rocket::http::Status;
+use rocket::outcome::Outcome::*;
+use rocket::request::{self, FromRequest, Request};
+use serde::Deserialize;
+
+/// A domain links are served on beside the server's own, as configured in `domains`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct DomainConfig {
+    /// The host links on this domain are served from, with its port if it is not the default.
+    pub host: String,
+    /// The owners whose api keys may create links on this domain. Admins always may. If unset anyone may.
+    #[serde(default)]
+    pub owners: Option<Vec<String>>,
+}
+
+/// An enum representing the reasons a share may not be created on a domain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DomainError {
+    NotServed(String),
+    Forbidden(String),
+}
+
+impl std::fmt::Display for DomainError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DomainError::NotServed(host) => write!(f, "links are not served on '{}'", host),
+            DomainError::Forbidden(host) => {
+                write!(
+                    f,
+                    "the provided bearer token may not create links on '{}'",
+                    host
+                )
+            }
+        }
+    }
+}
+
+impl From<DomainError> for (Status, String) {
+    fn from(err: DomainError) -> (Status, String) {
+        let status = match err {
+            DomainError::NotServed(_) => Status::BadRequest,
+            DomainError::Forbidden(_) => Status::Forbidden,
+        };
+        (status, err.to_string())
+    }
+}
+
+/// Hosts are compared ignoring case and any trailing dot.
+pub fn normalize(host: &str) -> String {
+    host.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Find the configured domain served on `host`, or None if it is the server's own domain.
+pub fn lookup<'a>(config: &'a Config, host: &str) -> Result<Option<&'a DomainConfig>, DomainError> {
+    let host = normalize(host);
+    if host == crate::SERVER_DOMAIN {
+        return Ok(None);
+    }
+    config
+        .domains
+        .iter()
+        .find(|d| normalize(&d.host) == host)
+        .map(Some)
+        .ok_or(DomainError::NotServed(host))
+}
+
+/// Check `principal` may create links on `host`, returning the domain to record against the share.
+/// None is returned for the server's own domain, on which anyone may create links.
+pub fn authorize(
+    config: &Config,
+    host: &str,
+    principal: Option<&Principal>,
+) -> Result<Option<String>, DomainError> {
+    let domain = match lookup(config, host)? {
+        Some(domain) => domain,
+        None => return Ok(None),
+    };
+    let allowed = match (&domain.owners, principal) {
+        (None, _) => true,
+        (Some(_), Some(p)) if p.admin => true,
+        (Some(owners), Some(p)) => owners.contains(&p.owner),
+        (Some(_), None) => false,
+    };
+    match allowed {
+        true => Ok(Some(normalize(&domain.host))),
+        false => Err(DomainError::Forbidden(normalize(&domain.host))),
+    }
+}
+
+/// The configured domain a request was made to, from its `Host` header.
+/// None if it was made to the server's own domain, or to a host which is not configured.
+pub struct RequestDomain(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestDomain {
+    type Error = std::convert::Infallible;
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let domain = match (
+            req.rocket().state::<Config>(),
+            req.headers().get_one("Host"),
+        ) {
+            (Some(config), Some(host)) => lookup(config, host)
+                .ok()
+                .flatten()
+                .map(|d| normalize(&d.host)),
+            _ => None,
+        };
+        Success(RequestDomain(domain))
+    }
+}
+
+#[test]
+fn test_domains() {
+    use rocket::http::{ContentType, Header};
+    let (client, dir) = crate::test_client_with(|f| {
+        f.merge((
+            "domains",
+            serde_json::json!([
+                { "host": "s.brand.io", "owners": ["brand"] },
+                { "host": "go.example.com" }
+            ]),
+        ))
+    });
+    let c =
+        rocket_sync_db_pools::rusqlite::Connection::open(dir.path().join("database.db")).unwrap();
+    crate::insert_api_keys(
+        &c,
+        &[("alice-key", "alice", false), ("brand-key", "brand", false)],
+    );
+    let shorten = |key: &str, host: Option<&str>, body: &str| {
+        let mut req = client
+            .post("/shorten")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", format!("Bearer {}", key)))
+            .body(body);
+        if let Some(host) = host {
+            req = req.header(Header::new("Host", host.to_owned()));
+        }
+        req.dispatch()
+    };
+    let follow = |link: &str, host: Option<&str>| {
+        let token = link.rsplit('/').next().unwrap();
+        let mut req = client.get(format!("/{}", token));
+        if let Some(host) = host {
+            req = req.header(Header::new("Host", host.to_owned()));
+        }
+        let res = req.dispatch();
+        res.headers().get_one("Location").map(str::to_owned)
+    };
+    let token = |link: &str| link.rsplit('/').next().unwrap().to_owned();
+    let number = |link: &str| crate::url_id::token_to_number(&token(link));
+    let brand = Some("https://brand.example".to_owned());
+    let alice = Some("https://a.example".to_owned());
+    let admin = Some("https://c.example".to_owned());
+
+    let brand_link = r#"{"url":"https://brand.example","domain":"s.brand.io"}"#;
+    assert_eq!(
+        shorten("alice-key", None, brand_link).status(),
+        Status::Forbidden
+    );
+    let res = shorten(
+        "alice-key",
+        None,
+        r#"{"url":"https://a.example","domain":"evil.example"}"#,
+    );
+    assert_eq!(res.status(), Status::BadRequest);
+    let brand_link = shorten("brand-key", None, brand_link)
+        .into_string()
+        .unwrap();
+    assert!(brand_link.starts_with("http://s.brand.io/"));
+    assert_eq!(follow(&brand_link, Some("s.brand.io")), brand);
+    assert_eq!(follow(&brand_link, Some("S.Brand.IO")), brand);
+    assert_eq!(follow(&brand_link, Some("go.example.com")), None);
+    assert_eq!(follow(&brand_link, None), None);
+
+    // Without a domain, links are created on the domain the request was made to.
+    let link = shorten(
+        "alice-key",
+        Some("go.example.com"),
+        r#"{"url":"https://a.example"}"#,
+    )
+    .into_string()
+    .unwrap();
+    assert!(link.starts_with("http://go.example.com/"));
+    assert_eq!(
+        shorten(
+            "alice-key",
+            Some("s.brand.io"),
+            r#"{"url":"https://a.example"}"#
+        )
+        .status(),
+        Status::Forbidden
+    );
+
+    // Each domain counts its tokens separately, so the same token leads to a different link on each.
+    let link = shorten("admin", None, r#"{"url":"https://c.example"}"#)
+        .into_string()
+        .unwrap();
+    assert!(link.starts_with(&format!("http://{}/", crate::SERVER_DOMAIN)));
+    assert_eq!(number(&link), number(&brand_link));
+    assert_eq!(follow(&link, None), admin);
+    assert_eq!(follow(&link, Some("unknown.example")), admin);
+    assert_eq!(follow(&link, Some("go.example.com")), alice);
+    assert_eq!(follow(&link, Some("s.brand.io")), brand);
+
+    // The api finds shares by their token on the domain it is called on.
+    let rules = |host: &str| {
+        client
+            .get(format!("/api/shares/{}/rules", token(&brand_link)))
+            .header(Header::new("Authorization", "Bearer brand-key"))
+            .header(Header::new("Host", host.to_owned()))
+            .dispatch()
+            .status()
+    };
+    assert_eq!(rules("s.brand.io"), Status::Ok);
+    assert_eq!(rules("go.example.com"), Status::Forbidden);
+}
@@ -0,0 +1,266 @@
+//! Events describing changes to shares and clicks on them. Each event is published on an in-process broadcast channel,
+//! streamed live to subscribers of `GET /api/events`, and queued for delivery to any subscribed webhook.
+use crate::auth::Principal;
+use crate::common::get_time_seconds;
+use crate::database::SharesDbConn;
+use crate::domains::RequestDomain;
+use crate::url_id::{token_to_number, UrlID};
+use crate::webhooks;
+use crate::workspaces::Role;
+use rocket::http::Status;
+use rocket::response::stream::{Event as SseEvent, EventStream};
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::{self, error::RecvError};
+use rocket::{Shutdown, State};
+use serde::{Deserialize, Serialize};
+
+/// How many events may be waiting for a slow subscriber before the oldest are dropped.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// The kinds of event concerning a share.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Event {
+    #[serde(rename = "share.created")]
+    Created,
+    #[serde(rename = "share.updated")]
+    Updated,
+    #[serde(rename = "share.deleted")]
+    Deleted,
+    #[serde(rename = "share.restored")]
+    Restored,
+    #[serde(rename = "share.clicked")]
+    Clicked,
+}
+
+impl Event {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Event::Created => "share.created",
+            Event::Updated => "share.updated",
+            Event::Deleted => "share.deleted",
+            Event::Restored => "share.restored",
+            Event::Clicked => "share.clicked",
+        }
+    }
+}
+
+/// Something which happened to a share, serialized as sent to event stream subscribers and webhooks.
+#[derive(Debug, Clone, Serialize)]
+pub struct ShareEvent {
+    pub event: Event,
+    pub timestamp: i64,
+    /// The share as it was after the event, alongside its link.
+    share: serde_json::Value,
+    /// Details specific to the kind of event, such as the variant a click was sent to.
+    data: serde_json::Value,
+    #[serde(skip)]
+    domain: Option<String>,
+    #[serde(skip)]
+    token: i64,
+    #[serde(skip)]
+    owner: Option<String>,
+    #[serde(skip)]
+    workspace: Option<i64>,
+}
+
+impl ShareEvent {
+    pub fn new(event: Event, share: &UrlID, data: serde_json::Value) -> Self {
+        let mut described = serde_json::to_value(share).unwrap_or_default();
+        described["link"] = share.get_shortened_link().into();
+        ShareEvent {
+            event,
+            timestamp: get_time_seconds(),
+            share: described,
+            data,
+            domain: share.get_domain().map(str::to_owned),
+            token: share.get_token(),
+            owner: share.get_owner().map(str::to_owned),
+            workspace: share.get_workspace(),
+        }
+    }
+
+    /// Who owns the share the event concerns.
+    pub fn owner(&self) -> Option<&str> {
+        self.owner.as_deref()
+    }
+}
+
+/// The channel events are published on, managed by rocket.
+pub struct EventBus {
+    sender: broadcast::Sender<ShareEvent>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus {
+            sender: broadcast::channel(CHANNEL_CAPACITY).0,
+        }
+    }
+
+    /// Announce an event to live subscribers, and queue it for any subscribed webhook.
+    pub async fn publish(&self, conn: &SharesDbConn, event: ShareEvent) {
+        webhooks::emit(conn, &event).await;
+        self.announce(event);
+    }
+
+    /// Announce an event to live subscribers only, for events whose webhook deliveries are queued separately.
+    pub fn announce(&self, event: ShareEvent) {
+        // Sending only fails when nobody is listening, which is not a problem.
+        let _ = self.sender.send(event);
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus::new()
+    }
+}
+
+/// Which events a subscriber is sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Filter {
+    owner: Option<String>,
+    workspace: Option<i64>,
+    /// The domain and token number of the one share followed, if only one is.
+    token: Option<(Option<String>, i64)>,
+}
+
+impl Filter {
+    fn matches(&self, event: &ShareEvent) -> bool {
+        self.owner
+            .as_deref()
+            .is_none_or(|owner| event.owner() == Some(owner))
+            && self.workspace.is_none_or(|id| event.workspace == Some(id))
+            && self
+                .token
+                .as_ref()
+                .is_none_or(|(domain, token)| event.domain == *domain && event.token == *token)
+    }
+}
+
+/// Stream events as they happen, as server-sent events named after the kind of event with the event as json data.
+/// Admins receive events for every share unless they filter by `owner`, while others only receive events for their
+/// own shares, or for the shares of a `workspace` they are a member of. Any may follow a single share by its `token`,
+/// on the domain the request is made to.
+/// Subscribers too slow to keep up are sent a comment saying how many events they missed.
+#[get("/events?<owner>&<workspace>&<token>")]
+fn stream_events(
+    owner: Option<String>,
+    workspace: Option<i64>,
+    token: Option<String>,
+    host: RequestDomain,
+    principal: Principal,
+    bus: &State<EventBus>,
+    mut shutdown: Shutdown,
+) -> Result<EventStream![], (Status, String)> {
+    if workspace.is_some_and(|id| !principal.holds(id, Role::Viewer)) {
+        return Err((
+            Status::Forbidden,
+            "the provided bearer token lacks permission".into(),
+        ));
+    }
+    let owner = match (principal.admin || workspace.is_some(), owner) {
+        (true, owner) => owner,
+        (false, Some(owner)) if owner != principal.owner => {
+            return Err((
+                Status::Forbidden,
+                "only admins may follow the events of another owner's shares".into(),
+            ))
+        }
+        (false, _) => Some(principal.owner),
+    };
+    let token = match token {
+        Some(token) => Some(
+            token_to_number(&token)
+                .map(|n| (host.0, n))
+                .ok_or_else(|| {
+                    (
+                        Status::BadRequest,
+                        format!("'{}' is not a valid token", token),
+                    )
+                })?,
+        ),
+        None => None,
+    };
+    let filter = Filter {
+        owner,
+        workspace,
+        token,
+    };
+    // Subscribe before responding, so no event published after the request is answered is missed.
+    let mut events = bus.sender.subscribe();
+    Ok(EventStream! {
+        loop {
+            let received = select! {
+                received = events.recv() => received,
+                _ = &mut shutdown => break,
+            };
+            match received {
+                Ok(event) if filter.matches(&event) => {
+                    yield SseEvent::json(&event).event(event.event.as_str());
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => {
+                    yield SseEvent::comment(format!("{} events were missed", missed));
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![stream_events]
+}
+
+#[rocket::async_test]
+async fn test_event_stream() {
+    use rocket::http::{ContentType, Header, Status};
+    use rocket::tokio::io::AsyncReadExt;
+    use rocket::tokio::time::{timeout, Duration};
+
+    let (client, dir) = crate::test_async_client_with(|f| f).await;
+    let c =
+        rocket_sync_db_pools::rusqlite::Connection::open(dir.path().join("database.db")).unwrap();
+    crate::insert_api_keys(&c, &[("alice-key", "alice", false)]);
+    let alice = || Header::new("Authorization", "Bearer alice-key");
+    let res = client
+        .get("/api/events?owner=bob")
+        .header(alice())
+        .dispatch()
+        .await;
+    assert_eq!(res.status(), Status::Forbidden);
+
+    let mut stream = client.get("/api/events").header(alice()).dispatch().await;
+    assert_eq!(stream.status(), Status::Ok);
+    let shorten = |key: &'static str, url: &'static str| {
+        client
+            .post("/shorten")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", format!("Bearer {}", key)))
+            .body(format!(r#"{{"url":"{}"}}"#, url))
+            .dispatch()
+    };
+    shorten("admin", "https://admin.example").await;
+    let link = shorten("alice-key", "https://alice.example")
+        .await
+        .into_string()
+        .await
+        .unwrap();
+    let token = link.rsplit('/').next().unwrap().to_owned();
+    client.get(format!("/{}", token)).dispatch().await;
+
+    let mut received = String::new();
+    let mut buf = [0; 4096];
+    while !received.contains("event:share.clicked") {
+        let read = timeout(Duration::from_secs(10), stream.read(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(read > 0, "the event stream ended early");
+        received.push_str(std::str::from_utf8(&buf[..read]).unwrap());
+    }
+    assert!(received.contains("event:share.created"));
+    assert!(received.contains("https://alice.example"));
+    assert!(!received.contains("https://admin.example"));
+}
@@ -0,0 +1,236 @@
+//! Parsing of the times and durations accepted for a share's `exp` and `nbf`, and the server's ttl policy.
+//! A time may be given as seconds since the unix epoch, an ISO-8601 timestamp such as `2024-06-01T09:00:00Z`,
+//! or a duration from now such as `7d`, `1d12h` or the ISO-8601 `PT12H`.
+use crate::config::Config;
+use crate::url_id::UrlIDError;
+use rocket::time::format_description::well_known::Iso8601;
+use rocket::time::{Date, OffsetDateTime, PrimitiveDateTime, Time};
+use serde::{Deserialize, Deserializer};
+
+/// Parse a duration into seconds. Both the ISO-8601 form (`P1DT12H`, `PT30M`, `P2W`) and a short form of
+/// numbers followed by units (`7d`, `1d12h`, `90s`) are accepted. Years and months are rejected, as their length varies.
+pub fn parse_duration(input: &str) -> Result<i64, String> {
+    let invalid = |reason: &str| format!("'{}' is not a valid duration: {}", input, reason);
+    let (iso, body) = match input.strip_prefix('P') {
+        Some(body) => (true, body),
+        None => (false, input),
+    };
+    if body.is_empty() {
+        return Err(invalid("no units given"));
+    }
+    let mut seconds: i64 = 0;
+    let mut number = String::new();
+    let mut in_time = !iso;
+    for c in body.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+        if iso && c == 'T' && number.is_empty() && !in_time {
+            in_time = true;
+            continue;
+        }
+        let unit = match (c.to_ascii_lowercase(), in_time, iso) {
+            ('w', false, true) | ('w', _, false) => 7 * 86400,
+            ('d', false, true) | ('d', _, false) => 86400,
+            ('h', true, _) => 3600,
+            ('m', true, _) => 60,
+            ('s', true, _) => 1,
+            ('y', _, _) | ('m', false, _) => {
+                return Err(invalid("years and months are not supported, use days"))
+            }
+            _ => return Err(invalid(&format!("unexpected '{}'", c))),
+        };
+        let n: i64 = number
+            .parse()
+            .map_err(|_| invalid(&format!("expected a number before '{}'", c)))?;
+        seconds = n
+            .checked_mul(unit)
+            .and_then(|s| seconds.checked_add(s))
+            .ok_or_else(|| invalid("too large"))?;
+        number.clear();
+    }
+    if !number.is_empty() {
+        return Err(invalid("missing a unit after the last number"));
+    }
+    Ok(seconds)
+}
+
+/// Parse a length of time given either in seconds or as a duration accepted by [`parse_duration`].
+pub fn parse_ttl(input: &str) -> Result<i64, String> {
+    input
+        .trim()
+        .parse::<i64>()
+        .or_else(|_| parse_duration(input.trim()))
+}
+
+/// Parse an ISO-8601 timestamp into seconds since the unix epoch. Timestamps without an offset are taken to be utc,
+/// and a date alone refers to midnight at its start.
+fn parse_timestamp(input: &str) -> Option<i64> {
+    if let Ok(t) = OffsetDateTime::parse(input, &Iso8601::DEFAULT) {
+        return Some(t.unix_timestamp());
+    }
+    if let Ok(t) = PrimitiveDateTime::parse(input, &Iso8601::DEFAULT) {
+        return Some(t.assume_utc().unix_timestamp());
+    }
+    Date::parse(input, &Iso8601::DEFAULT)
+        .ok()
+        .map(|d| d.with_time(Time::MIDNIGHT).assume_utc().unix_timestamp())
+}
+
+/// Parse a time given as seconds since the unix epoch, an ISO-8601 timestamp, or a duration from `now`.
+pub fn parse_time(input: &str, now: i64) -> Result<i64, String> {
+    let input = input.trim();
+    if let Ok(seconds) = input.parse::<i64>() {
+        return Ok(seconds);
+    }
+    if let Some(t) = parse_timestamp(input) {
+        return Ok(t);
+    }
+    parse_duration(input)
+        .map(|d| now.saturating_add(d))
+        .map_err(|_| {
+            format!(
+                "'{}' is not a valid time, expected seconds since the unix epoch, an ISO-8601 timestamp, or a duration such as 7d or PT12H",
+                input
+            )
+        })
+}
+
+/// A time as it may appear in json, either seconds since the unix epoch or a string accepted by [`parse_time`].
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TimeInput {
+    Seconds(i64),
+    Text(String),
+}
+
+impl TimeInput {
+    fn resolve(self, now: i64) -> Result<i64, String> {
+        match self {
+            TimeInput::Seconds(s) => Ok(s),
+            TimeInput::Text(s) => parse_time(&s, now),
+        }
+    }
+}
+
+/// Deserialize an optional time in any of the accepted formats, durations being measured from now.
+pub fn deserialize_time<'de, D: Deserializer<'de>>(d: D) -> Result<Option<i64>, D::Error> {
+    let now = crate::common::get_time_seconds();
+    Option::<TimeInput>::deserialize(d)?
+        .map(|t| t.resolve(now))
+        .transpose()
+        .map_err(serde::de::Error::custom)
+}
+
+/// Deserialize a time which may be explicitly cleared, distinguishing an absent field (None) from null (Some(None)).
+/// Use alongside `#[serde(default)]`.
+pub fn deserialize_time_update<'de, D: Deserializer<'de>>(
+    d: D,
+) -> Result<Option<Option<i64>>, D::Error> {
+    deserialize_time(d).map(Some)
+}
+
+/// Deserialize the expiry of a stored share. Shares exported before expiry was nullable marked "never" with `i64::MAX`.
+pub fn deserialize_stored_exp<'de, D: Deserializer<'de>>(d: D) -> Result<Option<i64>, D::Error> {
+    Ok(Option::<i64>::deserialize(d)?.filter(|exp| *exp != i64::MAX))
+}
+
+/// Deserialize an optional length of time, given in seconds or as a duration such as `30d`.
+pub fn deserialize_duration<'de, D: Deserializer<'de>>(d: D) -> Result<Option<i64>, D::Error> {
+    Ok(Option::<Ttl>::deserialize(d)?.map(|t| t.0))
+}
+
+/// Deserialize a length of time which may be explicitly cleared, as with [`deserialize_time_update`].
+pub fn deserialize_duration_update<'de, D: Deserializer<'de>>(
+    d: D,
+) -> Result<Option<Option<i64>>, D::Error> {
+    deserialize_duration(d).map(Some)
+}
+
+/// A length of time set in the configuration, either in seconds or as a duration such as `30d`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "TimeInput")]
+pub struct Ttl(pub i64);
+
+impl TryFrom<TimeInput> for Ttl {
+    type Error = String;
+    fn try_from(input: TimeInput) -> Result<Self, Self::Error> {
+        match input {
+            TimeInput::Seconds(s) => Ok(Ttl(s)),
+            TimeInput::Text(s) => parse_ttl(&s).map(Ttl),
+        }
+    }
+}
+
+/// Apply the configured ttl policy to the expiry of a new share. Shares without an expiry receive the default ttl,
+/// or failing that the maximum ttl, and an expiry further away than the maximum ttl is refused.
+pub fn apply_ttl(exp: Option<i64>, now: i64, config: &Config) -> Result<Option<i64>, UrlIDError> {
+    let exp = exp.or_else(|| {
+        config
+            .default_ttl
+            .or(config.max_ttl)
+            .map(|ttl| now.saturating_add(ttl.0))
+    });
+    check_max_ttl(exp, now, config)?;
+    Ok(exp)
+}
+
+/// Refuse an expiry further away than the configured maximum ttl, including never expiring.
+pub fn check_max_ttl(exp: Option<i64>, now: i64, config: &Config) -> Result<(), UrlIDError> {
+    let max = match config.max_ttl {
+        Some(max) => now.saturating_add(max.0),
+        None => return Ok(()),
+    };
+    match exp {
+        Some(exp) if exp <= max => Ok(()),
+        _ => Err(UrlIDError::ParseFailure(format!(
+            "shares must expire within {} seconds of now",
+            max - now
+        ))),
+    }
+}
+
+#[test]
+fn test_parse_time() {
+    let now = 1_000_000;
+    assert_eq!(parse_time("1700000000", now), Ok(1_700_000_000));
+    assert_eq!(parse_time("7d", now), Ok(now + 7 * 86400));
+    assert_eq!(parse_time("1d12h", now), Ok(now + 36 * 3600));
+    assert_eq!(parse_time("PT12H", now), Ok(now + 12 * 3600));
+    assert_eq!(parse_time("P1DT30M", now), Ok(now + 86400 + 1800));
+    assert_eq!(parse_time("P2W", now), Ok(now + 14 * 86400));
+    assert_eq!(parse_time("2023-11-14T22:13:20Z", now), Ok(1_700_000_000));
+    assert_eq!(
+        parse_time("2023-11-15T11:13:20+13:00", now),
+        Ok(1_700_000_000)
+    );
+    assert_eq!(parse_time("2023-11-14", now), Ok(1_699_920_000));
+    assert!(parse_time("P1M", now).is_err());
+    assert!(parse_time("PT1D", now).is_err());
+    assert!(parse_time("7", now).is_ok());
+    assert!(parse_time("7x", now).is_err());
+    assert!(parse_time("d", now).is_err());
+    assert!(parse_time("P", now).is_err());
+}
+
+#[test]
+fn test_ttl_policy() {
+    let now = 1_000;
+    let mut config = Config::default();
+    assert_eq!(apply_ttl(None, now, &config), Ok(None));
+
+    config.default_ttl = Some(Ttl(100));
+    config.max_ttl = Some(Ttl(500));
+    assert_eq!(apply_ttl(None, now, &config), Ok(Some(1_100)));
+    assert_eq!(apply_ttl(Some(1_400), now, &config), Ok(Some(1_400)));
+    assert!(apply_ttl(Some(1_501), now, &config).is_err());
+    assert!(check_max_ttl(None, now, &config).is_err());
+
+    config.default_ttl = None;
+    assert_eq!(apply_ttl(None, now, &config), Ok(Some(1_500)));
+    assert_eq!(
+        serde_json::from_str::<Ttl>(r#""30d""#).unwrap(),
+        Ttl(30 * 86400)
+    );
+}
@@ -0,0 +1,142 @@
+//! Fallback destinations, used in place of a dead link when a share is expired, has used up its clicks, or is disabled.
+use crate::access::AccessTracker;
+use crate::config::Config;
+use crate::database::{DatabaseError, SharesDbConn};
+use crate::url_id::{UrlID, UrlIDError};
+use rocket_sync_db_pools::rusqlite::types::{ToSql, ToSqlOutput};
+
+/// Why a share is not currently redirecting to its destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unavailable {
+    /// The share passed its expiry, or went unused for longer than its idle ttl.
+    Expired,
+    /// The share has been followed as many times as its `max_clicks` allows.
+    Exhausted,
+    /// The share was disabled by its owner.
+    Disabled,
+}
+
+impl Unavailable {
+    /// The name of this reason, as recorded against clicks and used to label metrics.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Unavailable::Expired => "expired",
+            Unavailable::Exhausted => "exhausted",
+            Unavailable::Disabled => "disabled",
+        }
+    }
+}
+
+impl ToSql for Unavailable {
+    fn to_sql(&self) -> rocket_sync_db_pools::rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+/// Find why a share is unavailable at `now`, if it is. Shares with a click limit require a query to count their clicks.
+pub async fn check(
+    conn: &SharesDbConn,
+    access: &AccessTracker,
+    share: &UrlID,
+    now: i64,
+) -> Result<Option<Unavailable>, DatabaseError> {
+    if share.get_disabled() {
+        return Ok(Some(Unavailable::Disabled));
+    }
+    if share.is_expired(now) {
+        return Ok(Some(Unavailable::Expired));
+    }
+    if let Some(max) = share.get_max_clicks() {
+        if access.count_redirects(conn, *share.get_id()).await? >= max {
+            return Ok(Some(Unavailable::Exhausted));
+        }
+    }
+    Ok(None)
+}
+
+/// Where to send visitors of an unavailable share: its own fallback, or else the server's default.
+pub fn destination<'a>(share: &'a UrlID, config: &'a Config) -> Option<&'a str> {
+    share
+        .get_fallback_url()
+        .or(config.default_fallback_url.as_deref())
+}
+
+/// Check a fallback is an absolute url, and that a click limit allows at least one click.
+pub fn validate(fallback_url: Option<&str>, max_clicks: Option<i64>) -> Result<(), UrlIDError> {
+    if max_clicks.is_some_and(|m| m <= 0) {
+        return Err(UrlIDError::ParseFailure(
+            "max_clicks must be greater than 0".into(),
+        ));
+    }
+    if let Some(fallback) = fallback_url {
+        url::Url::parse(fallback).map_err(|e| {
+            UrlIDError::ParseFailure(format!(
+                "fallback_url '{}' is not a valid url: {}",
+                fallback, e
+            ))
+        })?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_fallbacks() {
+    use rocket::http::{ContentType, Header, Status};
+    let (client, _dir) =
+        crate::test_client_with(|f| f.merge(("default_fallback_url", "https://home.example")));
+    let create = |body: &str| {
+        let link = client
+            .post("/shorten")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", "Bearer admin"))
+            .body(body)
+            .dispatch()
+            .into_string()
+            .unwrap();
+        link.rsplit('/').next().unwrap().to_owned()
+    };
+    let follow = |token: &str| {
+        let res = client.get(format!("/{}", token)).dispatch();
+        (
+            res.status(),
+            res.headers().get_one("Location").map(str::to_owned),
+        )
+    };
+
+    let limited = create(
+        r#"{"url":"https://a.example","max_clicks":1,"fallback_url":"https://sold-out.example"}"#,
+    );
+    assert_eq!(
+        follow(&limited),
+        (Status::SeeOther, Some("https://a.example".into()))
+    );
+    assert_eq!(
+        follow(&limited),
+        (Status::SeeOther, Some("https://sold-out.example".into()))
+    );
+
+    let expired = create(r#"{"url":"https://b.example","exp":1}"#);
+    assert_eq!(
+        follow(&expired),
+        (Status::SeeOther, Some("https://home.example".into()))
+    );
+
+    let disabled = create(r#"{"url":"https://c.example","disabled":true}"#);
+    assert_eq!(follow(&disabled).1.as_deref(), Some("https://home.example"));
+
+    let stats: serde_json::Value = client
+        .get(format!("/api/shares/{}/stats", limited))
+        .header(Header::new("Authorization", "Bearer admin"))
+        .dispatch()
+        .into_json()
+        .unwrap();
+    assert_eq!(stats["clicks"], 1);
+    assert_eq!(stats["fallbacks"]["exhausted"], 1);
+
+    let res = client
+        .post("/shorten")
+        .header(ContentType::JSON)
+        .body(r#"{"url":"https://a.example","fallback_url":"nowhere"}"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::BadRequest);
+}
@@ -0,0 +1,172 @@
+//! Liveness and readiness endpoints, for use by orchestrators and load balancers.
+use crate::database::{self, SharesDbConn};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::serde::json::Json;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Instant;
+
+/// The paths of the health endpoints, which are excluded from request metrics.
+pub const HEALTH_PATHS: &[&str] = &["/healthz", "/readyz"];
+
+/// The result of a single readiness check.
+#[derive(Debug, Serialize)]
+pub struct Check {
+    /// Either "ok" or "error".
+    status: &'static str,
+    /// How long the check took to run, in milliseconds.
+    latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Check {
+    fn new(start: Instant, result: Result<(), String>) -> Self {
+        let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+        match result {
+            Ok(()) => Check {
+                status: "ok",
+                latency_ms,
+                error: None,
+            },
+            Err(e) => Check {
+                status: "error",
+                latency_ms,
+                error: Some(e),
+            },
+        }
+    }
+
+    fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// The response body of both health endpoints.
+#[derive(Debug, Serialize)]
+pub struct Health {
+    status: &'static str,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    checks: BTreeMap<&'static str, Check>,
+}
+
+/// The path of the configured sqlite database file.
+pub struct DatabasePath(PathBuf);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DatabasePath {
+    type Error = String;
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        match req
+            .rocket()
+            .figment()
+            .extract_inner::<String>("databases.sqlite_shares.url")
+        {
+            Ok(url) => request::Outcome::Success(DatabasePath(PathBuf::from(url))),
+            Err(e) => request::Outcome::Failure((Status::InternalServerError, e.to_string())),
+        }
+    }
+}
+
+/// Whether the process is alive. Always succeeds if the server is able to respond at all.
+/// ```
+/// GET /healthz
+/// ```
+#[get("/healthz")]
+fn healthz() -> Json<Health> {
+    Json(Health {
+        status: "ok",
+        checks: BTreeMap::new(),
+    })
+}
+
+/// Whether the server is ready to handle requests: the database must be reachable, at the expected schema version, and writable.
+/// Responds with 503 Service Unavailable if any check fails.
+/// ```
+/// GET /readyz
+/// ```
+#[get("/readyz")]
+async fn readyz(conn: Option<SharesDbConn>, path: DatabasePath) -> (Status, Json<Health>) {
+    let mut checks = BTreeMap::new();
+
+    let start = Instant::now();
+    let version = match &conn {
+        Some(conn) => conn
+            .run(|c| {
+                c.query_row("SELECT 1;", [], |_| Ok(()))?;
+                database::schema_version(c)
+            })
+            .await
+            .map_err(|e| e.to_string()),
+        None => Err("failed to connect to the database".to_string()),
+    };
+    checks.insert("database", Check::new(start, version.clone().map(|_| ())));
+
+    let start = Instant::now();
+    let schema = match version {
+        Ok(v) if v == database::SCHEMA_VERSION => Ok(()),
+        Ok(v) => Err(format!(
+            "schema is at version {}, expected {}",
+            v,
+            database::SCHEMA_VERSION
+        )),
+        Err(_) => Err("unable to read schema version".to_string()),
+    };
+    checks.insert("schema", Check::new(start, schema));
+
+    let start = Instant::now();
+    checks.insert("disk", Check::new(start, check_writable(&path.0)));
+
+    let ready = checks.values().all(Check::is_ok);
+    let (status, label) = match ready {
+        true => (Status::Ok, "ok"),
+        false => (Status::ServiceUnavailable, "unavailable"),
+    };
+    (
+        status,
+        Json(Health {
+            status: label,
+            checks,
+        }),
+    )
+}
+
+/// Check the directory holding the database is writable, by creating and removing a file within it.
+fn check_writable(database: &std::path::Path) -> Result<(), String> {
+    let dir = match database.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let probe = dir.join(format!(".readyz-{}", std::process::id()));
+    std::fs::write(&probe, b"ok").map_err(|e| e.to_string())?;
+    std::fs::remove_file(&probe).map_err(|e| e.to_string())
+}
+
+/// The health routes, to be mounted under `/`.
+pub fn routes() -> Vec<rocket::Route> {
+    routes![healthz, readyz]
+}
+
+#[test]
+fn test_readiness() {
+    let (client, dir) = crate::test_client();
+    assert_eq!(client.get("/healthz").dispatch().status(), Status::Ok);
+
+    let res = client.get("/readyz").dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let body: serde_json::Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+    assert_eq!(body["status"], "ok");
+    for check in ["database", "schema", "disk"] {
+        assert_eq!(body["checks"][check]["status"], "ok");
+    }
+
+    let c =
+        rocket_sync_db_pools::rusqlite::Connection::open(dir.path().join("database.db")).unwrap();
+    c.execute_batch("PRAGMA user_version = 1;").unwrap();
+    let res = client.get("/readyz").dispatch();
+    assert_eq!(res.status(), Status::ServiceUnavailable);
+    let body: serde_json::Value = serde_json::from_str(&res.into_string().unwrap()).unwrap();
+    assert_eq!(body["checks"]["schema"]["status"], "error");
+}
@@ -0,0 +1,237 @@
+//! The versions a share has been through. Every creation and update stores the share's destination and settings as a
+//! new numbered version, so an owner may see how a link changed over time and revert it to an earlier version.
+use crate::audit::Actor;
+use crate::database::{DatabaseError, FromDatabase, SharesDbConn};
+use crate::url_id::UrlID;
+use rocket_sync_db_pools::rusqlite::{self, params, OptionalExtension};
+use serde::Serialize;
+
+/// A version of a share, as stored in the database.
+#[derive(Debug, Clone, Serialize)]
+pub struct ShareVersion {
+    /// Numbered from 1 for each share, increasing with each change.
+    version: i64,
+    ts: i64,
+    /// Who made the change, if known. Versions recorded from before history was kept have no actor.
+    actor: Option<String>,
+    /// The version this one restored, if it was made by reverting.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reverted_from: Option<i64>,
+    share: UrlID,
+}
+
+impl ShareVersion {
+    /// The share as it was at this version.
+    pub fn share(&self) -> &UrlID {
+        &self.share
+    }
+}
+
+impl FromDatabase for ShareVersion {
+    type Error = rusqlite::Error;
+    fn from_database(row: &rusqlite::Row<'_>) -> Result<ShareVersion, rusqlite::Error> {
+        let share: String = row.get("share")?;
+        Ok(ShareVersion {
+            version: row.get("version")?,
+            ts: row.get("ts")?,
+            actor: row.get("actor")?,
+            reverted_from: row.get("reverted_from")?,
+            share: serde_json::from_str(&share).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(
+                    0,
+                    rusqlite::types::Type::Text,
+                    Box::new(e),
+                )
+            })?,
+        })
+    }
+}
+
+/// Store `share` as the next version in its history. `previous` is the share before the change, recorded first as an
+/// anonymous version if the share has no history yet, as happens for shares created before history was kept.
+pub fn record_version(
+    c: &rusqlite::Connection,
+    share: &UrlID,
+    previous: Option<&UrlID>,
+    actor: &Actor,
+    reverted_from: Option<i64>,
+) -> Result<(), rusqlite::Error> {
+    let latest: i64 = c.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM share_versions WHERE share_id = ?1;",
+        params![share.get_id()],
+        |row| row.get(0),
+    )?;
+    let mut insert = c.prepare_cached(
+        "INSERT INTO share_versions (share_id, version, ts, actor, actor_key, reverted_from, share)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7);",
+    )?;
+    let snapshot = |share: &UrlID| {
+        serde_json::to_string(share)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+    };
+    let mut version = latest + 1;
+    if let (0, Some(previous)) = (latest, previous) {
+        insert.execute(params![
+            share.get_id(),
+            version,
+            previous.get_crt(),
+            None::<String>,
+            None::<i64>,
+            None::<i64>,
+            snapshot(previous)?
+        ])?;
+        version += 1;
+    }
+    insert.execute(params![
+        share.get_id(),
+        version,
+        crate::common::get_time_seconds(),
+        actor.name,
+        actor.key_id,
+        reverted_from,
+        snapshot(share)?
+    ])?;
+    Ok(())
+}
+
+/// List every version of a share, newest first.
+pub async fn find_versions(
+    conn: &SharesDbConn,
+    share_id: i64,
+) -> Result<Vec<ShareVersion>, DatabaseError> {
+    let result = conn
+        .run_timed(move |c| {
+            c.prepare("SELECT * FROM share_versions WHERE share_id = ?1 ORDER BY version DESC;")?
+                .query_map(params![share_id], ShareVersion::from_database)?
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .await?;
+    Ok(result)
+}
+
+/// Find a single version of a share.
+pub async fn find_version(
+    conn: &SharesDbConn,
+    share_id: i64,
+    version: i64,
+) -> Result<Option<ShareVersion>, DatabaseError> {
+    let result = conn
+        .run_timed(move |c| {
+            c.query_row(
+                "SELECT * FROM share_versions WHERE share_id = ?1 AND version = ?2;",
+                params![share_id, version],
+                ShareVersion::from_database,
+            )
+            .optional()
+        })
+        .await?;
+    Ok(result)
+}
+
+#[test]
+fn test_history_and_revert() {
+    use rocket::http::{ContentType, Header, Status};
+    let (client, dir) = crate::test_client();
+    let c = rusqlite::Connection::open(dir.path().join("database.db")).unwrap();
+    crate::insert_api_keys(
+        &c,
+        &[("alice-key", "alice", false), ("bob-key", "bob", false)],
+    );
+    let auth = |key: &str| Header::new("Authorization", format!("Bearer {}", key));
+    let link = client
+        .post("/shorten")
+        .header(ContentType::JSON)
+        .header(auth("alice-key"))
+        .body(r#"{"url":"https://a.example/v1","title":"Menu"}"#)
+        .dispatch()
+        .into_string()
+        .unwrap();
+    let token = link.rsplit('/').next().unwrap().to_owned();
+    client
+        .patch(format!("/api/shares/{}", token))
+        .header(ContentType::JSON)
+        .header(auth("alice-key"))
+        .body(r#"{"url":"https://a.example/v2","title":"Spring menu"}"#)
+        .dispatch();
+    let history = |key: &str| {
+        client
+            .get(format!("/api/shares/{}/history", token))
+            .header(auth(key))
+            .dispatch()
+    };
+    assert_eq!(history("bob-key").status(), Status::Forbidden);
+    let versions: serde_json::Value = history("alice-key").into_json().unwrap();
+    assert_eq!(versions.as_array().unwrap().len(), 2);
+    assert_eq!(versions[0]["version"], 2);
+    assert_eq!(versions[0]["share"]["url"], "https://a.example/v2");
+    assert_eq!(versions[1]["share"]["url"], "https://a.example/v1");
+    assert_eq!(versions[1]["actor"], "alice");
+
+    let revert = |version: i64| {
+        client
+            .post(format!("/api/shares/{}/history/{}/revert", token, version))
+            .header(auth("alice-key"))
+            .dispatch()
+    };
+    assert_eq!(revert(9).status(), Status::NotFound);
+    let reverted: serde_json::Value = revert(1).into_json().unwrap();
+    assert_eq!(reverted["url"], "https://a.example/v1");
+    assert_eq!(reverted["title"], "Menu");
+    let res = client.get(format!("/{}", token)).dispatch();
+    assert_eq!(
+        res.headers().get_one("Location"),
+        Some("https://a.example/v1")
+    );
+
+    let versions: serde_json::Value = history("alice-key").into_json().unwrap();
+    assert_eq!(versions[0]["version"], 3);
+    assert_eq!(versions[0]["reverted_from"], 1);
+    let audit: serde_json::Value = client
+        .get(format!("/admin/audit?token={}&action=revert", token))
+        .header(auth("admin"))
+        .dispatch()
+        .into_json()
+        .unwrap();
+    assert_eq!(audit[0]["actor"], "alice");
+    assert_eq!(audit[0]["before"]["url"], "https://a.example/v2");
+}
+
+#[test]
+fn test_revert_respects_max_ttl() {
+    use rocket::http::{ContentType, Header, Status};
+    let (client, dir) = crate::test_client_with(|f| f.merge(("max_ttl", "1d")));
+    let admin = || Header::new("Authorization", "Bearer admin");
+    let link = client
+        .post("/shorten")
+        .header(ContentType::JSON)
+        .header(admin())
+        .body(r#"{"url":"https://a.example/v1"}"#)
+        .dispatch()
+        .into_string()
+        .unwrap();
+    let token = link.rsplit('/').next().unwrap().to_owned();
+    client
+        .patch(format!("/api/shares/{}", token))
+        .header(ContentType::JSON)
+        .header(admin())
+        .body(r#"{"url":"https://a.example/v2"}"#)
+        .dispatch();
+    let revert = |version: i64| {
+        client
+            .post(format!("/api/shares/{}/history/{}/revert", token, version))
+            .header(admin())
+            .dispatch()
+    };
+    let reverted: serde_json::Value = revert(1).into_json().unwrap();
+    assert_eq!(reverted["url"], "https://a.example/v1");
+    assert!(reverted["exp"].is_i64());
+
+    // A version from before max_ttl was configured, which never expired.
+    let c = rusqlite::Connection::open(dir.path().join("database.db")).unwrap();
+    c.execute(
+        "UPDATE share_versions SET share = json_set(share, '$.exp', NULL) WHERE version = 2;",
+        [],
+    )
+    .unwrap();
+    assert_eq!(revert(2).status(), Status::BadRequest);
+}
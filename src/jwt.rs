@@ -0,0 +1,782 @@
+//! Authentication with JSON web tokens issued by an identity provider. A bearer token which looks like a JWT is
+//! verified against the provider's signing keys, read as a JWKS from a file or fetched from a url and cached, and its
+//! claims are mapped onto the owner of shares, admin access and roles in workspaces.
+use crate::auth::{bearer_token, AuthError};
+use crate::common::get_time_seconds;
+use crate::config::Config;
+use crate::expiry::Ttl;
+use crate::outbound;
+use crate::workspaces::Role;
+use futures::future::{BoxFuture, FutureExt, Shared};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::jwk::{Jwk, JwkSet, KeyAlgorithm};
+use jsonwebtoken::{DecodingKey, Validation};
+use rocket::http::Status;
+use rocket::outcome::Outcome::*;
+use rocket::request::{self, FromRequest, Request};
+use rocket::tokio;
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long a fetched JWKS is used before being fetched again, if `jwks_cache_ttl` is not configured.
+const DEFAULT_CACHE_SECONDS: i64 = 60 * 60;
+/// The least time between fetches of the JWKS prompted by tokens signed with a key it does not contain.
+const MIN_REFETCH_SECONDS: i64 = 30;
+/// The clock skew tolerated between the server and the identity provider, if `leeway` is not configured.
+const DEFAULT_LEEWAY_SECONDS: i64 = 60;
+/// How long the identity provider has to respond with its JWKS.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How bearer JWTs are verified and mapped onto principals, as configured in `jwt`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct JwtConfig {
+    /// A file holding the JWKS, read again each time the cache expires.
+    #[serde(default)]
+    pub jwks_file: Option<PathBuf>,
+    /// An https url to fetch the JWKS from, if no `jwks_file` is set.
+    #[serde(default, deserialize_with = "deserialize_jwks_url")]
+    pub jwks_url: Option<String>,
+    /// How long the JWKS is used before it is loaded again. Defaults to an hour.
+    #[serde(default)]
+    pub jwks_cache_ttl: Option<Ttl>,
+    /// The `iss` tokens must carry, if set.
+    #[serde(default)]
+    pub issuer: Option<String>,
+    /// A value the `aud` of tokens must carry, if set.
+    #[serde(default)]
+    pub audience: Option<String>,
+    /// The clock skew tolerated when checking `exp` and `nbf`. Defaults to a minute.
+    #[serde(default)]
+    pub leeway: Option<Ttl>,
+    /// The claim naming the owner a token acts as.
+    #[serde(default = "default_owner_claim")]
+    pub owner_claim: String,
+    /// The claim listing the groups a token's owner belongs to.
+    #[serde(default = "default_groups_claim")]
+    pub groups_claim: String,
+    /// Groups whose members are admins.
+    #[serde(default)]
+    pub admin_groups: Vec<String>,
+    /// Roles in workspaces granted to the members of groups.
+    #[serde(default)]
+    pub group_roles: Vec<GroupRole>,
+}
+
+/// Deserialize `jwks_url`, refusing any url which is not https, as the keys decide who may act as anyone.
+fn deserialize_jwks_url<'de, D: Deserializer<'de>>(d: D) -> Result<Option<String>, D::Error> {
+    use serde::de::Error;
+    let url = match Option::<String>::deserialize(d)? {
+        Some(url) => url,
+        None => return Ok(None),
+    };
+    match url::Url::parse(&url) {
+        Ok(parsed) if parsed.scheme() == "https" => Ok(Some(url)),
+        Ok(_) => Err(D::Error::custom(format!(
+            "jwks_url '{}' must be an https url",
+            url
+        ))),
+        Err(e) => Err(D::Error::custom(format!(
+            "jwks_url '{}' is not a valid url: {}",
+            url, e
+        ))),
+    }
+}
+
+fn default_owner_claim() -> String {
+    "sub".into()
+}
+
+fn default_groups_claim() -> String {
+    "groups".into()
+}
+
+/// A role in a workspace held by every member of a group.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct GroupRole {
+    pub group: String,
+    pub workspace: i64,
+    pub role: Role,
+}
+
+impl JwtConfig {
+    fn cache_ttl(&self) -> i64 {
+        self.jwks_cache_ttl.map_or(DEFAULT_CACHE_SECONDS, |t| t.0)
+    }
+
+    fn leeway(&self) -> i64 {
+        self.leeway.map_or(DEFAULT_LEEWAY_SECONDS, |t| t.0)
+    }
+
+    /// Map the claims of a verified token onto who it acts as.
+    pub fn identify(&self, claims: Claims) -> Identity {
+        let mut workspaces = BTreeMap::new();
+        for grant in &self.group_roles {
+            if claims.groups.contains(&grant.group) {
+                let role = workspaces.entry(grant.workspace).or_insert(grant.role);
+                *role = grant.role.max(*role);
+            }
+        }
+        Identity {
+            admin: claims.groups.iter().any(|g| self.admin_groups.contains(g)),
+            owner: claims.owner,
+            workspaces,
+        }
+    }
+}
+
+/// An enum representing the reasons a token may be rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JwtError {
+    Malformed,
+    UnsupportedAlgorithm(String),
+    UnknownKey,
+    BadSignature,
+    Expired,
+    NotYetValid,
+    WrongIssuer,
+    WrongAudience,
+    MissingClaim(String),
+    KeysUnavailable(String),
+}
+
+impl std::fmt::Display for JwtError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            JwtError::Malformed => f.write_str("the token is malformed"),
+            JwtError::UnsupportedAlgorithm(alg) => {
+                write!(f, "the signing algorithm '{}' is not supported", alg)
+            }
+            JwtError::UnknownKey => f.write_str("the token was signed with an unknown key"),
+            JwtError::BadSignature => f.write_str("the token's signature is not valid"),
+            JwtError::Expired => f.write_str("the token has expired"),
+            JwtError::NotYetValid => f.write_str("the token is not valid yet"),
+            JwtError::WrongIssuer => f.write_str("the token was issued by an untrusted issuer"),
+            JwtError::WrongAudience => f.write_str("the token is not intended for this server"),
+            JwtError::MissingClaim(claim) => write!(f, "the token lacks the '{}' claim", claim),
+            JwtError::KeysUnavailable(e) => write!(f, "failed to load the signing keys: {}", e),
+        }
+    }
+}
+
+/// The signing algorithms tokens may use. Symmetric algorithms and `none` are refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Algorithm {
+    RS256,
+    ES256,
+    EdDSA,
+}
+
+impl Algorithm {
+    fn parse(alg: &str) -> Result<Algorithm, JwtError> {
+        match alg {
+            "RS256" => Ok(Algorithm::RS256),
+            "ES256" => Ok(Algorithm::ES256),
+            "EdDSA" => Ok(Algorithm::EdDSA),
+            _ => Err(JwtError::UnsupportedAlgorithm(alg.to_owned())),
+        }
+    }
+
+    fn verifying(&self) -> jsonwebtoken::Algorithm {
+        match self {
+            Algorithm::RS256 => jsonwebtoken::Algorithm::RS256,
+            Algorithm::ES256 => jsonwebtoken::Algorithm::ES256,
+            Algorithm::EdDSA => jsonwebtoken::Algorithm::EdDSA,
+        }
+    }
+
+    fn key_algorithm(&self) -> KeyAlgorithm {
+        match self {
+            Algorithm::RS256 => KeyAlgorithm::RS256,
+            Algorithm::ES256 => KeyAlgorithm::ES256,
+            Algorithm::EdDSA => KeyAlgorithm::EdDSA,
+        }
+    }
+}
+
+/// A set of public keys, as published by an identity provider.
+pub type Jwks = JwkSet;
+
+/// The key a token was signed with, by its `kid`, or the only key if the token names none.
+fn find_key<'a>(keys: &'a Jwks, kid: Option<&str>) -> Option<&'a Jwk> {
+    match kid {
+        Some(kid) => keys.find(kid),
+        None if keys.keys.len() == 1 => keys.keys.first(),
+        None => None,
+    }
+}
+
+/// The parts of a token's header read before it is verified.
+#[derive(Debug, Deserialize)]
+struct Header {
+    alg: String,
+    #[serde(default)]
+    kid: Option<String>,
+}
+
+/// The claims of a verified token which the server uses.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Claims {
+    pub owner: String,
+    pub groups: Vec<String>,
+}
+
+/// Who a verified token acts as.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub owner: String,
+    pub admin: bool,
+    /// The roles granted by the token's groups, by workspace id.
+    pub workspaces: BTreeMap<i64, Role>,
+}
+
+/// Verify a token's signature against `keys`, and check its claims are valid now.
+pub fn verify(token: &str, keys: &Jwks, config: &JwtConfig) -> Result<Claims, JwtError> {
+    // The header is read first so that only the allowed algorithms are ever tried, whatever keys the JWKS holds.
+    let header = token.split('.').next().ok_or(JwtError::Malformed)?;
+    let header: Header = base64::decode_config(header, base64::URL_SAFE_NO_PAD)
+        .ok()
+        .and_then(|h| serde_json::from_slice(&h).ok())
+        .ok_or(JwtError::Malformed)?;
+    let alg = Algorithm::parse(&header.alg)?;
+    let jwk = find_key(keys, header.kid.as_deref()).ok_or(JwtError::UnknownKey)?;
+    if jwk
+        .common
+        .key_algorithm
+        .is_some_and(|a| a != alg.key_algorithm())
+    {
+        return Err(JwtError::BadSignature);
+    }
+    let key = DecodingKey::from_jwk(jwk).map_err(|_| JwtError::BadSignature)?;
+
+    // The issuer and audience are only checked when present, so they must also be required.
+    let mut validation = Validation::new(alg.verifying());
+    let mut required = vec!["exp"];
+    validation.leeway = config.leeway().max(0) as u64;
+    validation.validate_nbf = true;
+    if let Some(issuer) = &config.issuer {
+        validation.set_issuer(&[issuer]);
+        required.push("iss");
+    }
+    match &config.audience {
+        Some(audience) => {
+            validation.set_audience(&[audience]);
+            required.push("aud");
+        }
+        None => validation.validate_aud = false,
+    }
+    validation.set_required_spec_claims(&required);
+    let claims = jsonwebtoken::decode::<serde_json::Map<String, Value>>(token, &key, &validation)
+        .map_err(|e| match e.into_kind() {
+            ErrorKind::InvalidSignature | ErrorKind::InvalidAlgorithm => JwtError::BadSignature,
+            ErrorKind::ExpiredSignature => JwtError::Expired,
+            ErrorKind::ImmatureSignature => JwtError::NotYetValid,
+            ErrorKind::InvalidIssuer => JwtError::WrongIssuer,
+            ErrorKind::InvalidAudience => JwtError::WrongAudience,
+            ErrorKind::MissingRequiredClaim(claim) => JwtError::MissingClaim(claim),
+            _ => JwtError::Malformed,
+        })?
+        .claims;
+    let owner = claims
+        .get(&config.owner_claim)
+        .and_then(Value::as_str)
+        .filter(|o| !o.is_empty())
+        .ok_or_else(|| JwtError::MissingClaim(config.owner_claim.clone()))?;
+    let groups = match claims.get(&config.groups_claim) {
+        Some(Value::Array(groups)) => groups
+            .iter()
+            .filter_map(|g| g.as_str().map(str::to_owned))
+            .collect(),
+        Some(Value::String(group)) => vec![group.clone()],
+        _ => Vec::new(),
+    };
+    Ok(Claims {
+        owner: owner.to_owned(),
+        groups,
+    })
+}
+
+/// A load of the JWKS under way, which any number of requests may wait on together.
+type Fetch = Shared<BoxFuture<'static, Result<Arc<Jwks>, JwtError>>>;
+
+#[derive(Default)]
+struct CacheState {
+    /// The keys last loaded, and when.
+    keys: Option<(Arc<Jwks>, i64)>,
+    /// When a load was last started, successful or not.
+    attempted: i64,
+    /// Why the last load failed, if it did.
+    error: Option<JwtError>,
+    fetching: Option<Fetch>,
+}
+
+/// The JWKS tokens are verified against, managed by rocket and loaded again once older than the configured ttl. Only
+/// one load runs at a time, and loads are at least `MIN_REFETCH_SECONDS` apart, so an identity provider which cannot be
+/// reached holds up few requests.
+#[derive(Default)]
+pub struct KeyCache {
+    state: Arc<std::sync::Mutex<CacheState>>,
+}
+
+impl KeyCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The current keys, loading them if none are held. Expired keys are still answered with while they are loaded
+    /// again in the background. `refetch` loads them again early, unless they were loaded too recently, for when a
+    /// token names a key that may have been added since.
+    async fn keys(&self, config: &JwtConfig, refetch: bool) -> Result<Arc<Jwks>, JwtError> {
+        let now = get_time_seconds();
+        let (held, fetch, wait) = {
+            let mut state = self.state.lock().unwrap();
+            match state.keys.clone() {
+                Some((keys, loaded)) => {
+                    let age = now - loaded;
+                    let wanted = refetch && age >= MIN_REFETCH_SECONDS;
+                    if age < config.cache_ttl() && !wanted {
+                        return Ok(keys);
+                    }
+                    (Some(keys), self.fetch(&mut state, config, now), wanted)
+                }
+                None => match self.fetch(&mut state, config, now) {
+                    Some(fetch) => (None, Some(fetch), true),
+                    None => {
+                        let error = state.error.clone();
+                        return Err(error.unwrap_or_else(|| {
+                            JwtError::KeysUnavailable("the jwks has not been loaded".into())
+                        }));
+                    }
+                },
+            }
+        };
+        match (held, fetch) {
+            // Keep to the keys already held while the identity provider cannot be reached.
+            (Some(keys), Some(fetch)) if wait => Ok(fetch.await.unwrap_or(keys)),
+            (Some(keys), _) => Ok(keys),
+            (None, fetch) => fetch.expect("a fetch when no keys are held").await,
+        }
+    }
+
+    /// The load under way, or a new one if the last was started long enough ago. Loads run as their own tasks, so that
+    /// they finish even if the requests waiting on them do not.
+    fn fetch(&self, state: &mut CacheState, config: &JwtConfig, now: i64) -> Option<Fetch> {
+        if let Some(fetch) = &state.fetching {
+            return Some(fetch.clone());
+        }
+        if now - state.attempted < MIN_REFETCH_SECONDS {
+            return None;
+        }
+        state.attempted = now;
+        let (shared, config) = (self.state.clone(), config.clone());
+        let fetch = async move {
+            let result = load(&config).await.map(Arc::new);
+            let mut state = shared.lock().unwrap();
+            state.fetching = None;
+            match &result {
+                Ok(keys) => {
+                    state.keys = Some((keys.clone(), get_time_seconds()));
+                    state.error = None;
+                }
+                Err(e) => {
+                    tracing::warn!(error = %e, "failed to load the jwks");
+                    state.error = Some(e.clone());
+                }
+            }
+            result
+        }
+        .boxed()
+        .shared();
+        state.fetching = Some(fetch.clone());
+        tokio::spawn(fetch.clone());
+        Some(fetch)
+    }
+}
+
+/// Read or fetch the JWKS.
+async fn load(config: &JwtConfig) -> Result<Jwks, JwtError> {
+    let unavailable = |e: String| JwtError::KeysUnavailable(e);
+    let body = match (&config.jwks_file, &config.jwks_url) {
+        (Some(path), _) => tokio::fs::read(path)
+            .await
+            .map_err(|e| unavailable(format!("{}: {}", path.display(), e)))?,
+        (None, Some(url)) => {
+            let uri: hyper::Uri = url.parse().map_err(|e| unavailable(format!("{}", e)))?;
+            // The url is set by the operator, and may well name an identity provider on a private network.
+            let response = tokio::time::timeout(FETCH_TIMEOUT, outbound::client(true).get(uri))
+                .await
+                .map_err(|_| unavailable("timed out".into()))?
+                .map_err(|e| unavailable(e.to_string()))?;
+            if !response.status().is_success() {
+                return Err(unavailable(format!("responded {}", response.status())));
+            }
+            hyper::body::to_bytes(response.into_body())
+                .await
+                .map_err(|e| unavailable(e.to_string()))?
+                .to_vec()
+        }
+        (None, None) => return Err(unavailable("neither jwks_file nor jwks_url is set".into())),
+    };
+    serde_json::from_slice(&body).map_err(|e| unavailable(e.to_string()))
+}
+
+/// Verify a token against the cached keys, fetching them again if it names a key they lack.
+async fn authenticate(
+    cache: &KeyCache,
+    config: &JwtConfig,
+    token: &str,
+) -> Result<Claims, JwtError> {
+    match verify(token, &*cache.keys(config, false).await?, config) {
+        Err(JwtError::UnknownKey) => verify(token, &*cache.keys(config, true).await?, config),
+        result => result,
+    }
+}
+
+/// A request guard verifying a JWT sent as a bearer token. It forwards if JWT authentication is not configured, or the
+/// bearer token is not a JWT, so that it may be checked as an api key instead.
+pub struct BearerJwt(pub Identity);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for BearerJwt {
+    type Error = AuthError;
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let config = req.rocket().state::<Config>().and_then(|c| c.jwt.as_ref());
+        let (config, cache, token) =
+            match (config, req.rocket().state::<KeyCache>(), bearer_token(req)) {
+                // Api keys never contain dots, while JWTs always contain two.
+                (Some(config), Some(cache), Some(token)) if token.matches('.').count() == 2 => {
+                    (config, cache, token)
+                }
+                _ => return Forward(()),
+            };
+        match authenticate(cache, config, token).await {
+            Ok(claims) => Success(BearerJwt(config.identify(claims))),
+            Err(e @ JwtError::KeysUnavailable(_)) => {
+                tracing::error!(error = %e, "failed to verify a bearer jwt");
+                Failure((
+                    Status::ServiceUnavailable,
+                    AuthError::InvalidJwt(e.to_string()),
+                ))
+            }
+            Err(e) => Failure((Status::Unauthorized, AuthError::InvalidJwt(e.to_string()))),
+        }
+    }
+}
+
+/// Tokens signed with Ed25519 keys derived from the given seeds, and the JWKS publishing them.
+#[cfg(test)]
+pub mod testing {
+    use ring::signature::KeyPair;
+
+    /// The PKCS #8 encoding of an Ed25519 private key, which is a fixed prefix followed by its seed.
+    fn pkcs8(seed: &[u8; 32]) -> Vec<u8> {
+        let mut der = vec![
+            0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22,
+            0x04, 0x20,
+        ];
+        der.extend_from_slice(seed);
+        der
+    }
+
+    pub fn jwks(keys: &[(&str, [u8; 32])]) -> serde_json::Value {
+        let keys: Vec<_> = keys
+            .iter()
+            .map(|(kid, seed)| {
+                let pair = ring::signature::Ed25519KeyPair::from_seed_unchecked(seed).unwrap();
+                serde_json::json!({
+                    "kty": "OKP",
+                    "crv": "Ed25519",
+                    "kid": kid,
+                    "x": base64::encode_config(pair.public_key(), base64::URL_SAFE_NO_PAD),
+                })
+            })
+            .collect();
+        serde_json::json!({ "keys": keys })
+    }
+
+    pub fn token(kid: &str, seed: &[u8; 32], claims: serde_json::Value) -> String {
+        let mut header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::EdDSA);
+        header.kid = Some(kid.to_owned());
+        let key = jsonwebtoken::EncodingKey::from_ed_der(&pkcs8(seed));
+        jsonwebtoken::encode(&header, &claims, &key).unwrap()
+    }
+}
+
+#[test]
+fn test_verify_claims() {
+    use serde_json::json;
+    let keys: Jwks = serde_json::from_value(testing::jwks(&[("k1", [1; 32])])).unwrap();
+    let config: JwtConfig = serde_json::from_value(json!({
+        "issuer": "https://idp.example",
+        "audience": "url-shortener",
+        "leeway": 60,
+        "admin_groups": ["ops"],
+        "group_roles": [
+            { "group": "marketing", "workspace": 1, "role": "viewer" },
+            { "group": "design", "workspace": 1, "role": "editor" },
+            { "group": "design", "workspace": 2, "role": "admin" },
+        ],
+    }))
+    .unwrap();
+    let now = get_time_seconds();
+    let claims = |extra: serde_json::Value| {
+        let mut claims = json!({
+            "sub": "alice",
+            "iss": "https://idp.example",
+            "aud": ["other", "url-shortener"],
+            "exp": now + 300,
+            "groups": ["marketing", "design"],
+        });
+        for (k, v) in extra.as_object().unwrap() {
+            claims[k] = v.clone();
+        }
+        claims
+    };
+    let check = |token: &str| verify(token, &keys, &config);
+
+    let claims_ = check(&testing::token("k1", &[1; 32], claims(json!({})))).unwrap();
+    assert_eq!(claims_.owner, "alice");
+    let identity = config.identify(claims_);
+    assert!(!identity.admin);
+    assert_eq!(
+        identity.workspaces,
+        BTreeMap::from([(1, Role::Editor), (2, Role::Admin)])
+    );
+    let ops = check(&testing::token(
+        "k1",
+        &[1; 32],
+        claims(json!({"groups": "ops"})),
+    ));
+    assert!(config.identify(ops.unwrap()).admin);
+
+    // Tokens are accepted within the leeway either side of their validity.
+    for (extra, result) in [
+        (json!({"exp": now - 30}), Ok(())),
+        (json!({"exp": now - 90}), Err(JwtError::Expired)),
+        (json!({"nbf": now + 30}), Ok(())),
+        (json!({"nbf": now + 90}), Err(JwtError::NotYetValid)),
+        (
+            json!({"exp": null}),
+            Err(JwtError::MissingClaim("exp".into())),
+        ),
+        (
+            json!({"iss": "https://evil.example"}),
+            Err(JwtError::WrongIssuer),
+        ),
+        (
+            json!({"iss": null}),
+            Err(JwtError::MissingClaim("iss".into())),
+        ),
+        (json!({"aud": "other"}), Err(JwtError::WrongAudience)),
+        (json!({"aud": "url-shortener"}), Ok(())),
+        (
+            json!({"sub": ""}),
+            Err(JwtError::MissingClaim("sub".into())),
+        ),
+    ] {
+        let token = testing::token("k1", &[1; 32], claims(extra.clone()));
+        assert_eq!(check(&token).map(|_| ()), result, "{}", extra);
+    }
+
+    let token = testing::token("k1", &[2; 32], claims(json!({})));
+    assert_eq!(check(&token), Err(JwtError::BadSignature));
+    let token = testing::token("k2", &[1; 32], claims(json!({})));
+    assert_eq!(check(&token), Err(JwtError::UnknownKey));
+
+    // A payload swapped into a validly signed token.
+    let token = testing::token("k1", &[1; 32], claims(json!({})));
+    let forged = base64::encode_config(
+        claims(json!({"sub": "admin"})).to_string(),
+        base64::URL_SAFE_NO_PAD,
+    );
+    let parts: Vec<&str> = token.split('.').collect();
+    let forged = format!("{}.{}.{}", parts[0], forged, parts[2]);
+    assert_eq!(check(&forged), Err(JwtError::BadSignature));
+
+    // Unsigned and symmetrically signed tokens are refused outright.
+    for alg in ["none", "HS256"] {
+        let header = base64::encode_config(
+            json!({ "alg": alg, "kid": "k1" }).to_string(),
+            base64::URL_SAFE_NO_PAD,
+        );
+        let token = format!("{}.{}.", header, parts[1]);
+        assert_eq!(
+            check(&token),
+            Err(JwtError::UnsupportedAlgorithm(alg.into()))
+        );
+    }
+    assert_eq!(check("not.a.jwt"), Err(JwtError::Malformed));
+}
+
+#[test]
+fn test_jwt_authentication() {
+    use rocket::http::{ContentType, Header};
+    use serde_json::json;
+    let dir = tempfile::tempdir().unwrap();
+    let jwks_file = dir.path().join("jwks.json");
+    std::fs::write(&jwks_file, testing::jwks(&[("k1", [1; 32])]).to_string()).unwrap();
+    let (client, _dir) = crate::test_client_with(|f| {
+        f.merge((
+            "jwt",
+            json!({
+                "jwks_file": jwks_file,
+                "issuer": "https://idp.example",
+                "admin_groups": ["ops"],
+                "group_roles": [{ "group": "marketing", "workspace": 1, "role": "editor" }],
+            }),
+        ))
+    });
+    let bearer = |token: &str| Header::new("Authorization", format!("Bearer {}", token));
+    let token = |sub: &str, groups: &[&str], exp: i64| {
+        testing::token(
+            "k1",
+            &[1; 32],
+            json!({
+                "sub": sub,
+                "iss": "https://idp.example",
+                "exp": exp,
+                "groups": groups,
+            }),
+        )
+    };
+    let valid = get_time_seconds() + 600;
+
+    let res = client
+        .post("/api/workspaces")
+        .header(ContentType::JSON)
+        .header(bearer("admin"))
+        .body(r#"{"name":"Marketing"}"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::Created);
+
+    let alice = token("alice", &["marketing"], valid);
+    let link = client
+        .post("/shorten")
+        .header(ContentType::JSON)
+        .header(bearer(&alice))
+        .body(r#"{"url":"https://a.example","workspace":1}"#)
+        .dispatch()
+        .into_string()
+        .unwrap();
+    let share_token = link.rsplit('/').next().unwrap();
+    let res = client
+        .patch(format!("/api/shares/{}", share_token))
+        .header(ContentType::JSON)
+        .header(bearer(&alice))
+        .body(r#"{"title":"Launch"}"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let res = client
+        .put("/api/workspaces/1/members/bob")
+        .header(ContentType::JSON)
+        .header(bearer(&alice))
+        .body(r#"{"role":"viewer"}"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::Forbidden);
+
+    let audit = |token: &str| {
+        client
+            .get(format!("/admin/audit?token={}&action=create", share_token))
+            .header(bearer(token))
+            .dispatch()
+    };
+    assert_eq!(audit(&alice).status(), Status::Forbidden);
+    let entries: serde_json::Value = audit(&token("carol", &["ops"], valid)).into_json().unwrap();
+    assert_eq!(entries[0]["actor"], "alice");
+
+    let bob = token("bob", &[], valid);
+    let res = client
+        .patch(format!("/api/shares/{}", share_token))
+        .header(ContentType::JSON)
+        .header(bearer(&bob))
+        .body(r#"{"title":"Mine"}"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::Forbidden);
+
+    // Expired, forged and untrusted tokens are rejected, while api keys are still accepted.
+    let expired = token("alice", &["marketing"], get_time_seconds() - 3600);
+    let forged = testing::token("k1", &[9; 32], json!({"sub": "alice", "exp": valid}));
+    for rejected in [expired.as_str(), forged.as_str(), "a.b.c"] {
+        assert_eq!(audit(rejected).status(), Status::Unauthorized);
+    }
+    assert_eq!(audit("admin").status(), Status::Ok);
+}
+
+#[test]
+fn test_jwks_url_requires_https() {
+    let accepted = |url: &str| {
+        crate::test_figment(&tempfile::tempdir().unwrap())
+            .merge(("jwt", serde_json::json!({ "jwks_url": url })))
+            .extract::<Config>()
+            .is_ok()
+    };
+    assert!(accepted("https://idp.example/.well-known/jwks.json"));
+    for url in [
+        "http://idp.example/.well-known/jwks.json",
+        "ftp://idp.example/jwks.json",
+        "idp.example",
+    ] {
+        assert!(!accepted(url), "{}", url);
+    }
+}
+
+#[rocket::async_test]
+async fn test_jwks_cache() {
+    use rocket::http::Header;
+    let dir = tempfile::tempdir().unwrap();
+    let jwks_file = dir.path().join("jwks.json");
+    std::fs::write(&jwks_file, testing::jwks(&[("k1", [1; 32])]).to_string()).unwrap();
+    let (client, _dir) = crate::test_async_client_with(|f| {
+        f.merge(("jwt", serde_json::json!({ "jwks_file": jwks_file })))
+    })
+    .await;
+    let exp = get_time_seconds() + 600;
+    let status = |kid: &'static str, seed: [u8; 32]| {
+        let token = testing::token(kid, &seed, serde_json::json!({"sub": "alice", "exp": exp}));
+        let request = client
+            .get("/api/shares")
+            .header(Header::new("Authorization", format!("Bearer {}", token)));
+        async move { request.dispatch().await.status() }
+    };
+    assert_eq!(status("k1", [1; 32]).await, Status::Ok);
+    // The keys are rotated, but were loaded too recently to be loaded again for the unknown key.
+    std::fs::write(&jwks_file, testing::jwks(&[("k2", [2; 32])]).to_string()).unwrap();
+    assert_eq!(status("k2", [2; 32]).await, Status::Unauthorized);
+    assert_eq!(status("k1", [1; 32]).await, Status::Ok);
+}
+
+#[rocket::async_test]
+async fn test_unreachable_jwks_url() {
+    use serde_json::json;
+    // An identity provider which accepts connections but never answers them.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("https://{}/jwks.json", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        let mut connections = Vec::new();
+        while let Ok((connection, _)) = listener.accept().await {
+            connections.push(connection);
+        }
+    });
+    let config: JwtConfig = serde_json::from_value(json!({ "jwks_url": url })).unwrap();
+    let keys: Jwks = serde_json::from_value(testing::jwks(&[("k1", [1; 32])])).unwrap();
+    let cache = KeyCache::new();
+    let expired = get_time_seconds() - config.cache_ttl() - 1;
+    cache.state.lock().unwrap().keys = Some((Arc::new(keys), expired));
+
+    // The expired keys are answered with while they are fetched again, rather than each request waiting on a fetch.
+    let exp = get_time_seconds() + 600;
+    let token = testing::token("k1", &[1; 32], json!({"sub": "alice", "exp": exp}));
+    for _ in 0..3 {
+        let result = tokio::time::timeout(
+            Duration::from_secs(1),
+            authenticate(&cache, &config, &token),
+        )
+        .await
+        .expect("answered promptly");
+        assert_eq!(result.map(|c| c.owner), Ok("alice".to_owned()));
+    }
+    assert!(cache.state.lock().unwrap().fetching.is_some());
+}
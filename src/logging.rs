@@ -0,0 +1,161 @@
+//! Structured request logging. Every request is assigned an `X-Request-Id` (or keeps the one it was sent with),
+//! and a single log line is emitted per request once the response is ready.
+use crate::config::{Config, LogFormat};
+use rand::Rng;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Method};
+use rocket::request::{self, FromRequest};
+use rocket::{Data, Request, Response};
+use std::sync::Mutex;
+use std::time::Instant;
+use tracing_subscriber::EnvFilter;
+
+/// The header used to carry the request id.
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// The longest request id we will accept from a client, longer ids are replaced.
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// Install the global tracing subscriber, using the configured level and format.
+/// Log records emitted by rocket itself are forwarded through the same subscriber.
+pub fn init(config: &Config) {
+    let filter = EnvFilter::try_new(config.log_filter.as_deref().unwrap_or("info"))
+        .unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    let result = match config.log_format {
+        LogFormat::Json => builder.json().flatten_event(true).try_init(),
+        LogFormat::Text => builder.try_init(),
+    };
+    if let Err(e) = result {
+        eprintln!("failed to initialise logging: {}", e);
+    }
+}
+
+/// Per-request details which are gathered while a request is handled, then logged alongside the response.
+pub struct RequestContext {
+    pub id: String,
+    /// The method the request was made with. Rocket answers `HEAD` requests using `GET` routes, rewriting the method
+    /// of the request before routing it, so this is the only record of a `HEAD` request once it reaches a handler.
+    pub method: Method,
+    start: Instant,
+    token: Mutex<Option<String>>,
+    share_id: Mutex<Option<i64>>,
+    error: Mutex<Option<String>>,
+}
+
+impl RequestContext {
+    fn new(id: String, method: Method) -> Self {
+        RequestContext {
+            id,
+            method,
+            start: Instant::now(),
+            token: Mutex::new(None),
+            share_id: Mutex::new(None),
+            error: Mutex::new(None),
+        }
+    }
+
+    /// Get the context of a request, creating it if the logging fairing is not attached.
+    pub fn of<'r>(req: &'r Request<'_>) -> &'r RequestContext {
+        req.local_cache(|| RequestContext::new(generate_request_id(), req.method()))
+    }
+
+    /// Record the token the request was made for.
+    pub fn set_token(&self, token: &str) {
+        *self.token.lock().unwrap() = Some(token.to_owned());
+    }
+
+    /// Record the id of the share the request resolved to.
+    pub fn set_share_id(&self, id: i64) {
+        *self.share_id.lock().unwrap() = Some(id);
+    }
+
+    /// Record the variant of the error the request failed with.
+    pub fn set_error(&self, variant: &str) {
+        *self.error.lock().unwrap() = Some(variant.to_owned());
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r RequestContext {
+    type Error = std::convert::Infallible;
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        request::Outcome::Success(RequestContext::of(req))
+    }
+}
+
+/// Generate a new random request id, as 32 hex chars.
+fn generate_request_id() -> String {
+    let bytes: [u8; 16] = rand::thread_rng().gen();
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Whether a request id provided by a client is safe to propagate.
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+/// A fairing which assigns every request an id, and logs each request once it has been handled.
+pub struct RequestLogger;
+
+#[rocket::async_trait]
+impl Fairing for RequestLogger {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request Logger",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        let id = match req.headers().get_one(REQUEST_ID_HEADER) {
+            Some(id) if is_valid_request_id(id) => id.to_owned(),
+            _ => generate_request_id(),
+        };
+        let method = req.method();
+        req.local_cache(|| RequestContext::new(id, method));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let ctx = RequestContext::of(req);
+        res.set_header(Header::new(REQUEST_ID_HEADER, ctx.id.clone()));
+        let route = req.route().map(|r| r.uri.as_str()).unwrap_or("unmatched");
+        let token = ctx.token.lock().unwrap().clone();
+        let share_id = *ctx.share_id.lock().unwrap();
+        let error = ctx.error.lock().unwrap().clone();
+        tracing::info!(
+            target: "request",
+            request_id = %ctx.id,
+            method = %ctx.method,
+            uri = %req.uri(),
+            route,
+            token,
+            share_id,
+            status = res.status().code,
+            latency_ms = ctx.start.elapsed().as_secs_f64() * 1000.0,
+            error,
+        );
+    }
+}
+
+#[test]
+fn test_request_id() {
+    let (client, _dir) = crate::test_client();
+    let res = client
+        .get("/healthz")
+        .header(Header::new(REQUEST_ID_HEADER, "abc-123"))
+        .dispatch();
+    assert_eq!(res.headers().get_one(REQUEST_ID_HEADER), Some("abc-123"));
+
+    let res = client
+        .get("/healthz")
+        .header(Header::new(REQUEST_ID_HEADER, "not valid"))
+        .dispatch();
+    let id = res.headers().get_one(REQUEST_ID_HEADER).unwrap();
+    assert_eq!(id.len(), 32);
+    assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+}
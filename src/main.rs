@@ -2,32 +2,133 @@
 
 #[macro_use]
 extern crate rocket;
+mod access;
+// Rocket's route codegen re-exports a uri macro beside each route, which is flagged as unused outside the crate root.
+#[allow(unused_imports)]
+mod admin;
+#[allow(unused_imports)]
+mod api;
+mod audit;
+mod auth;
+mod cli;
 mod common;
+mod config;
 mod database;
+mod domains;
+#[allow(unused_imports)]
+mod events;
+mod expiry;
+mod fallback;
+#[allow(unused_imports)]
+mod health;
+mod history;
+mod jwt;
+mod logging;
+mod metadata;
+#[allow(unused_imports)]
+mod metrics;
+mod outbound;
+mod passthrough;
+#[allow(unused_imports)]
+mod redirect;
+mod rules;
+mod search;
+mod split;
+mod tags;
+mod template;
+mod transfer;
+mod trash;
 mod url_id;
+#[allow(unused_imports)]
+mod webhooks;
+#[allow(unused_imports)]
+mod workspaces;
+use access::AccessTracker;
+use audit::Actor;
+use auth::{AuthError, Principal};
+use clap::Parser;
+use config::Config;
 use database::*;
-use rocket::http::Status;
-use rocket::response::Redirect;
+use domains::RequestDomain;
+use events::{Event, EventBus, ShareEvent};
+use logging::RequestContext;
+use metadata::Delivery;
+use redirect::{ComingSoon, RedirectType, ShareRedirect, ShareResponse};
+use rocket::fairing::AdHoc;
+use rocket::http::uri::{fmt::Path, Origin, Segments};
+use rocket::http::{CookieJar, Status};
+use rocket::{Build, Rocket, State};
+use rules::Visitor;
+use std::net::IpAddr;
 use url_id::*;
+use workspaces::Role;
 
 //Configuration
 /// The IP address of this server, should be set to your domain or IP.
 const SERVER_DOMAIN: &str = "127.0.0.1:8000";
 
-/// Create a new shortened URL
+/// Create a new shortened URL. If an api key is provided as a bearer token, the share is recorded against its owner.
 /// ```JSON
 /// POST
 /// {
 ///     url: String,
-///     exp: Integer (optional, if excluded will default to forever)
+///     exp: Integer | String (optional, unix seconds, an ISO-8601 timestamp or a duration such as "7d" or "PT12H".
+///          If excluded the server's default_ttl applies, or the link never expires)
+///     nbf: Integer | String (optional, in the same formats as exp, the link will not resolve before this time, which must not be after exp)
+///     idle_ttl: Integer | String (optional, seconds or a duration such as "30d", the link expires once unused for this long)
+///     redirect_type: Integer (optional, one of 301, 302, 303, 307 or 308, defaults to the server's default_redirect_type)
+///     passthrough_query: Boolean (optional, merge the query string of requests into the destination)
+///     passthrough_path: Boolean (optional, append trailing path segments of requests to the destination)
+///     query_precedence: "incoming" | "destination" (optional, which value wins when a parameter is in both)
+///     variants: [{ url: String, weight: Integer }] (optional, split clicks between several destinations, url may then be omitted)
+///     sticky: Boolean (optional, remember which variant each visitor was sent to with a cookie)
+///     rules: [{ url: String, os, languages, referrers, schedule }] (optional, routing rules evaluated in order, see rules.rs)
+///     fallback_url: String (optional, where visitors are sent once the link is expired, exhausted or disabled,
+///          defaults to the server's default_fallback_url)
+///     max_clicks: Integer (optional, the link is exhausted after this many clicks)
+///     disabled: Boolean (optional, create the link disabled)
+///     domain: String (optional, the host the link is served on, which the caller must be allowed to create links on.
+///          Defaults to the host the request was made to)
+///     workspace: Integer (optional, the workspace the link belongs to, in which the caller must be an editor)
 /// }
 /// ```
 #[post("/shorten", data = "<url_id>")]
+#[allow(clippy::too_many_arguments)]
 async fn create_shortened_url(
     url_id: UncommittedUrlID,
+    principal: Result<Principal, AuthError>,
+    ip: Option<IpAddr>,
+    host: RequestDomain,
+    log: &RequestContext,
+    config: &State<Config>,
+    events: &State<EventBus>,
     conn: SharesDbConn,
 ) -> Result<String, (Status, String)> {
-    let inserted: UrlID = add_to_database(&conn, url_id).await?;
+    let principal = match principal {
+        Ok(p) => Some(p),
+        Err(AuthError::Missing) => None,
+        Err(e) => return Err((Status::Unauthorized, e.to_string())),
+    };
+    match (url_id.get_workspace(), &principal) {
+        (Some(id), Some(p)) if !p.holds(id, Role::Editor) => {
+            return Err((Status::Forbidden, AuthError::Forbidden.to_string()))
+        }
+        (Some(_), None) => return Err((Status::Unauthorized, AuthError::Missing.to_string())),
+        _ => {}
+    }
+    let domain = match url_id.get_domain().or(host.0.as_deref()) {
+        Some(requested) => domains::authorize(config, requested, principal.as_ref())?,
+        None => None,
+    };
+    let actor = Actor::new(principal.as_ref(), ip);
+    let owner = principal.map(|p| p.owner);
+    let url_id = url_id.set_owner(owner).set_domain(domain);
+    let inserted: UrlID = add_to_database(&conn, url_id, actor)
+        .await
+        .inspect_err(|e| log.set_error(e.variant()))?;
+    log.set_share_id(*inserted.get_id());
+    let event = ShareEvent::new(Event::Created, &inserted, serde_json::json!({}));
+    events.publish(&conn, event).await;
     Ok(inserted.get_shortened_link())
 }
 
@@ -39,18 +140,168 @@ async fn setup_db(conn: SharesDbConn) -> Result<String, (Status, String)> {
 }
 
 /// This should be the most commonly used endpoint, and will redirect a user to the correct page the url shortens to!
-#[get("/<token>")]
-async fn get_page(token: String, conn: SharesDbConn) -> Result<Option<Redirect>, (Status, String)> {
-    let converted_id: String = token.split(url_id::DELIM_CHAR).collect::<Vec<&str>>()[0].to_owned();
-    let search_result = Search::Id(base_61_to_10(converted_id, url_id::ALPHABET))
+/// Links only resolve on the domain they were created on, responding 404 when requested on any other host.
+/// Links which have been deleted respond 410 while they wait in the trash.
+/// Links scheduled to go live in the future respond 404, or with the configured coming soon page.
+/// Links which are expired, have used up their `max_clicks`, or are disabled redirect to their `fallback_url` or the
+/// configured default fallback, responding 404 if neither is set. Such redirects are never permanent, and are recorded with their reason.
+/// Following a link with an idle ttl extends its expiry. Clicks and access times are written to the database in batches.
+/// Trailing path segments fill any placeholders in the destination, such as `{1}` or `{*}`, responding 400 if too few are given.
+/// Any remaining path segments or query string are passed on to the destination if the link allows it.
+/// The first of the link's routing rules to match the visitor chooses the destination. Otherwise links with several
+/// variants send each visitor to one chosen by weight. Every click is recorded for the stats endpoint, and sent to any
+/// webhook subscribed to clicks and to the live event stream.
+/// Requests preferring `Accept: application/json` receive the link's metadata instead of a redirect, including its
+/// click count if they authenticate as someone who may manage it. Neither these nor `HEAD` requests count as clicks.
+#[get("/<token>/<rest..>", rank = 100)]
+#[allow(clippy::too_many_arguments)]
+async fn get_page(
+    token: Token<'_>,
+    rest: Segments<'_, Path>,
+    host: RequestDomain,
+    origin: &Origin<'_>,
+    cookies: &CookieJar<'_>,
+    visitor: Visitor,
+    delivery: Delivery,
+    access: &State<AccessTracker>,
+    log: &RequestContext,
+    config: &State<Config>,
+    events: &State<EventBus>,
+    conn: SharesDbConn,
+) -> Result<Option<ShareResponse>, (Status, String)> {
+    log.set_token(token.token);
+    let search_result = Search::Token(host.0, token.number)
         .find_share(&conn)
-        .await?;
-    if search_result.is_none() {
-        return Ok(None);
+        .await
+        .inspect_err(|e| log.set_error(e.variant()))?;
+    let share = match search_result {
+        Some(share) => access.refresh(share),
+        _ => {
+            metrics::record_redirect("not_found");
+            return Ok(None);
+        }
+    };
+    log.set_share_id(*share.get_id());
+    if share.is_deleted() {
+        metrics::record_redirect("deleted");
+        return Err((Status::Gone, format!("'{}' has been deleted", token.token)));
+    }
+    let now = common::get_time_seconds();
+    let unavailable = fallback::check(&conn, access, &share, now)
+        .await
+        .inspect_err(|e| log.set_error(e.variant()))?;
+    if let Some(reason) = unavailable {
+        metrics::record_redirect(reason.as_str());
+        let location = fallback::destination(&share, config).map(str::to_owned);
+        if let Delivery::Metadata(principal) = &delivery {
+            let metadata = metadata::describe(
+                &conn,
+                access,
+                &share,
+                location,
+                reason.as_str(),
+                principal.as_ref(),
+            )
+            .await
+            .inspect_err(|e| log.set_error(e.variant()))?;
+            return Ok(Some(ShareResponse::Metadata(metadata)));
+        }
+        let location = match location {
+            Some(location) => location,
+            None => return Ok(None),
+        };
+        if delivery.is_click() {
+            let details = serde_json::json!({ "variant": null, "fallback": reason.as_str() });
+            let event = ShareEvent::new(Event::Clicked, &share, details);
+            events.announce(event.clone());
+            access.record_click(&share, None, Some(reason), event);
+            access
+                .flush_if_immediate(&conn)
+                .await
+                .inspect_err(|e| log.set_error(e.variant()))?;
+        }
+        return Ok(Some(ShareResponse::Redirect(ShareRedirect {
+            redirect_type: RedirectType::default(),
+            location,
+            cacheable: false,
+        })));
+    }
+    if share.is_before_nbf(now) {
+        metrics::record_redirect("not_yet_active");
+        if let Delivery::Metadata(principal) = &delivery {
+            let metadata = metadata::describe(
+                &conn,
+                access,
+                &share,
+                None,
+                "not_yet_active",
+                principal.as_ref(),
+            )
+            .await
+            .inspect_err(|e| log.set_error(e.variant()))?;
+            return Ok(Some(ShareResponse::Metadata(metadata)));
+        }
+        return match &config.coming_soon_page {
+            Some(path) => ComingSoon::open(path)
+                .await
+                .map(|page| Some(ShareResponse::ComingSoon(page)))
+                .map_err(|e| (Status::InternalServerError, e.to_string())),
+            None => Ok(None),
+        };
+    }
+    let segments: Vec<&str> = rest.collect();
+    let routed = share.get_rules().route(&visitor, now);
+    let (dest, variant) = match routed {
+        Some(dest) => (dest, None),
+        None => {
+            let variant = split::choose(&share, cookies);
+            (share.get_destination(variant), variant)
+        }
+    };
+    let location = match share
+        .resolve_destination(dest, &segments, origin.query().map(|q| q.as_str()))
+        .inspect_err(|e| log.set_error(e.variant()))?
+    {
+        Some(location) => location,
+        None => {
+            metrics::record_redirect("not_found");
+            return Ok(None);
+        }
+    };
+    metrics::record_redirect("found");
+    if let Delivery::Metadata(principal) = &delivery {
+        let metadata = metadata::describe(
+            &conn,
+            access,
+            &share,
+            Some(location),
+            "active",
+            principal.as_ref(),
+        )
+        .await
+        .inspect_err(|e| log.set_error(e.variant()))?;
+        return Ok(Some(ShareResponse::Metadata(metadata)));
+    }
+    if delivery.is_click() {
+        let details = serde_json::json!({ "variant": variant, "fallback": null });
+        let event = ShareEvent::new(Event::Clicked, &share, details);
+        events.announce(event.clone());
+        access.record_click(&share, variant, None, event);
+        if share.get_idle_ttl().is_some() {
+            access.record(*share.get_id(), now);
+        }
+        access
+            .flush_if_immediate(&conn)
+            .await
+            .inspect_err(|e| log.set_error(e.variant()))?;
     }
-    Ok(Some(Redirect::to(
-        search_result.unwrap().get_dest_url().to_owned(),
-    ))) //SAFETY: This unwrap is fine as we have checked it is non-null above!
+    Ok(Some(ShareResponse::Redirect(ShareRedirect {
+        redirect_type: share
+            .get_redirect_type()
+            .unwrap_or(config.default_redirect_type),
+        location,
+        cacheable: share.is_cacheable(),
+    })))
 }
 
 /// Automatically catch 404 errors and server a slightly more interesting response.
@@ -60,11 +311,114 @@ fn not_found(req: &rocket::Request) -> String {
     format!("Sorry, '{}' is not a valid path.", req.uri())
 }
 
+/// Build the rocket instance, used both by the web server and the command line subcommands.
 #[doc(hidden)]
-#[launch]
-fn rocket() -> _ {
-    rocket::build()
+pub fn rocket() -> Rocket<Build> {
+    build(rocket::Config::figment())
+}
+
+/// Build the rocket instance from the given configuration.
+fn build(figment: rocket::figment::Figment) -> Rocket<Build> {
+    rocket::custom(figment)
         .mount("/", routes![create_shortened_url, get_page, setup_db])
+        .mount("/", metrics::routes())
+        .mount("/", health::routes())
+        .mount("/admin", admin::routes())
+        .mount("/api", api::routes())
+        .mount("/api", webhooks::routes())
+        .mount("/api", events::routes())
+        .mount("/api", workspaces::routes())
         .register("/", catchers![not_found])
+        .register("/api", api::catchers())
+        .manage(EventBus::new())
+        .manage(jwt::KeyCache::new())
         .attach(SharesDbConn::fairing())
+        .attach(AdHoc::config::<Config>())
+        .attach(access::AccessFairing)
+        .attach(webhooks::WebhookWorker)
+        .attach(trash::TrashPurger)
+        .attach(metrics::RequestTimer)
+        .attach(logging::RequestLogger)
+}
+
+/// Build a rocket instance backed by a fresh database in a temporary directory, for use in tests.
+/// The admin key is set to `admin`.
+#[cfg(test)]
+pub fn test_client() -> (rocket::local::blocking::Client, tempfile::TempDir) {
+    test_client_with(|figment| figment)
+}
+
+/// As [`test_client`], with further configuration merged in by `configure`.
+#[cfg(test)]
+pub fn test_client_with(
+    configure: impl FnOnce(rocket::figment::Figment) -> rocket::figment::Figment,
+) -> (rocket::local::blocking::Client, tempfile::TempDir) {
+    let dir = tempfile::tempdir().expect("failed to create temporary directory");
+    let client = rocket::local::blocking::Client::tracked(build(configure(test_figment(&dir))))
+        .expect("failed to build rocket instance");
+    assert_eq!(client.get("/setup").dispatch().status(), Status::Ok);
+    (client, dir)
+}
+
+/// As [`test_client_with`], but asynchronous, for tests which need background tasks to run while they wait.
+#[cfg(test)]
+pub async fn test_async_client_with(
+    configure: impl FnOnce(rocket::figment::Figment) -> rocket::figment::Figment,
+) -> (rocket::local::asynchronous::Client, tempfile::TempDir) {
+    let dir = tempfile::tempdir().expect("failed to create temporary directory");
+    let client = rocket::local::asynchronous::Client::tracked(build(configure(test_figment(&dir))))
+        .await
+        .expect("failed to build rocket instance");
+    assert_eq!(client.get("/setup").dispatch().await.status(), Status::Ok);
+    (client, dir)
+}
+
+/// Store api keys directly in a test database, each given as its secret, owner and whether it is an admin key.
+#[cfg(test)]
+pub fn insert_api_keys(
+    c: &rocket_sync_db_pools::rusqlite::Connection,
+    keys: &[(&str, &str, bool)],
+) {
+    for (secret, owner, admin) in keys {
+        c.execute(
+            "INSERT INTO api_keys (key_hash, owner, admin, crt) VALUES (?1, ?2, ?3, 0);",
+            rocket_sync_db_pools::rusqlite::params![auth::hash_key(secret), owner, admin],
+        )
+        .unwrap();
+    }
+}
+
+/// The configuration shared by test clients, with the database in `dir` and the admin key set to `admin`.
+#[cfg(test)]
+fn test_figment(dir: &tempfile::TempDir) -> rocket::figment::Figment {
+    rocket::Config::figment()
+        .merge((
+            "databases.sqlite_shares.url",
+            dir.path().join("database.db").to_str().unwrap(),
+        ))
+        // The background jobs each hold a connection, leave room for requests on machines with few cores.
+        .merge(("databases.sqlite_shares.pool_size", 8))
+        .merge(("admin_key", "admin"))
+}
+
+#[doc(hidden)]
+#[rocket::main]
+async fn main() {
+    let cli = cli::Cli::parse();
+    match cli.command {
+        None | Some(cli::Command::Serve) => {
+            let config: Config = rocket::Config::figment().extract().unwrap_or_default();
+            logging::init(&config);
+            if let Err(e) = rocket().launch().await {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Some(command) => {
+            if let Err(e) = cli::run(command, cli.json).await {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
 }
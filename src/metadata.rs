@@ -0,0 +1,156 @@
+//! Content negotiation for shortened links. Tools expanding a link may ask for its metadata as json rather than
+//! following the redirect, or make a `HEAD` request to read the `Location` without it counting as a click.
+use crate::access::AccessTracker;
+use crate::auth::Principal;
+use crate::database::{DatabaseError, SharesDbConn};
+use crate::logging::RequestContext;
+use crate::url_id::UrlID;
+use crate::workspaces::Role;
+use rocket::http::{Header, Method};
+use rocket::outcome::Outcome::*;
+use rocket::request::{self, FromRequest, Request};
+use rocket::serde::json::Json;
+use serde::Serialize;
+
+/// How a request for a shortened link wants to be answered.
+pub enum Delivery {
+    /// Redirect to the destination, counting a click.
+    Redirect,
+    /// Send the redirect headers alone, without counting a click.
+    Head,
+    /// Describe the share as json instead of redirecting, without counting a click.
+    /// Carries the caller's identity, if they gave a valid api key, as click counts are only shown to those who may manage the share.
+    Metadata(Option<Principal>),
+}
+
+impl Delivery {
+    /// Whether answering this request counts as a click on the share.
+    pub fn is_click(&self) -> bool {
+        matches!(self, Delivery::Redirect)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Delivery {
+    type Error = std::convert::Infallible;
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        if RequestContext::of(req).method == Method::Head {
+            return Success(Delivery::Head);
+        }
+        if req.accept().is_some_and(|a| a.preferred().is_json()) {
+            let principal = req.guard::<Principal>().await.succeeded();
+            return Success(Delivery::Metadata(principal));
+        }
+        Success(Delivery::Redirect)
+    }
+}
+
+/// The metadata of a share, as returned in place of a redirect.
+#[derive(Debug, Serialize)]
+pub struct ShareMetadata {
+    link: String,
+    /// Where this request would have been redirected, or None if the share is unavailable without a fallback.
+    destination: Option<String>,
+    /// Either `active`, or why the share is not redirecting to its destination.
+    status: &'static str,
+    created: i64,
+    /// When the share expires, taking into account its idle ttl, or None if it never will.
+    expires: Option<i64>,
+    /// The clicks redirected to the share's destination, only present for those who may manage the share.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clicks: Option<i64>,
+}
+
+/// Share metadata sent in place of a redirect. As the same link answers with either, caches are told the response varies by `Accept`.
+#[derive(Responder)]
+pub struct MetadataResponse {
+    body: Json<ShareMetadata>,
+    vary: Header<'static>,
+    cache_control: Header<'static>,
+}
+
+/// Describe a share to a request asking for metadata, including its click count if the caller may manage it.
+pub async fn describe(
+    conn: &SharesDbConn,
+    access: &AccessTracker,
+    share: &UrlID,
+    destination: Option<String>,
+    status: &'static str,
+    principal: Option<&Principal>,
+) -> Result<MetadataResponse, DatabaseError> {
+    let clicks = match principal {
+        Some(p) if p.permits(share, Role::Viewer) => {
+            Some(access.count_redirects(conn, *share.get_id()).await?)
+        }
+        _ => None,
+    };
+    Ok(MetadataResponse {
+        body: Json(ShareMetadata {
+            link: share.get_shortened_link(),
+            destination,
+            status,
+            created: *share.get_crt(),
+            expires: share.effective_exp(),
+            clicks,
+        }),
+        vary: Header::new("Vary", "Accept"),
+        cache_control: Header::new("Cache-Control", "private, no-store"),
+    })
+}
+
+#[test]
+fn test_content_negotiation() {
+    use rocket::http::{Accept, ContentType, Header, Status};
+    let (client, _dir) = crate::test_client();
+    let link = client
+        .post("/shorten")
+        .header(ContentType::JSON)
+        .header(Header::new("Authorization", "Bearer admin"))
+        .body(r#"{"url":"https://a.example","exp":"7d"}"#)
+        .dispatch()
+        .into_string()
+        .unwrap();
+    let token = link.rsplit('/').next().unwrap().to_owned();
+    let clicks = || {
+        let stats: serde_json::Value = client
+            .get(format!("/api/shares/{}/stats", token))
+            .header(Header::new("Authorization", "Bearer admin"))
+            .dispatch()
+            .into_json()
+            .unwrap();
+        stats["clicks"].as_i64().unwrap()
+    };
+
+    let res = client.head(format!("/{}", token)).dispatch();
+    assert_eq!(res.status(), Status::SeeOther);
+    assert_eq!(res.headers().get_one("Location"), Some("https://a.example"));
+    assert_eq!(clicks(), 0);
+
+    let res = client
+        .get(format!("/{}", token))
+        .header(Accept::JSON)
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    let metadata: serde_json::Value = res.into_json().unwrap();
+    assert_eq!(metadata["destination"], "https://a.example");
+    assert_eq!(metadata["status"], "active");
+    assert!(metadata["expires"].is_i64());
+    assert!(metadata.get("clicks").is_none());
+    assert_eq!(clicks(), 0);
+
+    client.get(format!("/{}", token)).dispatch();
+    let metadata: serde_json::Value = client
+        .get(format!("/{}", token))
+        .header(Accept::JSON)
+        .header(Header::new("Authorization", "Bearer admin"))
+        .dispatch()
+        .into_json()
+        .unwrap();
+    assert_eq!(metadata["clicks"], 1);
+
+    let res = client
+        .get(format!("/{}", token))
+        .header(Header::new("Accept", "text/html,application/json;q=0.9"))
+        .dispatch();
+    assert_eq!(res.status(), Status::SeeOther);
+}
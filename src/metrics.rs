@@ -0,0 +1,210 @@
+//! Prometheus metrics, exposed in the text exposition format at `GET /metrics`.
+use crate::common::get_time_seconds;
+use crate::database::{self, SharesDbConn};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{ContentType, Status};
+use rocket::{Data, Request, Response};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+/// Every metric recorded by the server, registered against a single registry.
+pub struct Metrics {
+    registry: Registry,
+    /// Shares created, through any interface.
+    pub shares_created: IntCounter,
+    /// Attempts to follow a shortened link, labelled by `outcome` (found, not_found, expired).
+    pub redirects: IntCounterVec,
+    /// Errors returned to a client, labelled by error `type` and `variant`.
+    pub errors: IntCounterVec,
+    /// Time taken to respond to a request, labelled by `method`, `route` and `status`.
+    pub request_duration: HistogramVec,
+    /// Time taken by closures run against the database connection pool.
+    pub db_duration: Histogram,
+    /// Every share in the database, refreshed when metrics are scraped.
+    pub shares_total: IntGauge,
+    /// Shares which have not yet expired, refreshed when metrics are scraped.
+    pub shares_active: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("url_shortener".into()), None)
+            .expect("failed to create metrics registry");
+        let shares_created = IntCounter::new("shares_created_total", "Shares created").unwrap();
+        let redirects = IntCounterVec::new(
+            Opts::new("redirects_total", "Shortened links followed, by outcome"),
+            &["outcome"],
+        )
+        .unwrap();
+        let errors = IntCounterVec::new(
+            Opts::new(
+                "errors_total",
+                "Errors returned to clients, by type and variant",
+            ),
+            &["type", "variant"],
+        )
+        .unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new("request_duration_seconds", "Time taken to handle requests"),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let db_duration = Histogram::with_opts(
+            HistogramOpts::new(
+                "db_query_duration_seconds",
+                "Time taken by database queries",
+            )
+            .buckets(prometheus::exponential_buckets(0.0001, 4.0, 8).unwrap()),
+        )
+        .unwrap();
+        let shares_total = IntGauge::new(
+            "shares",
+            "Shares in the database, other than those in the trash",
+        )
+        .unwrap();
+        let shares_active = IntGauge::new(
+            "shares_active",
+            "Shares in the database which have not expired or been deleted",
+        )
+        .unwrap();
+
+        registry.register(Box::new(shares_created.clone())).unwrap();
+        registry.register(Box::new(redirects.clone())).unwrap();
+        registry.register(Box::new(errors.clone())).unwrap();
+        registry
+            .register(Box::new(request_duration.clone()))
+            .unwrap();
+        registry.register(Box::new(db_duration.clone())).unwrap();
+        registry.register(Box::new(shares_total.clone())).unwrap();
+        registry.register(Box::new(shares_active.clone())).unwrap();
+
+        Metrics {
+            registry,
+            shares_created,
+            redirects,
+            errors,
+            request_duration,
+            db_duration,
+            shares_total,
+            shares_active,
+        }
+    }
+}
+
+/// The metrics for this process.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Record that an error of the given type and variant was returned to a client.
+pub fn record_error(kind: &str, variant: &str) {
+    METRICS.errors.with_label_values(&[kind, variant]).inc();
+}
+
+/// Record the outcome of an attempt to follow a shortened link.
+pub fn record_redirect(outcome: &str) {
+    METRICS.redirects.with_label_values(&[outcome]).inc();
+}
+
+/// Record how long a database query took.
+pub fn record_db_duration(duration: Duration) {
+    METRICS.db_duration.observe(duration.as_secs_f64());
+}
+
+/// Return all metrics in the prometheus text format.
+/// ```
+/// GET /metrics
+/// ```
+#[get("/metrics")]
+async fn metrics(conn: SharesDbConn) -> Result<(ContentType, String), (Status, String)> {
+    let (total, active) = database::count_shares(&conn, get_time_seconds()).await?;
+    METRICS.shares_total.set(total);
+    METRICS.shares_active.set(active);
+
+    let mut buffer = vec![];
+    TextEncoder::new()
+        .encode(&METRICS.registry.gather(), &mut buffer)
+        .map_err(|e| (Status::InternalServerError, e.to_string()))?;
+    let body =
+        String::from_utf8(buffer).map_err(|e| (Status::InternalServerError, e.to_string()))?;
+    Ok((
+        ContentType::new("text", "plain").with_params(("version", "0.0.4")),
+        body,
+    ))
+}
+
+/// The metrics routes, to be mounted under `/`.
+pub fn routes() -> Vec<rocket::Route> {
+    routes![metrics]
+}
+
+/// A fairing which records the time taken to handle every request, other than health checks.
+pub struct RequestTimer;
+
+/// The time a request was received, stored in the request's local cache.
+struct RequestStart(Option<Instant>);
+
+#[rocket::async_trait]
+impl Fairing for RequestTimer {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request Timer",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, req: &mut Request<'_>, _: &mut Data<'_>) {
+        if crate::health::HEALTH_PATHS.contains(&req.uri().path().as_str()) {
+            return;
+        }
+        req.local_cache(|| RequestStart(Some(Instant::now())));
+    }
+
+    async fn on_response<'r>(&self, req: &'r Request<'_>, res: &mut Response<'r>) {
+        let start = match req.local_cache(|| RequestStart(None)).0 {
+            Some(start) => start,
+            None => return,
+        };
+        let route = req.route().map(|r| r.uri.as_str()).unwrap_or("unmatched");
+        METRICS
+            .request_duration
+            .with_label_values(&[req.method().as_str(), route, &res.status().code.to_string()])
+            .observe(start.elapsed().as_secs_f64());
+    }
+}
+
+#[test]
+fn test_metrics_endpoint() {
+    let (client, _dir) = crate::test_client();
+    let link = client
+        .post("/shorten")
+        .header(ContentType::JSON)
+        .body(r#"{"url":"https://example.com"}"#)
+        .dispatch()
+        .into_string()
+        .unwrap();
+    let token = link.rsplit('/').next().unwrap();
+    assert_eq!(
+        client.get(format!("/{}", token)).dispatch().status(),
+        Status::SeeOther
+    );
+
+    let body = client.get("/metrics").dispatch().into_string().unwrap();
+    assert!(body.contains("url_shortener_shares 1\n"));
+    assert!(body.contains("url_shortener_shares_active 1\n"));
+    assert!(body.contains(r#"url_shortener_redirects_total{outcome="found"}"#));
+    assert!(body.contains("url_shortener_db_query_duration_seconds_count"));
+    assert!(body.contains(r#"route="/<token>/<rest..>",status="303""#));
+
+    // Shares in the trash are no longer counted.
+    let res = client
+        .delete(format!("/api/shares/{}", token))
+        .header(rocket::http::Header::new("Authorization", "Bearer admin"))
+        .dispatch();
+    assert_eq!(res.status(), Status::NoContent);
+    let body = client.get("/metrics").dispatch().into_string().unwrap();
+    assert!(body.contains("url_shortener_shares 0\n"));
+    assert!(body.contains("url_shortener_shares_active 0\n"));
+}
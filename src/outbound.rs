@@ -0,0 +1,186 @@
+//! Outgoing http requests, such as webhook deliveries and fetching JWKS. Requests are made over https or plain http,
+//! and a client may refuse to connect to loopback, private, link-local and cloud metadata addresses, so that a url
+//! chosen by a user cannot be used to reach services only the server can see.
+use hyper::client::connect::dns::{GaiResolver, Name};
+use hyper::client::HttpConnector;
+use hyper::service::Service;
+use hyper_rustls::HttpsConnector;
+use rocket::tokio;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// A client for outgoing requests over https or http.
+pub type Client = hyper::Client<HttpsConnector<HttpConnector<Resolver>>>;
+
+/// Build a client, which refuses to connect to non-public addresses unless `allow_private` is set.
+pub fn client(allow_private: bool) -> Client {
+    let mut http = HttpConnector::new_with_resolver(Resolver {
+        inner: GaiResolver::new(),
+        allow_private,
+    });
+    http.enforce_http(false);
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .wrap_connector(http);
+    hyper::Client::builder().build(https)
+}
+
+/// Whether an address is reachable on the public internet, rather than being loopback, private, link-local (which
+/// includes the cloud metadata address `169.254.169.254`), shared, unspecified or multicast.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || a == 0
+        // Shared address space used for carrier-grade NAT, and by some clouds for their metadata service.
+        || (a == 100 && (64..128).contains(&b)))
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local addresses, fc00::/7.
+        || (first & 0xfe00) == 0xfc00
+        // Link-local addresses, fe80::/10.
+        || (first & 0xffc0) == 0xfe80)
+}
+
+/// Check a url may be requested by a client refusing non-public addresses, resolving its host if it is a name.
+/// Hosts which do not resolve are refused, as are those with any non-public address.
+pub async fn check_public(url: &url::Url) -> Result<(), String> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| format!("'{}' has no host", url))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addrs: Vec<SocketAddr> = match literal(url) {
+        Some(ip) => vec![SocketAddr::new(ip, port)],
+        None => tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|e| format!("'{}' could not be resolved: {}", host, e))?
+            .collect(),
+    };
+    match addrs.iter().find(|a| !is_public(a.ip())) {
+        Some(addr) => Err(format!(
+            "'{}' resolves to {}, which is not a public address",
+            host,
+            addr.ip()
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Check a url does not name a non-public address directly. Names are checked as they are resolved by the client,
+/// but addresses written into the url are connected to without being resolved.
+pub fn check_literal(url: &url::Url) -> Result<(), String> {
+    match literal(url) {
+        Some(ip) if !is_public(ip) => Err(format!("{} is not a public address", ip)),
+        _ => Ok(()),
+    }
+}
+
+/// The address a url's host is written as, if it is not a name.
+fn literal(url: &url::Url) -> Option<IpAddr> {
+    match url.host()? {
+        url::Host::Ipv4(ip) => Some(IpAddr::V4(ip)),
+        url::Host::Ipv6(ip) => Some(IpAddr::V6(ip)),
+        url::Host::Domain(_) => None,
+    }
+}
+
+/// Resolves names with the system resolver, discarding any non-public addresses unless private ones are allowed.
+#[derive(Clone)]
+pub struct Resolver {
+    inner: GaiResolver,
+    allow_private: bool,
+}
+
+impl Service<Name> for Resolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, io::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let resolving = self.inner.call(name.clone());
+        let allow_private = self.allow_private;
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = resolving
+                .await?
+                .filter(|a| allow_private || is_public(a.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("'{}' has no public address", name),
+                ));
+            }
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+#[test]
+fn test_is_public() {
+    for ip in [
+        "127.0.0.1",
+        "10.1.2.3",
+        "172.16.0.1",
+        "192.168.1.1",
+        "169.254.169.254",
+        "100.100.100.200",
+        "0.0.0.0",
+        "255.255.255.255",
+        "::1",
+        "::",
+        "fd00:ec2::254",
+        "fe80::1",
+        "::ffff:127.0.0.1",
+        "::ffff:10.0.0.1",
+    ] {
+        assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+    }
+    for ip in ["93.184.216.34", "1.1.1.1", "2606:4700:4700::1111"] {
+        assert!(is_public(ip.parse().unwrap()), "{}", ip);
+    }
+}
+
+#[rocket::async_test]
+async fn test_check_public() {
+    let check = |url: &str| {
+        let url = url::Url::parse(url).unwrap();
+        async move { check_public(&url).await }
+    };
+    assert!(check("http://127.0.0.1:8000/hook").await.is_err());
+    assert!(check("http://[::1]/hook").await.is_err());
+    assert!(check("http://169.254.169.254/latest/meta-data")
+        .await
+        .is_err());
+    assert!(check("http://localhost/hook").await.is_err());
+    assert!(check("https://93.184.216.34/hook").await.is_ok());
+    assert!(check_literal(&url::Url::parse("http://10.0.0.1/").unwrap()).is_err());
+    assert!(check_literal(&url::Url::parse("https://hooks.example/").unwrap()).is_ok());
+}
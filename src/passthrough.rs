@@ -0,0 +1,197 @@
+//! Passing the query string and trailing path segments of an incoming request on to a share's destination.
+use crate::url_id::UrlIDError;
+use rocket_sync_db_pools::rusqlite::types::{
+    FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef,
+};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+/// When a query parameter appears in both the incoming request and the destination, which value is kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueryPrecedence {
+    /// The value from the incoming request replaces the destination's.
+    #[default]
+    Incoming,
+    /// The destination's value is kept, and the incoming value discarded.
+    Destination,
+}
+
+impl QueryPrecedence {
+    fn as_str(&self) -> &'static str {
+        match self {
+            QueryPrecedence::Incoming => "incoming",
+            QueryPrecedence::Destination => "destination",
+        }
+    }
+}
+
+impl ToSql for QueryPrecedence {
+    fn to_sql(&self) -> rocket_sync_db_pools::rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for QueryPrecedence {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "incoming" => Ok(QueryPrecedence::Incoming),
+            "destination" => Ok(QueryPrecedence::Destination),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// Check a destination is an absolute url which path segments and query parameters can be added to.
+pub fn validate_destination(dest: &str) -> Result<Url, UrlIDError> {
+    let url = Url::parse(dest)
+        .map_err(|e| UrlIDError::ParseFailure(format!("'{}' is not a valid url: {}", dest, e)))?;
+    if url.cannot_be_a_base() {
+        return Err(UrlIDError::ParseFailure(format!(
+            "'{}' cannot have path segments appended to it",
+            dest
+        )));
+    }
+    Ok(url)
+}
+
+/// Build the url to redirect to, appending the trailing `segments` of the request path and merging in its raw `query`.
+/// Segments are expected percent-encoded, as they appear in the request, and are re-encoded when appended.
+pub fn apply(
+    dest: &str,
+    segments: &[&str],
+    query: Option<&str>,
+    precedence: QueryPrecedence,
+) -> Result<String, UrlIDError> {
+    let query = query.filter(|q| !q.is_empty());
+    if segments.is_empty() && query.is_none() {
+        return Ok(dest.to_owned());
+    }
+    let mut url = validate_destination(dest)?;
+
+    if !segments.is_empty() {
+        let mut path = url
+            .path_segments_mut()
+            .map_err(|_| UrlIDError::ServerError("destination cannot be a base".into()))?;
+        path.pop_if_empty();
+        for segment in segments {
+            let decoded = rocket::http::RawStr::new(segment)
+                .percent_decode()
+                .map_err(|e| UrlIDError::ParseFailure(e.to_string()))?;
+            path.push(&decoded);
+        }
+    }
+
+    if let Some(query) = query {
+        let existing: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+        let incoming: Vec<(String, String)> = url::form_urlencoded::parse(query.as_bytes())
+            .into_owned()
+            .collect();
+        let (mut merged, extra) = match precedence {
+            QueryPrecedence::Incoming => (without_keys(existing, &incoming), incoming),
+            QueryPrecedence::Destination => {
+                let extra = without_keys(incoming, &existing);
+                (existing, extra)
+            }
+        };
+        merged.extend(extra);
+        url.query_pairs_mut().clear().extend_pairs(merged);
+    }
+
+    Ok(url.to_string())
+}
+
+/// Remove any pairs whose key also appears in `other`.
+fn without_keys(pairs: Vec<(String, String)>, other: &[(String, String)]) -> Vec<(String, String)> {
+    pairs
+        .into_iter()
+        .filter(|(k, _)| !other.iter().any(|(o, _)| o == k))
+        .collect()
+}
+
+#[test]
+fn test_apply() {
+    assert_eq!(
+        apply(
+            "https://a.example/x?b=1",
+            &[],
+            None,
+            QueryPrecedence::Incoming
+        )
+        .unwrap(),
+        "https://a.example/x?b=1"
+    );
+    assert_eq!(
+        apply(
+            "https://a.example/x/",
+            &["extra", "a%20b", "c%2Fd"],
+            None,
+            QueryPrecedence::Incoming
+        )
+        .unwrap(),
+        "https://a.example/x/extra/a%20b/c%2Fd"
+    );
+    assert_eq!(
+        apply(
+            "https://a.example/?b=1&c=2",
+            &[],
+            Some("c=3&ref=news%20letter"),
+            QueryPrecedence::Incoming
+        )
+        .unwrap(),
+        "https://a.example/?b=1&c=3&ref=news+letter"
+    );
+    assert_eq!(
+        apply(
+            "https://a.example/?b=1&c=2",
+            &[],
+            Some("c=3&ref=x"),
+            QueryPrecedence::Destination
+        )
+        .unwrap(),
+        "https://a.example/?b=1&c=2&ref=x"
+    );
+    assert!(validate_destination("mailto:someone@example.com").is_err());
+}
+
+#[test]
+fn test_passthrough_routes() {
+    use rocket::http::{ContentType, Status};
+    let (client, _dir) = crate::test_client();
+    let create = |body: &str| {
+        let link = client
+            .post("/shorten")
+            .header(ContentType::JSON)
+            .body(body)
+            .dispatch()
+            .into_string()
+            .unwrap();
+        link.rsplit('/').next().unwrap().to_owned()
+    };
+    let location = |path: String| {
+        let res = client.get(path).dispatch();
+        res.headers().get_one("Location").map(str::to_owned)
+    };
+
+    let plain = create(r#"{"url":"https://a.example/?x=1"}"#);
+    assert_eq!(
+        location(format!("/{}?ref=news", plain)).as_deref(),
+        Some("https://a.example/?x=1")
+    );
+    assert_eq!(location(format!("/{}/extra", plain)), None);
+
+    let passing = create(
+        r#"{"url":"https://a.example/docs?x=1","passthrough_query":true,"passthrough_path":true}"#,
+    );
+    assert_eq!(
+        location(format!("/{}/extra/a%20b?ref=news&x=2", passing)).as_deref(),
+        Some("https://a.example/docs/extra/a%20b?ref=news&x=2")
+    );
+
+    let res = client
+        .post("/shorten")
+        .header(ContentType::JSON)
+        .body(r#"{"url":"not a url","passthrough_path":true}"#)
+        .dispatch();
+    assert_eq!(res.status(), Status::BadRequest);
+}
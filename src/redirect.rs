@@ -0,0 +1,287 @@
+//! The kinds of redirect a shortened link may respond with, and the responder used to send them.
+use crate::metadata::MetadataResponse;
+use rocket::fs::NamedFile;
+use rocket::http::{Header, Status};
+use rocket::response::{self, Responder, Response};
+use rocket::Request;
+use rocket_sync_db_pools::rusqlite::types::{
+    FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef,
+};
+use serde::{Deserialize, Serialize};
+
+/// How long browsers and proxies may cache a permanent redirect, in seconds.
+const PERMANENT_MAX_AGE_SECONDS: u32 = 86400;
+
+/// The http status codes a shortened link may redirect with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(try_from = "u16", into = "u16")]
+pub enum RedirectType {
+    /// 301 Moved Permanently
+    MovedPermanently,
+    /// 302 Found
+    Found,
+    /// 303 See Other
+    #[default]
+    SeeOther,
+    /// 307 Temporary Redirect, preserving the request method and body.
+    Temporary,
+    /// 308 Permanent Redirect, preserving the request method and body.
+    Permanent,
+}
+
+impl RedirectType {
+    /// The status code of this redirect.
+    pub fn code(&self) -> u16 {
+        match self {
+            RedirectType::MovedPermanently => 301,
+            RedirectType::Found => 302,
+            RedirectType::SeeOther => 303,
+            RedirectType::Temporary => 307,
+            RedirectType::Permanent => 308,
+        }
+    }
+
+    /// Whether clients may remember this redirect indefinitely.
+    pub fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            RedirectType::MovedPermanently | RedirectType::Permanent
+        )
+    }
+
+    /// The `Cache-Control` header sent alongside this redirect. Permanent redirects to a `cacheable` share may be cached,
+    /// others must not be so that every click reaches the server.
+    pub fn cache_control(&self, cacheable: bool) -> String {
+        if self.is_permanent() && cacheable {
+            format!("public, max-age={}", PERMANENT_MAX_AGE_SECONDS)
+        } else {
+            "private, no-store".to_string()
+        }
+    }
+}
+
+impl TryFrom<u16> for RedirectType {
+    type Error = String;
+    fn try_from(code: u16) -> Result<Self, Self::Error> {
+        match code {
+            301 => Ok(RedirectType::MovedPermanently),
+            302 => Ok(RedirectType::Found),
+            303 => Ok(RedirectType::SeeOther),
+            307 => Ok(RedirectType::Temporary),
+            308 => Ok(RedirectType::Permanent),
+            _ => Err(format!(
+                "{} is not a supported redirect type, expected one of 301, 302, 303, 307 or 308",
+                code
+            )),
+        }
+    }
+}
+
+impl From<RedirectType> for u16 {
+    fn from(redirect: RedirectType) -> u16 {
+        redirect.code()
+    }
+}
+
+impl ToSql for RedirectType {
+    fn to_sql(&self) -> rocket_sync_db_pools::rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.code() as i64))
+    }
+}
+
+impl FromSql for RedirectType {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let code = u16::try_from(value.as_i64()?).map_err(|_| FromSqlError::InvalidType)?;
+        RedirectType::try_from(code).map_err(|_| FromSqlError::InvalidType)
+    }
+}
+
+/// A redirect to the destination of a share, sent with the status and caching headers of its redirect type.
+#[derive(Debug)]
+pub struct ShareRedirect {
+    pub redirect_type: RedirectType,
+    pub location: String,
+    /// Whether the share always redirects every visitor here, see [`crate::url_id::UrlID::is_cacheable`].
+    pub cacheable: bool,
+}
+
+impl<'r> Responder<'r, 'static> for ShareRedirect {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        Response::build()
+            .status(Status::new(self.redirect_type.code()))
+            .header(Header::new("Location", self.location))
+            .header(Header::new(
+                "Cache-Control",
+                self.redirect_type.cache_control(self.cacheable),
+            ))
+            .header(Header::new("Vary", "Accept"))
+            .ok()
+    }
+}
+
+/// The page served in place of a share which has not yet gone live, which must not be cached past its launch.
+#[derive(Responder)]
+pub struct ComingSoon {
+    page: NamedFile,
+    cache_control: Header<'static>,
+}
+
+impl ComingSoon {
+    /// Open the configured coming soon page.
+    pub async fn open(path: &std::path::Path) -> std::io::Result<Self> {
+        Ok(ComingSoon {
+            page: NamedFile::open(path).await?,
+            cache_control: Header::new("Cache-Control", "private, no-store"),
+        })
+    }
+}
+
+/// The responses a request to a share may receive.
+#[derive(Responder)]
+pub enum ShareResponse {
+    Redirect(ShareRedirect),
+    ComingSoon(ComingSoon),
+    Metadata(MetadataResponse),
+}
+
+#[test]
+fn test_redirect_codes() {
+    for code in [301, 302, 303, 307, 308] {
+        assert_eq!(RedirectType::try_from(code).unwrap().code(), code);
+    }
+    assert!(RedirectType::try_from(304).is_err());
+    assert!(serde_json::from_str::<RedirectType>("300").is_err());
+}
+
+#[test]
+fn test_share_redirect_type() {
+    use rocket::http::ContentType;
+    let (client, _dir) = crate::test_client();
+    let create = |body: &str| {
+        client
+            .post("/shorten")
+            .header(ContentType::JSON)
+            .body(body)
+            .dispatch()
+    };
+    let follow = |link: String| {
+        let token = link.rsplit('/').next().unwrap().to_owned();
+        client.get(format!("/{}", token)).dispatch()
+    };
+
+    let res = follow(
+        create(r#"{"url":"https://a.example"}"#)
+            .into_string()
+            .unwrap(),
+    );
+    assert_eq!(res.status(), Status::SeeOther);
+    assert_eq!(
+        res.headers().get_one("Cache-Control"),
+        Some("private, no-store")
+    );
+
+    let link = create(r#"{"url":"https://b.example","redirect_type":308}"#)
+        .into_string()
+        .unwrap();
+    let res = follow(link);
+    assert_eq!(res.status(), Status::PermanentRedirect);
+    assert_eq!(res.headers().get_one("Location"), Some("https://b.example"));
+    assert_eq!(
+        res.headers().get_one("Cache-Control"),
+        Some("public, max-age=86400")
+    );
+
+    // Permanent redirects which may change between visits must not be cached.
+    for body in [
+        r#"{"url":"https://b.example","redirect_type":301,"max_clicks":5}"#,
+        r#"{"url":"https://b.example","redirect_type":301,"exp":"1d"}"#,
+        r#"{"url":"https://b.example","redirect_type":301,"idle_ttl":3600}"#,
+        r#"{"redirect_type":308,"variants":[{"url":"https://a.example","weight":1},{"url":"https://b.example","weight":1}]}"#,
+        r#"{"url":"https://b.example","redirect_type":308,"rules":[{"url":"https://m.example","os":["ios"]}]}"#,
+    ] {
+        let res = follow(create(body).into_string().unwrap());
+        assert!(
+            res.status().code == 301 || res.status().code == 308,
+            "{}",
+            body
+        );
+        assert_eq!(
+            res.headers().get_one("Cache-Control"),
+            Some("private, no-store"),
+            "{}",
+            body
+        );
+    }
+
+    assert_eq!(
+        create(r#"{"url":"https://c.example","redirect_type":300}"#).status(),
+        Status::BadRequest
+    );
+}
+
+#[test]
+fn test_not_before() {
+    use rocket::http::ContentType;
+    let page = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(page.path(), "<h1>Coming soon</h1>").unwrap();
+    let path = page.path().to_str().unwrap().to_owned();
+    let (client, _dir) = crate::test_client_with(|f| f.merge(("coming_soon_page", path)));
+    let create = |body: String| {
+        client
+            .post("/shorten")
+            .header(ContentType::JSON)
+            .body(body)
+            .dispatch()
+    };
+    let now = crate::common::get_time_seconds();
+
+    let link = create(format!(
+        r#"{{"url":"https://a.example","nbf":{}}}"#,
+        now + 3600
+    ))
+    .into_string()
+    .unwrap();
+    let res = client
+        .get(format!("/{}", link.rsplit('/').next().unwrap()))
+        .dispatch();
+    assert_eq!(res.status(), Status::Ok);
+    assert_eq!(res.headers().get_one("Location"), None);
+    assert_eq!(res.into_string().unwrap(), "<h1>Coming soon</h1>");
+
+    let link = create(format!(
+        r#"{{"url":"https://a.example","nbf":{}}}"#,
+        now - 1
+    ))
+    .into_string()
+    .unwrap();
+    let res = client
+        .get(format!("/{}", link.rsplit('/').next().unwrap()))
+        .dispatch();
+    assert_eq!(res.status(), Status::SeeOther);
+
+    assert_eq!(
+        create(format!(
+            r#"{{"url":"https://a.example","nbf":{},"exp":{}}}"#,
+            now + 10,
+            now
+        ))
+        .status(),
+        Status::BadRequest
+    );
+
+    let (client, _dir) = crate::test_client();
+    let link = client
+        .post("/shorten")
+        .header(ContentType::JSON)
+        .body(format!(
+            r#"{{"url":"https://a.example","nbf":{}}}"#,
+            now + 3600
+        ))
+        .dispatch()
+        .into_string()
+        .unwrap();
+    let res = client
+        .get(format!("/{}", link.rsplit('/').next().unwrap()))
+        .dispatch();
+    assert_eq!(res.status(), Status::NotFound);
+}
@@ -0,0 +1,532 @@
+//! Routing rules, which send visitors to different destinations depending on their device, language,
+//! where they came from or when they visit. Rules are evaluated in order, and the first to match wins.
+use crate::url_id::UrlIDError;
+use rocket::request::{self, FromRequest, Request};
+use rocket::time::{OffsetDateTime, UtcOffset, Weekday};
+use rocket_sync_db_pools::rusqlite::types::{
+    FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef,
+};
+use serde::{Deserialize, Deserializer, Serialize};
+use time_tz::{timezones, OffsetDateTimeExt, TimeZone};
+
+/// The operating system families a rule may match, as detected from the `User-Agent` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OsFamily {
+    Windows,
+    MacOs,
+    Ios,
+    Android,
+    ChromeOs,
+    Linux,
+    Other,
+}
+
+impl OsFamily {
+    /// Detect the operating system family from a `User-Agent` header. The order of the checks matters,
+    /// as android and chrome os agents also claim to be linux, and ios agents claim to be like mac os.
+    pub fn detect(user_agent: &str) -> OsFamily {
+        if user_agent.contains("Android") {
+            OsFamily::Android
+        } else if ["iPhone", "iPad", "iPod"]
+            .iter()
+            .any(|d| user_agent.contains(d))
+        {
+            OsFamily::Ios
+        } else if user_agent.contains("CrOS") {
+            OsFamily::ChromeOs
+        } else if user_agent.contains("Windows") {
+            OsFamily::Windows
+        } else if user_agent.contains("Macintosh") || user_agent.contains("Mac OS X") {
+            OsFamily::MacOs
+        } else if user_agent.contains("Linux") {
+            OsFamily::Linux
+        } else {
+            OsFamily::Other
+        }
+    }
+}
+
+/// A day of the week, as used in a schedule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Day {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl From<Weekday> for Day {
+    fn from(day: Weekday) -> Day {
+        match day {
+            Weekday::Monday => Day::Mon,
+            Weekday::Tuesday => Day::Tue,
+            Weekday::Wednesday => Day::Wed,
+            Weekday::Thursday => Day::Thu,
+            Weekday::Friday => Day::Fri,
+            Weekday::Saturday => Day::Sat,
+            Weekday::Sunday => Day::Sun,
+        }
+    }
+}
+
+/// A time of day, written as `HH:MM` and stored as minutes past midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay(u16);
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("'{}' is not a valid time of day, expected HH:MM", s);
+        let (h, m) = s.split_once(':').ok_or_else(invalid)?;
+        let h: u16 = h.parse().map_err(|_| invalid())?;
+        let m: u16 = m.parse().map_err(|_| invalid())?;
+        if h > 23 || m > 59 {
+            return Err(invalid());
+        }
+        Ok(TimeOfDay(h * 60 + m))
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(t: TimeOfDay) -> String {
+        format!("{:02}:{:02}", t.0 / 60, t.0 % 60)
+    }
+}
+
+/// A timezone, given as an IANA name such as `Pacific/Auckland`, whose offset follows daylight saving time, or as a
+/// fixed offset from utc such as `+13:00`, `-05:00` or `UTC`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(into = "String")]
+pub enum Timezone {
+    Offset(UtcOffset),
+    /// The canonical name of a zone in the timezone database.
+    Named(&'static str),
+}
+
+impl Timezone {
+    /// Convert a time to the local time of this timezone.
+    fn local(&self, t: OffsetDateTime) -> OffsetDateTime {
+        match self {
+            Timezone::Offset(offset) => t.to_offset(*offset),
+            Timezone::Named(name) => match timezones::get_by_name(name) {
+                Some(tz) => t.to_timezone(tz),
+                None => t,
+            },
+        }
+    }
+}
+
+impl Default for Timezone {
+    fn default() -> Self {
+        Timezone::Offset(UtcOffset::UTC)
+    }
+}
+
+impl TryFrom<String> for Timezone {
+    type Error = String;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let invalid = || {
+            format!(
+                "'{}' is not a valid timezone, expected a name such as Pacific/Auckland, UTC or an offset such as +13:00",
+                s
+            )
+        };
+        let offset = s.strip_prefix("UTC").unwrap_or(&s);
+        if offset.is_empty() || offset == "Z" {
+            return Ok(Timezone::default());
+        }
+        let (sign, offset) = match (offset.strip_prefix('+'), offset.strip_prefix('-')) {
+            (Some(rest), _) => (1, rest),
+            (_, Some(rest)) => (-1, rest),
+            _ => {
+                return timezones::get_by_name(&s)
+                    .map(|tz| Timezone::Named(tz.name()))
+                    .ok_or_else(invalid)
+            }
+        };
+        let (h, m) = offset.split_once(':').unwrap_or((offset, "0"));
+        let h: i8 = h.parse().map_err(|_| invalid())?;
+        let m: i8 = m.parse().map_err(|_| invalid())?;
+        UtcOffset::from_hms(sign * h, sign * m, 0)
+            .map(Timezone::Offset)
+            .map_err(|_| invalid())
+    }
+}
+
+// Derived with `try_from`, serde would require the name to be borrowed from the input for as long as it is kept.
+impl<'de> Deserialize<'de> for Timezone {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        Timezone::try_from(String::deserialize(d)?).map_err(serde::de::Error::custom)
+    }
+}
+
+impl From<Timezone> for String {
+    fn from(tz: Timezone) -> String {
+        match tz {
+            Timezone::Offset(offset) => {
+                let (h, m, _) = offset.as_hms();
+                let sign = if offset.is_negative() { '-' } else { '+' };
+                format!("{}{:02}:{:02}", sign, h.abs(), m.abs())
+            }
+            Timezone::Named(name) => name.to_owned(),
+        }
+    }
+}
+
+/// A weekly window of time in which a rule applies. A window whose start is after its end wraps past midnight.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schedule {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub days: Option<Vec<Day>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start: Option<TimeOfDay>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end: Option<TimeOfDay>,
+    #[serde(default)]
+    pub timezone: Timezone,
+}
+
+impl Schedule {
+    /// Whether the given time, in seconds since the unix epoch, falls within this schedule.
+    /// The hours after midnight of a window which wraps past it belong to the day the window started on.
+    fn matches(&self, now: i64) -> bool {
+        let local = match OffsetDateTime::from_unix_timestamp(now) {
+            Ok(t) => self.timezone.local(t),
+            Err(_) => return false,
+        };
+        let t = TimeOfDay(local.hour() as u16 * 60 + local.minute() as u16);
+        let today = local.weekday();
+        let (within, day) = match (self.start, self.end) {
+            (Some(start), Some(end)) if start <= end => (start <= t && t < end, today),
+            (Some(start), Some(_)) if t >= start => (true, today),
+            (Some(_), Some(end)) => (t < end, today.previous()),
+            (Some(start), None) => (t >= start, today),
+            (None, Some(end)) => (t < end, today),
+            (None, None) => (true, today),
+        };
+        within
+            && self
+                .days
+                .as_ref()
+                .is_none_or(|days| days.contains(&Day::from(day)))
+    }
+}
+
+/// A destination used when every condition given alongside it matches the visitor.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rule {
+    pub url: String,
+    /// Operating system families, any of which match.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os: Option<Vec<OsFamily>>,
+    /// Language tags such as `de` or `en-GB`, matched against the visitor's preferred language.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub languages: Option<Vec<String>>,
+    /// Hosts the visitor may have been referred from, including their subdomains.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub referrers: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<Schedule>,
+}
+
+impl Rule {
+    /// Check the rule has at least one condition, and none which can never match.
+    fn validate(&self) -> Result<(), UrlIDError> {
+        let lists = [
+            ("os", self.os.as_ref().map(Vec::len)),
+            ("languages", self.languages.as_ref().map(Vec::len)),
+            ("referrers", self.referrers.as_ref().map(Vec::len)),
+            (
+                "schedule.days",
+                self.schedule
+                    .as_ref()
+                    .and_then(|s| s.days.as_ref())
+                    .map(Vec::len),
+            ),
+        ];
+        if let Some((name, _)) = lists.iter().find(|(_, len)| *len == Some(0)) {
+            return Err(UrlIDError::ParseFailure(format!(
+                "the rule for '{}' has an empty {} list, which can never match",
+                self.url, name
+            )));
+        }
+        if lists.iter().take(3).all(|(_, len)| len.is_none()) && self.schedule.is_none() {
+            return Err(UrlIDError::ParseFailure(format!(
+                "the rule for '{}' has no conditions",
+                self.url
+            )));
+        }
+        Ok(())
+    }
+
+    /// Whether every condition of this rule matches the visitor.
+    fn matches(&self, visitor: &Visitor, now: i64) -> bool {
+        if let Some(os) = &self.os {
+            let detected = OsFamily::detect(visitor.user_agent.as_deref().unwrap_or_default());
+            if !os.contains(&detected) {
+                return false;
+            }
+        }
+        if let Some(languages) = &self.languages {
+            let preferred = match &visitor.language {
+                Some(l) => l,
+                None => return false,
+            };
+            if !languages.iter().any(|l| language_matches(l, preferred)) {
+                return false;
+            }
+        }
+        if let Some(referrers) = &self.referrers {
+            let host = match &visitor.referrer_host {
+                Some(h) => h,
+                None => return false,
+            };
+            if !referrers.iter().any(|r| host_matches(r, host)) {
+                return false;
+            }
+        }
+        if let Some(schedule) = &self.schedule {
+            if !schedule.matches(now) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Whether a language tag from a rule covers the visitor's language, e.g. `en` covers `en-GB`.
+fn language_matches(rule: &str, language: &str) -> bool {
+    let (rule, language) = (rule.to_ascii_lowercase(), language.to_ascii_lowercase());
+    language == rule || language.starts_with(&format!("{}-", rule))
+}
+
+/// Whether a host from a rule covers the referring host, e.g. `example.com` covers `news.example.com`.
+fn host_matches(rule: &str, host: &str) -> bool {
+    let (rule, host) = (rule.to_ascii_lowercase(), host.to_ascii_lowercase());
+    host == rule || host.ends_with(&format!(".{}", rule))
+}
+
+/// The ordered routing rules of a share, empty if it has none.
+/// Stored in the database as a json array, and accepted as a json encoded string so it may round trip through csv.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct Rules(Vec<Rule>);
+
+impl Rules {
+    pub fn iter(&self) -> std::slice::Iter<'_, Rule> {
+        self.0.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Check every rule can match some visitor.
+    pub fn validate(&self) -> Result<(), UrlIDError> {
+        self.0.iter().try_for_each(Rule::validate)
+    }
+
+    /// Find the destination of the first rule matching the visitor, if any.
+    pub fn route(&self, visitor: &Visitor, now: i64) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|r| r.matches(visitor, now))
+            .map(|r| r.url.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Rules {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            List(Vec<Rule>),
+            Encoded(String),
+        }
+        match Option::<Repr>::deserialize(deserializer)? {
+            None => Ok(Rules::default()),
+            Some(Repr::List(list)) => Ok(Rules(list)),
+            Some(Repr::Encoded(s)) if s.is_empty() => Ok(Rules::default()),
+            Some(Repr::Encoded(s)) => serde_json::from_str(&s)
+                .map(Rules)
+                .map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+impl ToSql for Rules {
+    fn to_sql(&self) -> rocket_sync_db_pools::rusqlite::Result<ToSqlOutput<'_>> {
+        if self.0.is_empty() {
+            return Ok(ToSqlOutput::from(
+                rocket_sync_db_pools::rusqlite::types::Null,
+            ));
+        }
+        let json = serde_json::to_string(&self.0).map_err(|e| {
+            rocket_sync_db_pools::rusqlite::Error::ToSqlConversionFailure(Box::new(e))
+        })?;
+        Ok(ToSqlOutput::from(json))
+    }
+}
+
+impl FromSql for Rules {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value {
+            ValueRef::Null => Ok(Rules::default()),
+            value => serde_json::from_str(value.as_str()?)
+                .map(Rules)
+                .map_err(|e| FromSqlError::Other(Box::new(e))),
+        }
+    }
+}
+
+/// What is known about the visitor following a link, as used to evaluate routing rules.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Visitor {
+    pub user_agent: Option<String>,
+    /// The visitor's most preferred language from `Accept-Language`.
+    pub language: Option<String>,
+    /// The host of the `Referer` header.
+    pub referrer_host: Option<String>,
+}
+
+/// Find the most preferred language in an `Accept-Language` header, ignoring the wildcard.
+fn preferred_language(header: &str) -> Option<String> {
+    header
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';').map(str::trim);
+            let tag = parts.next().filter(|t| !t.is_empty() && *t != "*")?;
+            let q = parts
+                .find_map(|p| p.strip_prefix("q="))
+                .map(|q| q.parse::<f32>().unwrap_or(0.0))
+                .unwrap_or(1.0);
+            Some((tag, q))
+        })
+        .filter(|(_, q)| *q > 0.0)
+        .fold(None, |best: Option<(&str, f32)>, (tag, q)| match best {
+            Some((_, best_q)) if best_q >= q => best,
+            _ => Some((tag, q)),
+        })
+        .map(|(tag, _)| tag.to_owned())
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Visitor {
+    type Error = std::convert::Infallible;
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let headers = req.headers();
+        request::Outcome::Success(Visitor {
+            user_agent: headers.get_one("User-Agent").map(str::to_owned),
+            language: headers
+                .get_one("Accept-Language")
+                .and_then(preferred_language),
+            referrer_host: headers
+                .get_one("Referer")
+                .and_then(|r| url::Url::parse(r).ok())
+                .and_then(|u| u.host_str().map(str::to_owned)),
+        })
+    }
+}
+
+#[test]
+fn test_rule_matching() {
+    let rules: Rules = serde_json::from_str(
+        r#"[
+            {"url":"https://a.example/ios","os":["ios"]},
+            {"url":"https://a.example/de","languages":["de"]},
+            {"url":"https://a.example/news","referrers":["news.example"]},
+            {"url":"https://a.example/night","schedule":{"days":["sat"],"start":"22:00","end":"02:00","timezone":"+13:00"}}
+        ]"#,
+    )
+    .unwrap();
+    rules.validate().unwrap();
+    let visitor = |ua: &str, lang: &str, referrer: &str| Visitor {
+        user_agent: Some(ua.into()),
+        language: preferred_language(lang),
+        referrer_host: Some(referrer.into()),
+    };
+    let iphone = "Mozilla/5.0 (iPhone; CPU iPhone OS 16_0 like Mac OS X)";
+    let windows = "Mozilla/5.0 (Windows NT 10.0; Win64; x64)";
+    // Saturday 2023-01-07 23:30 in +13:00.
+    let saturday_night = 1673087400;
+    let monday = saturday_night + 2 * 86400;
+
+    assert_eq!(
+        rules.route(&visitor(iphone, "de", "x.example"), monday),
+        Some("https://a.example/ios")
+    );
+    assert_eq!(
+        rules.route(&visitor(windows, "fr;q=0.5, de-AT", "x.example"), monday),
+        Some("https://a.example/de")
+    );
+    assert_eq!(
+        rules.route(&visitor(windows, "en", "www.news.example"), monday),
+        Some("https://a.example/news")
+    );
+    assert_eq!(
+        rules.route(&visitor(windows, "en", "x.example"), saturday_night),
+        Some("https://a.example/night")
+    );
+    assert_eq!(
+        rules.route(&visitor(windows, "en", "x.example"), monday),
+        None
+    );
+    // The early hours of Sunday belong to Saturday night, while those of Saturday belong to Friday night.
+    assert_eq!(
+        rules.route(
+            &visitor(windows, "en", "x.example"),
+            saturday_night + 2 * 3600
+        ),
+        Some("https://a.example/night")
+    );
+    assert_eq!(
+        rules.route(
+            &visitor(windows, "en", "x.example"),
+            saturday_night - 22 * 3600
+        ),
+        None
+    );
+
+    assert_eq!(
+        OsFamily::detect("Mozilla/5.0 (Linux; Android 13)"),
+        OsFamily::Android
+    );
+    assert!(
+        serde_json::from_str::<Rules>(r#"[{"url":"https://a.example"}]"#)
+            .unwrap()
+            .validate()
+            .is_err()
+    );
+    assert!(serde_json::from_str::<Rules>(
+        r#"[{"url":"https://a.example","schedule":{"start":"25:00"}}]"#
+    )
+    .is_err());
+    assert!(serde_json::from_str::<Rules>(
+        r#"[{"url":"https://a.example","schedule":{"timezone":"Pacific/Nowhere"}}]"#
+    )
+    .is_err());
+}
+
+#[test]
+fn test_named_timezones() {
+    let schedule: Schedule =
+        serde_json::from_str(r#"{"start":"09:00","end":"17:00","timezone":"Pacific/Auckland"}"#)
+            .unwrap();
+    assert_eq!(schedule.timezone, Timezone::Named("Pacific/Auckland"));
+    assert_eq!(
+        serde_json::to_value(&schedule).unwrap()["timezone"],
+        "Pacific/Auckland"
+    );
+    // Auckland is at +13:00 in January and +12:00 in July, so 20:30 utc is 09:30 in January but 08:30 in July.
+    let january = 1768509000; // 2026-01-15 20:30 utc
+    let july = 1784147400; // 2026-07-15 20:30 utc
+    assert!(schedule.matches(january));
+    assert!(!schedule.matches(july));
+    assert!(schedule.matches(july + 3600));
+}
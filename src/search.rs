@@ -0,0 +1,95 @@
+//! Full-text search over shares, backed by the sqlite FTS5 table `shares_fts`. Each share is indexed by its destination,
+//! title, notes and tags, and the index is rewritten whenever the share is.
+use crate::database::{DatabaseError, FromDatabase, Search, SharesDbConn, SHARE_COLUMNS};
+use crate::url_id::UrlID;
+use rocket_sync_db_pools::rusqlite::{self, params};
+
+/// How heavily a match in each indexed column counts towards a share's rank, in the order url, title, notes, tags.
+/// A word in the title says more about a share than the same word buried in its destination.
+const COLUMN_WEIGHTS: &str = "2.0, 10.0, 4.0, 6.0";
+
+/// Rewrite the index entry of a share, removing it if the share no longer exists.
+pub fn reindex_share(c: &rusqlite::Connection, share_id: i64) -> Result<(), rusqlite::Error> {
+    c.execute(
+        "DELETE FROM shares_fts WHERE rowid = ?1;",
+        params![share_id],
+    )?;
+    c.execute(
+        "INSERT INTO shares_fts (rowid, url, title, notes, tags)
+        SELECT id, url, title, notes, (
+            SELECT group_concat(tags.name, ' ') FROM share_tags JOIN tags ON tags.id = share_tags.tag_id
+            WHERE share_tags.share_id = shares.id
+        ) FROM shares WHERE id = ?1;",
+        params![share_id],
+    )?;
+    Ok(())
+}
+
+/// Remove the index entries of shares which no longer exist.
+pub fn remove_orphans(c: &rusqlite::Connection) -> Result<(), rusqlite::Error> {
+    c.execute(
+        "DELETE FROM shares_fts WHERE rowid NOT IN (SELECT id FROM shares);",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Turn free text into an FTS5 query matching shares containing every word, treating each word as a prefix so
+/// results appear while a word is still being typed. Returns None if the text contains no words.
+/// Words are quoted, so characters with a meaning in the FTS5 query syntax are matched literally rather than rejected.
+pub fn to_match_query(text: &str) -> Option<String> {
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| format!("\"{}\"*", w))
+        .collect();
+    match words.is_empty() {
+        true => None,
+        false => Some(words.join(" ")),
+    }
+}
+
+/// Find the shares matching an FTS5 query and the given search, best matches first.
+pub async fn find_matches(
+    conn: &SharesDbConn,
+    query: String,
+    search: Search,
+    limit: i64,
+) -> Result<Vec<UrlID>, DatabaseError> {
+    let result = conn
+        .run_timed(move |c| {
+            let mut values = vec![query.into()];
+            let condition = search.get_search_term(&mut values);
+            values.push(limit.into());
+            c.prepare(&format!(
+                "WITH matches AS (
+                    SELECT rowid AS share_id, bm25(shares_fts, {}) AS score FROM shares_fts WHERE shares_fts MATCH ?1
+                )
+                SELECT {} FROM shares JOIN matches ON matches.share_id = shares.id
+                WHERE {} ORDER BY matches.score, shares.id DESC LIMIT ?{};",
+                COLUMN_WEIGHTS,
+                SHARE_COLUMNS,
+                condition,
+                values.len()
+            ))
+            .and_then(|mut res| {
+                res.query_map(rusqlite::params_from_iter(values), UrlID::from_database)?
+                    .collect()
+            })
+        })
+        .await?;
+    Ok(result)
+}
+
+#[test]
+fn test_match_query() {
+    assert_eq!(
+        to_match_query("Q3 planning-doc"),
+        Some(r#""Q3"* "planning"* "doc"*"#.into())
+    );
+    assert_eq!(
+        to_match_query(r#"" OR NEAR("#),
+        Some(r#""OR"* "NEAR"*"#.into())
+    );
+    assert_eq!(to_match_query("  ---  "), None);
+}
@@ -0,0 +1,251 @@
+//! Weighted split destinations, where each click on a link is sent to one of several destinations
+//! chosen at random in proportion to their weights, e.g. to A/B test two landing pages behind one link.
+use crate::url_id::{UrlID, UrlIDError};
+use rand::Rng;
+use rocket::http::{Cookie, CookieJar};
+use rocket_sync_db_pools::rusqlite::types::{
+    FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef,
+};
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// How long a visitor remains assigned to the same variant of a sticky link, in seconds.
+const STICKY_MAX_AGE_SECONDS: i64 = 60 * 60 * 24 * 30;
+
+/// One of the destinations of a split link.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Variant {
+    pub url: String,
+    /// The relative share of clicks sent to this destination. Defaults to 1.
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// The destinations of a split link, empty if the link has a single destination.
+/// Stored in the database as a json array, and accepted as a json encoded string so it may round trip through csv.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct Variants(Vec<Variant>);
+
+impl Variants {
+    pub fn new(variants: Vec<Variant>) -> Self {
+        Variants(variants)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Variant> {
+        self.0.get(index)
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, Variant> {
+        self.0.iter()
+    }
+
+    /// Check at least one variant may be chosen.
+    pub fn validate(&self) -> Result<(), UrlIDError> {
+        if !self.is_empty() && self.total_weight() == 0 {
+            return Err(UrlIDError::ParseFailure(
+                "at least one variant must have a weight above 0".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn total_weight(&self) -> u64 {
+        self.0.iter().map(|v| v.weight as u64).sum()
+    }
+
+    /// Choose the index of a variant at random, in proportion to the weights. Returns None if there are no variants.
+    pub fn pick<R: Rng>(&self, rng: &mut R) -> Option<usize> {
+        let total = self.total_weight();
+        if total == 0 {
+            return None;
+        }
+        let mut roll = rng.gen_range(0..total);
+        for (i, variant) in self.0.iter().enumerate() {
+            if roll < variant.weight as u64 {
+                return Some(i);
+            }
+            roll -= variant.weight as u64;
+        }
+        None
+    }
+}
+
+impl<'de> Deserialize<'de> for Variants {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            List(Vec<Variant>),
+            Encoded(String),
+        }
+        match Option::<Repr>::deserialize(deserializer)? {
+            None => Ok(Variants::default()),
+            Some(Repr::List(list)) => Ok(Variants(list)),
+            Some(Repr::Encoded(s)) if s.is_empty() => Ok(Variants::default()),
+            Some(Repr::Encoded(s)) => serde_json::from_str(&s)
+                .map(Variants)
+                .map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+impl ToSql for Variants {
+    fn to_sql(&self) -> rocket_sync_db_pools::rusqlite::Result<ToSqlOutput<'_>> {
+        if self.is_empty() {
+            return Ok(ToSqlOutput::from(
+                rocket_sync_db_pools::rusqlite::types::Null,
+            ));
+        }
+        let json = serde_json::to_string(&self.0).map_err(|e| {
+            rocket_sync_db_pools::rusqlite::Error::ToSqlConversionFailure(Box::new(e))
+        })?;
+        Ok(ToSqlOutput::from(json))
+    }
+}
+
+impl FromSql for Variants {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value {
+            ValueRef::Null => Ok(Variants::default()),
+            value => serde_json::from_str(value.as_str()?)
+                .map(Variants)
+                .map_err(|e| FromSqlError::Other(Box::new(e))),
+        }
+    }
+}
+
+/// The name of the cookie remembering which variant of a share a visitor was assigned.
+/// Named by id rather than token, as every token of a share carries different random padding.
+fn cookie_name(share: &UrlID) -> String {
+    format!("variant_{}", share.get_id())
+}
+
+/// Choose which variant of a share to send a visitor to, or None if the share has a single destination.
+/// Sticky shares reuse the variant remembered in the visitor's cookie, assigning and remembering one if there is none.
+pub fn choose(share: &UrlID, cookies: &CookieJar<'_>) -> Option<usize> {
+    let variants = share.get_variants();
+    if variants.is_empty() {
+        return None;
+    }
+    let name = cookie_name(share);
+    if share.get_sticky() {
+        let remembered = cookies
+            .get(&name)
+            .and_then(|c| c.value().parse::<usize>().ok())
+            .filter(|i| *i < variants.len());
+        if remembered.is_some() {
+            return remembered;
+        }
+    }
+    let chosen = variants.pick(&mut rand::thread_rng())?;
+    if share.get_sticky() {
+        cookies.add(
+            Cookie::build(name, chosen.to_string())
+                .path("/")
+                .max_age(rocket::time::Duration::seconds(STICKY_MAX_AGE_SECONDS))
+                .finish(),
+        );
+    }
+    Some(chosen)
+}
+
+#[test]
+fn test_pick_follows_weights() {
+    use rand::SeedableRng;
+    let variants = Variants::new(vec![
+        Variant {
+            url: "https://a.example".into(),
+            weight: 3,
+        },
+        Variant {
+            url: "https://b.example".into(),
+            weight: 1,
+        },
+        Variant {
+            url: "https://c.example".into(),
+            weight: 0,
+        },
+    ]);
+    let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+    let mut counts = [0; 3];
+    for _ in 0..4000 {
+        counts[variants.pick(&mut rng).unwrap()] += 1;
+    }
+    assert_eq!(counts[2], 0);
+    assert!((2800..3200).contains(&counts[0]), "{:?}", counts);
+
+    assert_eq!(Variants::default().pick(&mut rng), None);
+    let zero = Variants::new(vec![Variant {
+        url: "https://a.example".into(),
+        weight: 0,
+    }]);
+    assert!(zero.validate().is_err());
+}
+
+#[test]
+fn test_split_routes() {
+    use rocket::http::{ContentType, Header, Status};
+    let (client, _dir) = crate::test_client();
+    let create = |body: &str| {
+        client
+            .post("/shorten")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", "Bearer admin"))
+            .body(body)
+            .dispatch()
+    };
+    let link = create(
+        r#"{"variants":[{"url":"https://a.example","weight":1},{"url":"https://b.example","weight":1}],"sticky":true}"#,
+    )
+    .into_string()
+    .unwrap();
+    let token = link.rsplit('/').next().unwrap().to_owned();
+
+    let first = client.get(format!("/{}", token)).dispatch();
+    let location = first.headers().get_one("Location").unwrap().to_owned();
+    assert!(first.cookies().get(&format!("variant_{}", 1)).is_some());
+    for _ in 0..10 {
+        let res = client.get(format!("/{}", token)).dispatch();
+        assert_eq!(res.headers().get_one("Location"), Some(location.as_str()));
+    }
+
+    let stats = client
+        .get(format!("/api/shares/{}/stats", token))
+        .header(Header::new("Authorization", "Bearer admin"))
+        .dispatch();
+    assert_eq!(stats.status(), Status::Ok);
+    let stats: serde_json::Value = stats.into_json().unwrap();
+    assert_eq!(stats["clicks"], 11);
+    let chosen = if location == "https://a.example" {
+        0
+    } else {
+        1
+    };
+    assert_eq!(stats["variants"][chosen]["clicks"], 11);
+    assert_eq!(stats["variants"][1 - chosen]["clicks"], 0);
+
+    assert_eq!(
+        client
+            .get(format!("/api/shares/{}/stats", token))
+            .dispatch()
+            .status(),
+        Status::Unauthorized
+    );
+    assert_eq!(
+        create(r#"{"variants":[{"url":"https://a.example","weight":0}]}"#).status(),
+        Status::BadRequest
+    );
+    assert_eq!(create(r#"{}"#).status(), Status::BadRequest);
+}
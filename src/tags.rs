@@ -0,0 +1,125 @@
+//! Free-form tags attached to shares, used to group and filter them. Tags are stored once in the `tags` table and linked
+//! to any number of shares through `share_tags`.
+use crate::url_id::UrlIDError;
+use rocket_sync_db_pools::rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ValueRef};
+use rocket_sync_db_pools::rusqlite::{self, params};
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// The longest tag accepted, in chars.
+const MAX_TAG_LENGTH_CHARS: usize = 64;
+
+/// The most tags a single share may carry.
+const MAX_TAGS: usize = 32;
+
+/// The tags of a share, kept sorted and without duplicates.
+/// Accepted as a json encoded string as well as a list, so it may round trip through csv.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct Tags(Vec<String>);
+
+impl Tags {
+    /// Collect tags, trimming whitespace and dropping duplicates and empty tags.
+    pub fn new(tags: Vec<String>) -> Self {
+        let mut tags: Vec<String> = tags
+            .into_iter()
+            .map(|t| t.trim().to_owned())
+            .filter(|t| !t.is_empty())
+            .collect();
+        tags.sort();
+        tags.dedup();
+        Tags(tags)
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, String> {
+        self.0.iter()
+    }
+
+    /// Check there are not too many tags, and none are too long.
+    pub fn validate(&self) -> Result<(), UrlIDError> {
+        if self.0.len() > MAX_TAGS {
+            return Err(UrlIDError::ParseFailure(format!(
+                "a share may have at most {} tags",
+                MAX_TAGS
+            )));
+        }
+        match self
+            .0
+            .iter()
+            .find(|t| t.chars().count() > MAX_TAG_LENGTH_CHARS)
+        {
+            Some(tag) => Err(UrlIDError::ParseFailure(format!(
+                "tag '{}' is longer than {} chars",
+                tag, MAX_TAG_LENGTH_CHARS
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Tags {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            List(Vec<String>),
+            Encoded(String),
+        }
+        match Option::<Repr>::deserialize(deserializer)? {
+            None => Ok(Tags::default()),
+            Some(Repr::List(list)) => Ok(Tags::new(list)),
+            Some(Repr::Encoded(s)) if s.is_empty() => Ok(Tags::default()),
+            Some(Repr::Encoded(s)) => serde_json::from_str(&s)
+                .map(Tags::new)
+                .map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// Read from the json array the tags of a share are selected as, see [`crate::database::SHARE_COLUMNS`].
+impl FromSql for Tags {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value {
+            ValueRef::Null => Ok(Tags::default()),
+            value => serde_json::from_str(value.as_str()?)
+                .map(Tags::new)
+                .map_err(|e| FromSqlError::Other(Box::new(e))),
+        }
+    }
+}
+
+/// Replace the tags of a share, creating any tag which does not yet exist.
+pub fn set_share_tags(
+    c: &rusqlite::Connection,
+    share_id: i64,
+    tags: &Tags,
+) -> Result<(), rusqlite::Error> {
+    c.execute(
+        "DELETE FROM share_tags WHERE share_id = ?1;",
+        params![share_id],
+    )?;
+    let mut create = c.prepare_cached("INSERT OR IGNORE INTO tags (name) VALUES (?1);")?;
+    let mut link = c.prepare_cached(
+        "INSERT INTO share_tags (share_id, tag_id) SELECT ?1, id FROM tags WHERE name = ?2;",
+    )?;
+    for tag in tags.iter() {
+        create.execute(params![tag])?;
+        link.execute(params![share_id, tag])?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_tags_are_normalised() {
+    let tags = Tags::new(vec![
+        " launch ".into(),
+        "beta".into(),
+        "launch".into(),
+        "".into(),
+    ]);
+    assert_eq!(tags, Tags(vec!["beta".into(), "launch".into()]));
+    assert_eq!(
+        serde_json::from_str::<Tags>(r#""[\"b\",\"a\"]""#).unwrap(),
+        Tags(vec!["a".into(), "b".into()])
+    );
+    assert!(Tags::new(vec!["x".repeat(65)]).validate().is_err());
+}
@@ -0,0 +1,218 @@
+//! Templated destinations, where placeholders such as `{1}`, `{2}` and `{*}` in the destination are filled
+//! from the path segments which follow the token, e.g. `/abc123/PROJ-123` with a destination of
+//! `https://jira.example.com/browse/{1}`. Any other brace is part of the destination, and `{{` and `}}` may be
+//! written for a literal brace where one would otherwise start or end a placeholder.
+use crate::url_id::UrlIDError;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+/// Characters left unencoded when substituting a parameter: the unreserved set of RFC 3986.
+const PARAMETER: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// A piece of a parsed template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    /// `{n}`, the nth path segment after the token, counting from 1.
+    Positional(usize),
+    /// `{*}`, every path segment after the last positional parameter, joined by `/`.
+    Rest,
+}
+
+/// A destination containing placeholders.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    /// Parse a destination, returning None if it contains no placeholders or escaped braces.
+    /// Only `{n}` and `{*}` are placeholders, `{{` and `}}` stand for a literal brace, and any other brace is kept as it is.
+    pub fn parse(dest: &str) -> Option<Template> {
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut rest = dest;
+        while let Some(start) = rest.find(['{', '}']) {
+            literal.push_str(&rest[..start]);
+            rest = &rest[start..];
+            if rest.starts_with("{{") || rest.starts_with("}}") {
+                literal.push_str(&rest[..1]);
+                rest = &rest[2..];
+                continue;
+            }
+            let end = rest.find('}').filter(|_| rest.starts_with('{'));
+            let placeholder = end.and_then(|end| match &rest[1..end] {
+                "*" => Some((Part::Rest, end)),
+                n if n.bytes().all(|b| b.is_ascii_digit()) => match n.parse::<usize>() {
+                    Ok(n) if n > 0 => Some((Part::Positional(n), end)),
+                    _ => None,
+                },
+                _ => None,
+            });
+            match placeholder {
+                Some((part, end)) => {
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(part);
+                    rest = &rest[end + 1..];
+                }
+                None => {
+                    literal.push_str(&rest[..1]);
+                    rest = &rest[1..];
+                }
+            }
+        }
+        literal.push_str(rest);
+        if parts.is_empty() && literal == dest {
+            return None;
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Some(Template { parts })
+    }
+
+    /// How many path segments must follow the token for every positional parameter to be filled.
+    pub fn required(&self) -> usize {
+        self.parts
+            .iter()
+            .filter_map(|p| match p {
+                Part::Positional(n) => Some(*n),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Whether the template consumes every remaining path segment with `{*}`.
+    fn has_rest(&self) -> bool {
+        self.parts.contains(&Part::Rest)
+    }
+
+    /// Fill the template from percent-encoded path `segments`, returning the destination and how many segments were used.
+    pub fn fill(&self, segments: &[&str]) -> Result<(String, usize), UrlIDError> {
+        let required = self.required();
+        if segments.len() < required {
+            return Err(UrlIDError::MissingParameter(format!(
+                "this link requires {} path parameter(s) after the token, but {} were given",
+                required,
+                segments.len()
+            )));
+        }
+        let decoded = segments
+            .iter()
+            .map(|s| {
+                rocket::http::RawStr::new(s)
+                    .percent_decode()
+                    .map(|d| utf8_percent_encode(&d, PARAMETER).to_string())
+                    .map_err(|e| UrlIDError::ParseFailure(e.to_string()))
+            })
+            .collect::<Result<Vec<String>, UrlIDError>>()?;
+        let mut out = String::new();
+        for part in self.parts.iter() {
+            match part {
+                Part::Literal(s) => out.push_str(s),
+                Part::Positional(n) => out.push_str(&decoded[n - 1]),
+                Part::Rest => out.push_str(&decoded[required..].join("/")),
+            }
+        }
+        let used = if self.has_rest() {
+            segments.len()
+        } else {
+            required
+        };
+        Ok((out, used))
+    }
+
+    /// Check the template produces a valid absolute url once filled.
+    pub fn validate(&self) -> Result<(), UrlIDError> {
+        let sample = vec!["x"; self.required() + 1];
+        let (filled, _) = self.fill(&sample)?;
+        url::Url::parse(&filled).map_err(|e| {
+            UrlIDError::ParseFailure(format!("destination is not a valid url once filled: {}", e))
+        })?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_template() {
+    // Braces other than placeholders are kept as they are, unless escaped by doubling them.
+    for dest in [
+        "https://a.example/",
+        "https://a.example/?q={term}",
+        "https://a.example/{0}",
+        "https://a.example/{1",
+        "https://a.example/1}",
+        r#"https://a.example/?json={"a":1}"#,
+    ] {
+        assert_eq!(Template::parse(dest), None, "{}", dest);
+    }
+    let t = Template::parse("https://a.example/{{1}}/{1}?q={{{*}}}").unwrap();
+    assert_eq!(
+        t.fill(&["x", "y"]).unwrap(),
+        ("https://a.example/{1}/x?q={y}".to_string(), 2)
+    );
+    let t = Template::parse("https://a.example/?json={{}}").unwrap();
+    assert_eq!(t.required(), 0);
+    assert_eq!(
+        t.fill(&[]).unwrap(),
+        ("https://a.example/?json={}".to_string(), 0)
+    );
+
+    let t = Template::parse("https://jira.example.com/browse/{1}?from={2}").unwrap();
+    assert_eq!(t.required(), 2);
+    assert_eq!(
+        t.fill(&["PROJ-123", "a%20b&c", "extra"]).unwrap(),
+        (
+            "https://jira.example.com/browse/PROJ-123?from=a%20b%26c".to_string(),
+            2
+        )
+    );
+    assert!(matches!(
+        t.fill(&["PROJ-123"]),
+        Err(UrlIDError::MissingParameter(_))
+    ));
+
+    let t = Template::parse("https://a.example/{1}/{*}").unwrap();
+    assert_eq!(
+        t.fill(&["x", "y", "z%2F"]).unwrap(),
+        ("https://a.example/x/y/z%2F".to_string(), 3)
+    );
+}
+
+#[test]
+fn test_templated_routes() {
+    use rocket::http::{ContentType, Status};
+    let (client, _dir) = crate::test_client();
+    let create = |body: &str| {
+        client
+            .post("/shorten")
+            .header(ContentType::JSON)
+            .body(body)
+            .dispatch()
+    };
+    let link = create(r#"{"url":"https://jira.example.com/browse/{1}"}"#)
+        .into_string()
+        .unwrap();
+    let token = link.rsplit('/').next().unwrap();
+
+    let res = client.get(format!("/{}/PROJ-123", token)).dispatch();
+    assert_eq!(
+        res.headers().get_one("Location"),
+        Some("https://jira.example.com/browse/PROJ-123")
+    );
+    assert_eq!(
+        client.get(format!("/{}", token)).dispatch().status(),
+        Status::BadRequest
+    );
+    assert_eq!(
+        client.get(format!("/{}/a/b", token)).dispatch().status(),
+        Status::NotFound
+    );
+    assert_eq!(create(r#"{"url":"{1}"}"#).status(), Status::BadRequest);
+}
@@ -0,0 +1,321 @@
+//! Export and import of every share in the database, for backups and migrations between instances.
+use crate::audit::Actor;
+use crate::database::{self, DatabaseError, SharesDbConn};
+use crate::url_id::{UrlID, UrlIDError};
+use serde::Serialize;
+
+/// The formats shares may be exported to or imported from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromFormField, clap::ValueEnum)]
+pub enum Format {
+    /// One JSON object per line.
+    Jsonl,
+    /// Comma separated values, with a header row.
+    Csv,
+}
+
+impl Format {
+    /// The content type a file of this format should be served with.
+    pub fn content_type(&self) -> rocket::http::ContentType {
+        match self {
+            Format::Jsonl => rocket::http::ContentType::new("application", "x-ndjson"),
+            Format::Csv => rocket::http::ContentType::CSV,
+        }
+    }
+}
+
+/// What to do when an imported share has the same id as one already in the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromFormField, clap::ValueEnum)]
+pub enum Conflict {
+    /// Keep the existing share, discarding the imported one.
+    Skip,
+    /// Replace the existing share with the imported one.
+    Overwrite,
+    /// Abort the whole import, leaving the database untouched.
+    Fail,
+}
+
+/// A summary of the changes made by an import.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ImportReport {
+    pub inserted: usize,
+    pub overwritten: usize,
+    pub skipped: usize,
+}
+
+/// The columns of a csv export, in order. Every field of a share must be listed, so that it survives a round trip.
+const CSV_COLUMNS: &[&str] = &[
+    "id",
+    "token",
+    "exp",
+    "crt",
+    "nbf",
+    "idle_ttl",
+    "last_access",
+    "url",
+    "owner",
+    "redirect_type",
+    "passthrough_query",
+    "passthrough_path",
+    "query_precedence",
+    "variants",
+    "sticky",
+    "rules",
+    "fallback_url",
+    "max_clicks",
+    "disabled",
+    "title",
+    "notes",
+    "tags",
+    "deleted_at",
+    "domain",
+    "workspace",
+];
+
+/// Flatten a share into the values of a csv row, one for each of [`CSV_COLUMNS`]. Csv has no notion of nested values,
+/// so any lists (such as the variants of a split share) are written as json, which the share accepts when parsed back.
+fn csv_row(share: &UrlID) -> Result<Vec<String>, UrlIDError> {
+    let value = serde_json::to_value(share).map_err(|e| UrlIDError::ServerError(e.to_string()))?;
+    let mut fields = match value {
+        serde_json::Value::Object(fields) => fields,
+        _ => return Err(UrlIDError::ServerError("share is not a json object".into())),
+    };
+    Ok(CSV_COLUMNS
+        .iter()
+        .map(|column| match fields.remove(*column) {
+            None | Some(serde_json::Value::Null) => String::new(),
+            Some(serde_json::Value::String(s)) => s,
+            Some(serde_json::Value::Array(a)) if a.is_empty() => String::new(),
+            Some(v) => v.to_string(),
+        })
+        .collect())
+}
+
+/// Serialize a list of shares into the given format.
+pub fn encode(shares: &[UrlID], format: Format) -> Result<String, UrlIDError> {
+    match format {
+        Format::Jsonl => {
+            let mut out = String::new();
+            for share in shares {
+                out.push_str(
+                    &serde_json::to_string(share)
+                        .map_err(|e| UrlIDError::ServerError(e.to_string()))?,
+                );
+                out.push('\n');
+            }
+            Ok(out)
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(vec![]);
+            writer
+                .write_record(CSV_COLUMNS)
+                .map_err(|e| UrlIDError::ServerError(e.to_string()))?;
+            for share in shares {
+                writer
+                    .write_record(csv_row(share)?)
+                    .map_err(|e| UrlIDError::ServerError(e.to_string()))?;
+            }
+            let bytes = writer
+                .into_inner()
+                .map_err(|e| UrlIDError::ServerError(e.to_string()))?;
+            String::from_utf8(bytes).map_err(|e| UrlIDError::ServerError(e.to_string()))
+        }
+    }
+}
+
+/// A share parsed from the line it starts on, or why it could not be.
+type Line = (u64, Result<UrlID, String>);
+
+/// Parse each share from the given format. Blank lines in jsonl input are ignored. Fails outright only if a csv header
+/// cannot be read.
+fn decode_lines(input: &str, format: Format) -> Result<Vec<Line>, UrlIDError> {
+    match format {
+        Format::Jsonl => Ok(input
+            .lines()
+            .zip(1..)
+            .filter(|(line, _)| !line.trim().is_empty())
+            .map(|(line, n)| (n, serde_json::from_str(line).map_err(|e| e.to_string())))
+            .collect()),
+        Format::Csv => {
+            let mut reader = csv::Reader::from_reader(input.as_bytes());
+            let headers = reader
+                .headers()
+                .map_err(|e| UrlIDError::ParseFailure(e.to_string()))?
+                .clone();
+            Ok(reader
+                .records()
+                .map(|record| match record {
+                    Ok(record) => (
+                        record.position().map_or(0, |p| p.line()),
+                        record
+                            .deserialize(Some(&headers))
+                            .map_err(|e| e.to_string()),
+                    ),
+                    Err(e) => (e.position().map_or(0, |p| p.line()), Err(e.to_string())),
+                })
+                .collect())
+        }
+    }
+}
+
+/// Collect parsed shares, or fail listing every line which could not be used.
+fn collect(lines: Vec<Line>) -> Result<Vec<UrlID>, UrlIDError> {
+    let mut shares = Vec::new();
+    let mut errors = Vec::new();
+    for (n, share) in lines {
+        match share {
+            Ok(share) => shares.push(share),
+            Err(e) => errors.push(format!("line {}: {}", n, e)),
+        }
+    }
+    match errors.is_empty() {
+        true => Ok(shares),
+        false => Err(UrlIDError::ParseFailure(errors.join("; "))),
+    }
+}
+
+/// Parse a list of shares from the given format, checking each is valid as if it had been created through the api.
+/// Fails naming every line which does not hold a valid share. Blank lines in jsonl input are ignored.
+pub fn decode(input: &str, format: Format) -> Result<Vec<UrlID>, UrlIDError> {
+    let lines = decode_lines(input, format)?
+        .into_iter()
+        .map(|(n, share)| {
+            let share = share.and_then(|s| s.validate().map(|_| s).map_err(String::from));
+            (n, share)
+        })
+        .collect();
+    collect(lines)
+}
+
+/// Export every share in the database.
+pub async fn export(conn: &SharesDbConn, format: Format) -> Result<String, DatabaseError> {
+    let shares = database::all_shares(conn).await?;
+    encode(&shares, format).map_err(DatabaseError::UrlIDError)
+}
+
+/// Import shares into the database, keeping their ids and tokens so previously issued links continue to resolve.
+/// The import happens within a single transaction, so a failure leaves the database unchanged. Input with any line
+/// which does not hold a valid share is refused, naming each such line.
+pub async fn import(
+    conn: &SharesDbConn,
+    input: &str,
+    format: Format,
+    conflict: Conflict,
+    actor: Actor,
+) -> Result<ImportReport, DatabaseError> {
+    let shares = decode(input, format).map_err(DatabaseError::UrlIDError)?;
+    conn.run_timed(move |c| -> Result<ImportReport, DatabaseError> {
+        let tx = c.transaction()?;
+        let mut report = ImportReport::default();
+        for share in shares.iter() {
+            let exists = database::share_exists(&tx, *share.get_id())?;
+            if !(exists && conflict == Conflict::Skip) {
+                let holder = database::token_holder(
+                    &tx,
+                    share.get_domain(),
+                    share.get_token(),
+                    *share.get_id(),
+                )?;
+                if let Some(holder) = holder {
+                    return Err(DatabaseError::InsertError(format!(
+                        "share {} has the token '{}', which share {} already holds on its domain",
+                        share.get_id(),
+                        share.generate_token(),
+                        holder
+                    )));
+                }
+            }
+            match (exists, conflict) {
+                (false, _) => {
+                    database::insert_share_with_id(&tx, share, false, &actor)?;
+                    report.inserted += 1;
+                }
+                (true, Conflict::Skip) => report.skipped += 1,
+                (true, Conflict::Overwrite) => {
+                    database::insert_share_with_id(&tx, share, true, &actor)?;
+                    report.overwritten += 1;
+                }
+                (true, Conflict::Fail) => {
+                    return Err(DatabaseError::AlreadyExists(*share.get_id()))
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(report)
+    })
+    .await
+}
+
+#[test]
+fn test_round_trip() {
+    let split: UrlID = serde_json::from_str(
+        r#"{"id":3,"exp":1,"crt":1,"url":"https://a.example","sticky":true,
+            "variants":[{"url":"https://a.example","weight":2},{"url":"https://b.example/?x=1,2","weight":1}]}"#,
+    )
+    .unwrap();
+    // Shares with and without a domain or workspace, which must still share one set of csv columns.
+    let scoped: UrlID = serde_json::from_str(
+        r#"{"id":4,"crt":1,"url":"https://c.example","domain":"go.example.com","workspace":2}"#,
+    )
+    .unwrap();
+    let shares = vec![
+        UrlID::new("https://example.com/a?b=c,d".into()).set_exp(Some(10)),
+        scoped,
+        UrlID::new("https://example.com/\"quoted\"".into()),
+        split,
+    ];
+    for format in [Format::Jsonl, Format::Csv] {
+        let encoded = encode(&shares, format).unwrap();
+        assert_eq!(decode(&encoded, format).unwrap(), shares);
+    }
+}
+
+#[test]
+fn test_csv_columns() {
+    // Every field of a share has a column, so none are dropped from csv exports.
+    let share: UrlID =
+        serde_json::from_str(r#"{"id":1,"crt":1,"url":"https://a.example"}"#).unwrap();
+    let fields = serde_json::to_value(&share).unwrap();
+    let mut fields: Vec<&str> = fields
+        .as_object()
+        .unwrap()
+        .keys()
+        .map(String::as_str)
+        .collect();
+    let mut columns = CSV_COLUMNS.to_vec();
+    fields.sort();
+    columns.sort();
+    assert_eq!(fields, columns);
+    assert_eq!(
+        encode(&[], Format::Csv).unwrap().trim_end(),
+        CSV_COLUMNS.join(",")
+    );
+}
+
+#[test]
+fn test_invalid_imports() {
+    let jsonl = r#"{"id":1,"crt":1,"url":"https://a.example"}
+
+{"id":2,"crt":1,"url":""}
+{"id":3,"crt":1,"url":"https://a.example","nbf":20,"exp":10}
+{"id":4,"#;
+    assert_eq!(
+        decode(jsonl, Format::Jsonl).unwrap_err().variant(),
+        "ParseFailure"
+    );
+    let error = String::from(decode(jsonl, Format::Jsonl).unwrap_err());
+    for line in ["line 3:", "line 4:", "line 5:"] {
+        assert!(error.contains(line), "{}", error);
+    }
+    assert!(!error.contains("line 1:"), "{}", error);
+
+    let csv = "id,crt,url\n1,1,https://a.example\n2,1,\n3,x,https://a.example\n";
+    let error = String::from(decode(csv, Format::Csv).unwrap_err());
+    assert!(
+        error.contains("line 3:") && error.contains("line 4:"),
+        "{}",
+        error
+    );
+    assert!(!error.contains("line 2:"), "{}", error);
+    let valid = "id,crt,url\n1,1,https://a.example\n";
+    assert_eq!(decode(valid, Format::Csv).unwrap().len(), 1);
+}
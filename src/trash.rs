@@ -0,0 +1,114 @@
+//! The trash deleted shares wait in, so a link removed by mistake may be restored. A background job permanently
+//! removes shares which have been in the trash for longer than the configured retention.
+use crate::audit::Actor;
+use crate::common::get_time_seconds;
+use crate::config::Config;
+use crate::database::{self, SharesDbConn};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::tokio::{self, time::Duration};
+use rocket::{Orbit, Rocket, Shutdown};
+
+/// How long deleted shares are kept if `trash_retention` is not configured, in seconds.
+const DEFAULT_RETENTION_SECONDS: i64 = 30 * 24 * 60 * 60;
+
+/// How often the trash is emptied of shares past their retention.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long deleted shares are kept in the trash, in seconds.
+pub fn retention(config: Option<&Config>) -> i64 {
+    config
+        .and_then(|c| c.trash_retention)
+        .map(|t| t.0)
+        .unwrap_or(DEFAULT_RETENTION_SECONDS)
+}
+
+/// Permanently remove every share deleted longer than `retention` seconds ago, returning how many were removed.
+pub async fn purge(conn: &SharesDbConn, retention: i64) -> Result<usize, database::DatabaseError> {
+    let cutoff = get_time_seconds().saturating_sub(retention);
+    database::purge_deleted(conn, cutoff, Actor::trash_purge()).await
+}
+
+/// Empty the trash of shares past their retention every [`PURGE_INTERVAL`] until shutdown.
+async fn run(conn: SharesDbConn, retention: i64, mut shutdown: Shutdown) {
+    loop {
+        match purge(&conn, retention).await {
+            Ok(0) => {}
+            Ok(removed) => tracing::info!(removed, "purged deleted shares from the trash"),
+            Err(e) => tracing::warn!(error = %e, "failed to purge deleted shares from the trash"),
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(PURGE_INTERVAL) => {}
+            _ = &mut shutdown => return,
+        }
+    }
+}
+
+/// Starts the job emptying the trash once the server has launched, stopping it on shutdown.
+pub struct TrashPurger;
+
+#[rocket::async_trait]
+impl Fairing for TrashPurger {
+    fn info(&self) -> Info {
+        Info {
+            name: "Trash Purger",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let conn = match SharesDbConn::get_one(rocket).await {
+            Some(conn) => conn,
+            None => {
+                tracing::error!(
+                    "failed to start purging the trash, no database connection available"
+                );
+                return;
+            }
+        };
+        let retention = retention(rocket.state::<Config>());
+        tokio::spawn(run(conn, retention, rocket.shutdown()));
+    }
+}
+
+#[rocket::async_test]
+async fn test_purge_trash() {
+    use rocket::http::{ContentType, Header, Status};
+    let (client, _dir) = crate::test_async_client_with(|f| f).await;
+    let admin = || Header::new("Authorization", "Bearer admin");
+    let link = client
+        .post("/shorten")
+        .header(ContentType::JSON)
+        .header(admin())
+        .body(r#"{"url":"https://a.example"}"#)
+        .dispatch()
+        .await
+        .into_string()
+        .await
+        .unwrap();
+    let token = link.rsplit('/').next().unwrap().to_owned();
+    let res = client
+        .delete(format!("/api/shares/{}", token))
+        .header(admin())
+        .dispatch()
+        .await;
+    assert_eq!(res.status(), Status::NoContent);
+
+    let conn = SharesDbConn::get_one(client.rocket()).await.unwrap();
+    assert_eq!(purge(&conn, 60).await.unwrap(), 0);
+    assert_eq!(purge(&conn, -1).await.unwrap(), 1);
+    let res = client
+        .post(format!("/api/shares/{}/restore", token))
+        .header(admin())
+        .dispatch()
+        .await;
+    assert_eq!(res.status(), Status::NotFound);
+    let purged: serde_json::Value = client
+        .get(format!("/admin/audit?token={}&action=purge", token))
+        .header(admin())
+        .dispatch()
+        .await
+        .into_json()
+        .await
+        .unwrap();
+    assert_eq!(purged[0]["actor"], "trash-purge");
+}
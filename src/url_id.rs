@@ -1,5 +1,15 @@
 //! The url id share, representing a valid shortened url object, and all information related to it.
 use crate::common::*;
+use crate::config::Config;
+use crate::expiry;
+use crate::fallback;
+use crate::metrics;
+use crate::passthrough::{self, QueryPrecedence};
+use crate::redirect::RedirectType;
+use crate::rules::Rules;
+use crate::split::Variants;
+use crate::tags::Tags;
+use crate::template::Template;
 use rand::Rng;
 use rocket::data::{self, Data, FromData, ToByteUnit};
 use rocket::http::{ContentType, Status};
@@ -7,7 +17,7 @@ use rocket::outcome::Outcome::*;
 use rocket_sync_db_pools::rusqlite;
 use serde::{Deserialize, Serialize};
 
-//// Helper Functions (mostly for url generation) ////
+///// Helper Functions (mostly for url generation) /////
 
 /// A 62-char alphabet, which is used for our base conversion into the shortened url.
 pub const ALPHABET: &[char] = &[
@@ -26,6 +36,9 @@ pub const DELIM_CHAR: char = 'g';
 /// The base being used for conversion
 const BASE: usize = 61;
 
+/// The longest id portion of a token we will attempt to convert, any longer may overflow an i64.
+const MAX_ID_LENGTH_CHARS: usize = 10;
+
 /// Convert a base 10 number to a base 61 number
 fn base_10_to_61(mut id: i64, alphabet: &[char]) -> String {
     //Converting from base 10 (id), to base 61.
@@ -50,6 +63,52 @@ fn get_char_position(letter: char, alphabet: &[char]) -> i64 {
     panic!("Finding char position failed!");
 }
 
+/// Convert a token, as found in a shortened link, back into the number it encodes, which identifies a share among those
+/// served on the same domain. Returns None if the token contains characters outside of the alphabet, or is too long to
+/// be a valid number.
+pub fn token_to_number(token: &str) -> Option<i64> {
+    let converted_id = token.split(DELIM_CHAR).next()?;
+    if converted_id.is_empty()
+        || converted_id.len() > MAX_ID_LENGTH_CHARS
+        || !converted_id.chars().all(|c| ALPHABET.contains(&c))
+    {
+        return None;
+    }
+    Some(base_61_to_10(converted_id.to_owned(), ALPHABET))
+}
+
+/// Convert the number identifying a share on its domain into the token found in its shortened link, the inverse of
+/// [`token_to_number`].
+pub fn number_to_token(number: i64) -> String {
+    normalize_length(
+        base_10_to_61(number, ALPHABET),
+        TOKEN_MIN_LENGTH_CHARS,
+        ALPHABET,
+        DELIM_CHAR,
+    )
+}
+
+/// A token taken from the path of a request, only matched if it is well formed: as long as every issued token, and
+/// with a number portion in the alphabet. Anything else, such as `/api/typo` or `/favicon.ico`, is forwarded without
+/// reaching the database.
+pub struct Token<'a> {
+    pub token: &'a str,
+    pub number: i64,
+}
+
+impl<'a> rocket::request::FromParam<'a> for Token<'a> {
+    type Error = &'a str;
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        match token_to_number(param) {
+            Some(number) if param.len() >= TOKEN_MIN_LENGTH_CHARS => Ok(Token {
+                token: param,
+                number,
+            }),
+            _ => Err(param),
+        }
+    }
+}
+
 /// Convert a base 61 number to a base 10 number
 pub fn base_61_to_10(token: String, alpha: &[char]) -> i64 {
     let mut result = 0;
@@ -104,6 +163,23 @@ pub enum UrlIDError {
     IdError,
     NoToken,
     DatabaseError(String),
+    MissingParameter(String),
+}
+
+impl UrlIDError {
+    /// The name of this variant, as used to label metrics.
+    pub fn variant(&self) -> &'static str {
+        match self {
+            UrlIDError::ContentType => "ContentType",
+            UrlIDError::TooLarge => "TooLarge",
+            UrlIDError::ServerError(_) => "ServerError",
+            UrlIDError::ParseFailure(_) => "ParseFailure",
+            UrlIDError::IdError => "IdError",
+            UrlIDError::NoToken => "NoToken",
+            UrlIDError::DatabaseError(_) => "DatabaseError",
+            UrlIDError::MissingParameter(_) => "MissingParameter",
+        }
+    }
 }
 
 impl From<UrlIDError> for String {
@@ -116,6 +192,7 @@ impl From<UrlIDError> for String {
             UrlIDError::IdError => "attempted to access id, but was none value".into(),
             UrlIDError::NoToken => "attempted to access an inaccessible token".into(),
             UrlIDError::DatabaseError(e) => e,
+            UrlIDError::MissingParameter(e) => e,
         }
     }
 }
@@ -130,6 +207,7 @@ impl std::error::Error for UrlIDError {
             UrlIDError::IdError => "attempted to access id, but was none value",
             UrlIDError::NoToken => "attempted to access an inaccessible token",
             UrlIDError::DatabaseError(e) => e,
+            UrlIDError::MissingParameter(e) => e,
         }
     }
 }
@@ -144,8 +222,73 @@ impl std::fmt::Display for UrlIDError {
             UrlIDError::IdError => f.write_str("attempted to access id, but was none value"),
             UrlIDError::NoToken => f.write_str("attempted to access an inaccessible token"),
             UrlIDError::DatabaseError(e) => f.write_str(e),
+            UrlIDError::MissingParameter(e) => f.write_str(e),
+        }
+    }
+}
+
+/// Check a destination is usable, filling any placeholders and ensuring paths and queries may be passed on to it if required.
+fn validate_destination(dest: &str, passthrough: bool) -> Result<(), UrlIDError> {
+    match Template::parse(dest) {
+        Some(template) => template.validate(),
+        None if passthrough => passthrough::validate_destination(dest).map(|_| ()),
+        None => Ok(()),
+    }
+}
+
+/// Check the settings of a share are consistent, and that every destination it may redirect to is usable.
+#[allow(clippy::too_many_arguments)]
+fn validate_share(
+    url: &str,
+    exp: Option<i64>,
+    nbf: Option<i64>,
+    idle_ttl: Option<i64>,
+    variants: &Variants,
+    rules: &Rules,
+    passthrough: bool,
+) -> Result<(), UrlIDError> {
+    if url.is_empty() {
+        return Err(UrlIDError::ParseFailure(
+            "a share requires either a url or variants".into(),
+        ));
+    }
+    if let (Some(nbf), Some(exp)) = (nbf, exp) {
+        if nbf > exp {
+            return Err(UrlIDError::ParseFailure(format!(
+                "nbf ({}) must not be after exp ({})",
+                nbf, exp
+            )));
         }
     }
+    if idle_ttl.is_some_and(|t| t <= 0) {
+        return Err(UrlIDError::ParseFailure(
+            "idle_ttl must be greater than 0".into(),
+        ));
+    }
+    variants.validate()?;
+    validate_rules(rules, passthrough)?;
+    let destinations = std::iter::once(url).chain(variants.iter().map(|v| v.url.as_str()));
+    for dest in destinations {
+        validate_destination(dest, passthrough)?;
+    }
+    Ok(())
+}
+
+/// Check routing rules can each match, and that their destinations are usable.
+pub fn validate_rules(rules: &Rules, passthrough: bool) -> Result<(), UrlIDError> {
+    rules.validate()?;
+    rules
+        .iter()
+        .try_for_each(|r| validate_destination(&r.url, passthrough))
+}
+
+/// The lowercase host of a destination, used to filter shares by the domain they redirect to.
+/// Returns None for destinations whose host is a placeholder, or which otherwise fail to parse.
+pub fn destination_host(url: &str) -> Option<String> {
+    url::Url::parse(url)
+        .ok()?
+        .host_str()
+        .map(|h| h.to_ascii_lowercase())
 }
 
 /////  Data Structs  /////
@@ -153,20 +296,280 @@ impl std::fmt::Display for UrlIDError {
 /// A UrlId before it has been committed to the database.
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct UncommittedUrlID {
+    #[serde(default)]
     url: String,
+    #[serde(default, deserialize_with = "expiry::deserialize_time")]
     exp: Option<i64>,
     crt: Option<i64>,
+    #[serde(default, deserialize_with = "expiry::deserialize_time")]
+    nbf: Option<i64>,
+    #[serde(default, deserialize_with = "expiry::deserialize_duration")]
+    idle_ttl: Option<i64>,
+    #[serde(skip)]
+    owner: Option<String>,
+    #[serde(default)]
+    redirect_type: Option<RedirectType>,
+    #[serde(default)]
+    passthrough_query: bool,
+    #[serde(default)]
+    passthrough_path: bool,
+    #[serde(default)]
+    query_precedence: QueryPrecedence,
+    #[serde(default)]
+    variants: Variants,
+    #[serde(default)]
+    sticky: bool,
+    #[serde(default)]
+    rules: Rules,
+    #[serde(default)]
+    fallback_url: Option<String>,
+    #[serde(default)]
+    max_clicks: Option<i64>,
+    #[serde(default)]
+    disabled: bool,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    notes: Option<String>,
+    #[serde(default)]
+    tags: Tags,
+    /// The host the link is served on, if not the server's own domain.
+    #[serde(default)]
+    domain: Option<String>,
+    /// The workspace the link belongs to, if any.
+    #[serde(default)]
+    workspace: Option<i64>,
 }
 
 impl UncommittedUrlID {
+    /// Creates a new share for the given url, created now and expiring at `exp` (or never).
+    /// The server's ttl policy is not applied until [`UncommittedUrlID::apply_ttl`] is called.
+    pub fn new(url: String, exp: Option<i64>) -> Self {
+        UncommittedUrlID {
+            url,
+            exp,
+            crt: Some(get_time_seconds()),
+            nbf: None,
+            idle_ttl: None,
+            owner: None,
+            redirect_type: None,
+            passthrough_query: false,
+            passthrough_path: false,
+            query_precedence: QueryPrecedence::default(),
+            variants: Variants::default(),
+            sticky: false,
+            rules: Rules::default(),
+            fallback_url: None,
+            max_clicks: None,
+            disabled: false,
+            title: None,
+            notes: None,
+            tags: Tags::default(),
+            domain: None,
+            workspace: None,
+        }
+    }
+
+    /// Get the workspace this share is to be created in, if any.
+    pub fn get_workspace(&self) -> Option<i64> {
+        self.workspace
+    }
+
+    /// Set the domain this share is served on, None being the server's own, can be chained.
+    pub fn set_domain(mut self, domain: Option<String>) -> Self {
+        self.domain = domain;
+        self
+    }
+
+    /// Get the domain this share is served on, if it is not the server's own.
+    pub fn get_domain(&self) -> Option<&str> {
+        self.domain.as_deref()
+    }
+
+    /// Set the title, notes and tags used to describe and find this share, can be chained.
+    pub fn set_description(
+        mut self,
+        title: Option<String>,
+        notes: Option<String>,
+        tags: Tags,
+    ) -> Self {
+        self.title = title;
+        self.notes = notes;
+        self.tags = tags;
+        self
+    }
+
+    /// Get the title of this share, if it has one.
+    pub fn get_title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    /// Get the notes kept about this share, if any.
+    pub fn get_notes(&self) -> Option<&str> {
+        self.notes.as_deref()
+    }
+
+    /// Get the tags of this share.
+    pub fn get_tags(&self) -> &Tags {
+        &self.tags
+    }
+
+    /// Get the routing rules of this share, evaluated in order before falling back to its url or variants.
+    pub fn get_rules(&self) -> &Rules {
+        &self.rules
+    }
+
+    /// Set where visitors are sent once this share is unavailable, and how many clicks it allows, can be chained.
+    pub fn set_fallback(mut self, fallback_url: Option<String>, max_clicks: Option<i64>) -> Self {
+        self.fallback_url = fallback_url;
+        self.max_clicks = max_clicks;
+        self
+    }
+
+    /// Get where visitors are sent once this share is unavailable, if it overrides the server default.
+    pub fn get_fallback_url(&self) -> Option<&str> {
+        self.fallback_url.as_deref()
+    }
+
+    /// Get how many times this share may be followed before it is exhausted, if it is limited.
+    pub fn get_max_clicks(&self) -> Option<i64> {
+        self.max_clicks
+    }
+
+    /// Get whether this share has been disabled.
+    pub fn get_disabled(&self) -> bool {
+        self.disabled
+    }
+
+    /// Set the time before which this share will not resolve, can be chained.
+    pub fn set_nbf(mut self, nbf: Option<i64>) -> Self {
+        self.nbf = nbf;
+        self
+    }
+
+    /// Get the time before which this share will not resolve, if it has one.
+    pub fn get_nbf(&self) -> Option<i64> {
+        self.nbf
+    }
+
+    /// Set how long this share may go unused before it expires, can be chained.
+    pub fn set_idle_ttl(mut self, idle_ttl: Option<i64>) -> Self {
+        self.idle_ttl = idle_ttl;
+        self
+    }
+
+    /// Get how long this share may go unused before it expires, if it slides.
+    pub fn get_idle_ttl(&self) -> Option<i64> {
+        self.idle_ttl
+    }
+
+    /// Set the weighted destinations of this share, and whether visitors keep the variant they were first sent to, can be chained.
+    /// If the share has no url the first variant becomes its url.
+    pub fn set_variants(mut self, variants: Variants, sticky: bool) -> Self {
+        self.variants = variants;
+        self.sticky = sticky;
+        self.url_from_first_variant();
+        self
+    }
+
+    /// Use the first variant as the url of a share created without one.
+    fn url_from_first_variant(&mut self) {
+        if self.url.is_empty() {
+            if let Some(first) = self.variants.get(0) {
+                self.url = first.url.clone();
+            }
+        }
+    }
+
+    /// Get the weighted destinations of this share, empty if it has a single destination.
+    pub fn get_variants(&self) -> &Variants {
+        &self.variants
+    }
+
+    /// Get whether visitors keep the variant they were first sent to.
+    pub fn get_sticky(&self) -> bool {
+        self.sticky
+    }
+
+    /// Set whether the query string and trailing path segments of requests are passed on to the destination, can be chained.
+    pub fn set_passthrough(
+        mut self,
+        query: bool,
+        path: bool,
+        query_precedence: QueryPrecedence,
+    ) -> Self {
+        self.passthrough_query = query;
+        self.passthrough_path = path;
+        self.query_precedence = query_precedence;
+        self
+    }
+
+    /// Get whether the query string of requests is merged into the destination.
+    pub fn get_passthrough_query(&self) -> bool {
+        self.passthrough_query
+    }
+
+    /// Get whether trailing path segments of requests are appended to the destination.
+    pub fn get_passthrough_path(&self) -> bool {
+        self.passthrough_path
+    }
+
+    /// Get which query parameters win when both the request and destination contain them.
+    pub fn get_query_precedence(&self) -> QueryPrecedence {
+        self.query_precedence
+    }
+
+    /// Check this share is valid before it is committed.
+    pub fn validate(&self) -> Result<(), UrlIDError> {
+        validate_share(
+            &self.url,
+            self.exp,
+            self.nbf,
+            self.idle_ttl,
+            &self.variants,
+            &self.rules,
+            self.passthrough_query || self.passthrough_path,
+        )?;
+        fallback::validate(self.fallback_url.as_deref(), self.max_clicks)?;
+        self.tags.validate()
+    }
+
+    /// Apply the server's default and maximum ttl to the expiry of this share.
+    pub fn apply_ttl(&mut self, config: &Config) -> Result<(), UrlIDError> {
+        self.exp = expiry::apply_ttl(self.exp, get_time_seconds(), config)?;
+        Ok(())
+    }
+
+    /// Set the redirect type of this share, can be chained.
+    pub fn set_redirect_type(mut self, redirect_type: Option<RedirectType>) -> Self {
+        self.redirect_type = redirect_type;
+        self
+    }
+
+    ///Get the redirect type of this shortened link, if one was chosen.
+    pub fn get_redirect_type(&self) -> Option<RedirectType> {
+        self.redirect_type
+    }
+
+    /// Set the owner of this share, can be chained.
+    pub fn set_owner(mut self, owner: Option<String>) -> Self {
+        self.owner = owner;
+        self
+    }
+
+    ///Get the owner of this shortened link, if it has one.
+    pub fn get_owner(&self) -> Option<&str> {
+        self.owner.as_deref()
+    }
+
     ///Get the destination url of this shortened link.
     pub fn get_dest_url(&self) -> &str {
         &self.url
     }
 
-    ///Get the time that this shortened link will expire.
-    pub fn get_exp(&self) -> i64 {
-        self.exp.unwrap()
+    ///Get the time that this shortened link will expire, if it ever will.
+    pub fn get_exp(&self) -> Option<i64> {
+        self.exp
     }
 
     ///Get the time this shortened link was created.
@@ -176,25 +579,111 @@ impl UncommittedUrlID {
 }
 
 /// This struct represents a valid url ID
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct UrlID {
     ///ID
     id: i64,
-    /// When this url expires
-    exp: i64,
+    /// The number encoded in this url's token, counted separately on each domain. Missing from shares exported before
+    /// domains had token spaces of their own, whose tokens encoded their id.
+    #[serde(default)]
+    token: i64,
+    /// When this url expires, or None if it never does
+    #[serde(default, deserialize_with = "expiry::deserialize_stored_exp")]
+    exp: Option<i64>,
     /// When this url was created
     crt: i64,
+    /// When this url begins resolving, if it is scheduled to go live in the future
+    #[serde(default)]
+    nbf: Option<i64>,
+    /// How long this url may go unused before it expires, if its expiry slides with each click
+    #[serde(default)]
+    idle_ttl: Option<i64>,
+    /// When this url was last followed, if it has an idle ttl and has been followed
+    #[serde(default)]
+    last_access: Option<i64>,
     /// Url this redirects to
     url: String,
+    /// The owner of the api key used to create this url, if any
+    #[serde(default)]
+    owner: Option<String>,
+    /// The status code used when redirecting, if unset the server default is used
+    #[serde(default)]
+    redirect_type: Option<RedirectType>,
+    /// Whether the query string of requests is merged into the destination
+    #[serde(default)]
+    passthrough_query: bool,
+    /// Whether trailing path segments of requests are appended to the destination
+    #[serde(default)]
+    passthrough_path: bool,
+    /// Which query parameters win when both the request and destination contain them
+    #[serde(default)]
+    query_precedence: QueryPrecedence,
+    /// Weighted destinations chosen between on each click, empty if the url is the only destination
+    #[serde(default)]
+    variants: Variants,
+    /// Whether visitors keep the variant they were first sent to
+    #[serde(default)]
+    sticky: bool,
+    /// Routing rules evaluated in order, the first to match the visitor chooses the destination
+    #[serde(default)]
+    rules: Rules,
+    /// Where visitors are sent once this url is unavailable, if it overrides the server default
+    #[serde(default)]
+    fallback_url: Option<String>,
+    /// How many times this url may be followed before it is exhausted, if it is limited
+    #[serde(default)]
+    max_clicks: Option<i64>,
+    /// Whether this url has been disabled by its owner
+    #[serde(default)]
+    disabled: bool,
+    /// A short description of this url
+    #[serde(default)]
+    title: Option<String>,
+    /// Free text notes kept about this url
+    #[serde(default)]
+    notes: Option<String>,
+    /// Tags used to group and filter urls
+    #[serde(default)]
+    tags: Tags,
+    /// When this url was moved to the trash, if it has been deleted
+    #[serde(default)]
+    deleted_at: Option<i64>,
+    /// The host this url is served on, if not the server's own domain
+    #[serde(default)]
+    domain: Option<String>,
+    /// The workspace this url belongs to, if it is shared with a team rather than kept by its owner
+    #[serde(default)]
+    workspace: Option<i64>,
 }
 
 impl Default for UrlID {
     fn default() -> Self {
         UrlID {
-            id: std::i64::MAX,
-            exp: std::i64::MAX,
+            id: i64::MAX,
+            token: 0,
+            exp: None,
             crt: get_time_seconds(),
+            nbf: None,
+            idle_ttl: None,
+            last_access: None,
             url: String::default(),
+            owner: None,
+            redirect_type: None,
+            passthrough_query: false,
+            passthrough_path: false,
+            query_precedence: QueryPrecedence::default(),
+            variants: Variants::default(),
+            sticky: false,
+            rules: Rules::default(),
+            fallback_url: None,
+            max_clicks: None,
+            disabled: false,
+            title: None,
+            notes: None,
+            tags: Tags::default(),
+            deleted_at: None,
+            domain: None,
+            workspace: None,
         }
     }
 }
@@ -209,23 +698,86 @@ impl UrlID {
         }
     }
 
-    /// Set the expiry, can be chained.
-    #[allow(dead_code)]
-    pub fn set_exp(mut self, exp: &i64) -> Self {
-        self.exp = exp.to_owned();
+    /// Set the expiry, None meaning the share never expires, can be chained.
+    pub fn set_exp(mut self, exp: Option<i64>) -> Self {
+        self.exp = exp;
         self
     }
 
+    /// Set the destination url, can be chained.
+    pub fn set_url(mut self, url: String) -> Self {
+        self.url = url;
+        self
+    }
+
+    /// Set the time before which this share will not resolve, can be chained.
+    pub fn set_nbf(mut self, nbf: Option<i64>) -> Self {
+        self.nbf = nbf;
+        self
+    }
+
+    /// Check the share is valid after it has been changed.
+    pub fn validate(&self) -> Result<(), UrlIDError> {
+        validate_share(
+            &self.url,
+            self.exp,
+            self.nbf,
+            self.idle_ttl,
+            &self.variants,
+            &self.rules,
+            self.passthrough_query || self.passthrough_path,
+        )?;
+        fallback::validate(self.fallback_url.as_deref(), self.max_clicks)?;
+        self.tags.validate()
+    }
+
     #[allow(dead_code)]
     /// Get the destination url of this shortened link.
     pub fn get_dest_url(&self) -> &str {
         &self.url
     }
 
-    #[allow(dead_code)]
-    /// Get the time that this shortened link will expire.
-    pub fn get_exp(&self) -> &i64 {
-        &self.exp
+    /// Get the time that this shortened link will expire, if it ever will.
+    pub fn get_exp(&self) -> Option<i64> {
+        self.exp
+    }
+
+    /// Get how long this shortened link may go unused before it expires, if it slides.
+    pub fn get_idle_ttl(&self) -> Option<i64> {
+        self.idle_ttl
+    }
+
+    /// Set how long this share may go unused before it expires, can be chained.
+    pub fn set_idle_ttl(mut self, idle_ttl: Option<i64>) -> Self {
+        self.idle_ttl = idle_ttl;
+        self
+    }
+
+    /// Get when this shortened link was last followed, if that is being tracked.
+    pub fn get_last_access(&self) -> Option<i64> {
+        self.last_access
+    }
+
+    /// Record that this share was followed at the given time, can be chained.
+    pub fn touch(mut self, at: i64) -> Self {
+        self.last_access = Some(self.last_access.map_or(at, |last| last.max(at)));
+        self
+    }
+
+    /// The time this shortened link expires, taking into account how long it may go unused, or None if it never will.
+    pub fn effective_exp(&self) -> Option<i64> {
+        let idle_exp = self
+            .idle_ttl
+            .map(|ttl| self.last_access.unwrap_or(self.crt).saturating_add(ttl));
+        match (self.exp, idle_exp) {
+            (Some(exp), Some(idle)) => Some(exp.min(idle)),
+            (exp, idle) => exp.or(idle),
+        }
+    }
+
+    /// Whether this shortened link had expired by the given time.
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.effective_exp().is_some_and(|exp| exp < now)
     }
 
     #[allow(dead_code)]
@@ -234,30 +786,241 @@ impl UrlID {
         &self.crt
     }
 
+    /// Get the time before which this shortened link will not resolve, if it has one.
+    pub fn get_nbf(&self) -> Option<i64> {
+        self.nbf
+    }
+
+    /// Whether this shortened link is scheduled to go live after the given time.
+    pub fn is_before_nbf(&self, now: i64) -> bool {
+        self.nbf.is_some_and(|nbf| now < nbf)
+    }
+
+    /// Get the owner of this shortened link, if it has one.
+    pub fn get_owner(&self) -> Option<&str> {
+        self.owner.as_deref()
+    }
+
+    /// Get the status code this link redirects with, if one was chosen when it was created.
+    pub fn get_redirect_type(&self) -> Option<RedirectType> {
+        self.redirect_type
+    }
+
+    /// Whether every visit to this share is sent to the same destination for as long as it exists, so a permanent
+    /// redirect to it may be cached. Split and routed shares choose a destination per visitor, and shares which expire
+    /// or count clicks must be reached on every visit.
+    pub fn is_cacheable(&self) -> bool {
+        self.variants.is_empty()
+            && self.rules.is_empty()
+            && self.max_clicks.is_none()
+            && self.exp.is_none()
+            && self.idle_ttl.is_none()
+    }
+
+    /// Get whether the query string of requests is merged into the destination.
+    pub fn get_passthrough_query(&self) -> bool {
+        self.passthrough_query
+    }
+
+    /// Get whether trailing path segments of requests are appended to the destination.
+    pub fn get_passthrough_path(&self) -> bool {
+        self.passthrough_path
+    }
+
+    /// Get which query parameters win when both the request and destination contain them.
+    pub fn get_query_precedence(&self) -> QueryPrecedence {
+        self.query_precedence
+    }
+
+    /// Get the weighted destinations of this share, empty if it has a single destination.
+    pub fn get_variants(&self) -> &Variants {
+        &self.variants
+    }
+
+    /// Get whether visitors keep the variant they were first sent to.
+    pub fn get_sticky(&self) -> bool {
+        self.sticky
+    }
+
+    /// Get the routing rules of this share, evaluated in order before falling back to its url or variants.
+    pub fn get_rules(&self) -> &Rules {
+        &self.rules
+    }
+
+    /// Replace the routing rules of this share, can be chained.
+    pub fn set_rules(mut self, rules: Rules) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    /// Get where visitors are sent once this share is unavailable, if it overrides the server default.
+    pub fn get_fallback_url(&self) -> Option<&str> {
+        self.fallback_url.as_deref()
+    }
+
+    /// Set where visitors are sent once this share is unavailable, can be chained.
+    pub fn set_fallback_url(mut self, fallback_url: Option<String>) -> Self {
+        self.fallback_url = fallback_url;
+        self
+    }
+
+    /// Get how many times this share may be followed before it is exhausted, if it is limited.
+    pub fn get_max_clicks(&self) -> Option<i64> {
+        self.max_clicks
+    }
+
+    /// Set how many times this share may be followed before it is exhausted, can be chained.
+    pub fn set_max_clicks(mut self, max_clicks: Option<i64>) -> Self {
+        self.max_clicks = max_clicks;
+        self
+    }
+
+    /// Get whether this share has been disabled.
+    pub fn get_disabled(&self) -> bool {
+        self.disabled
+    }
+
+    /// Disable or re-enable this share, can be chained.
+    pub fn set_disabled(mut self, disabled: bool) -> Self {
+        self.disabled = disabled;
+        self
+    }
+
+    /// Get the title of this share, if it has one.
+    pub fn get_title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    /// Set the title of this share, can be chained.
+    pub fn set_title(mut self, title: Option<String>) -> Self {
+        self.title = title;
+        self
+    }
+
+    /// Get the notes kept about this share, if any.
+    pub fn get_notes(&self) -> Option<&str> {
+        self.notes.as_deref()
+    }
+
+    /// Set the notes kept about this share, can be chained.
+    pub fn set_notes(mut self, notes: Option<String>) -> Self {
+        self.notes = notes;
+        self
+    }
+
+    /// Get the tags of this share.
+    pub fn get_tags(&self) -> &Tags {
+        &self.tags
+    }
+
+    /// Replace the tags of this share, can be chained.
+    pub fn set_tags(mut self, tags: Tags) -> Self {
+        self.tags = tags;
+        self
+    }
+
+    /// Take the destination and settings of `version`, an earlier version of this share, keeping this share's identity,
+    /// token, owner, domain, workspace, access time and whether it is in the trash.
+    pub fn with_settings_of(self, version: UrlID) -> Self {
+        UrlID {
+            id: self.id,
+            token: self.token,
+            crt: self.crt,
+            owner: self.owner,
+            last_access: self.last_access,
+            deleted_at: self.deleted_at,
+            domain: self.domain,
+            workspace: self.workspace,
+            ..version
+        }
+    }
+
+    /// Get when this share was moved to the trash, or None if it has not been deleted.
+    pub fn get_deleted_at(&self) -> Option<i64> {
+        self.deleted_at
+    }
+
+    /// Whether this share has been deleted, and is waiting in the trash to be restored or purged.
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
+    /// Get the domain this share is served on, or None if it is served on the server's own domain.
+    pub fn get_domain(&self) -> Option<&str> {
+        self.domain.as_deref()
+    }
+
+    /// Get the workspace this share belongs to, or None if it is kept by its owner.
+    pub fn get_workspace(&self) -> Option<i64> {
+        self.workspace
+    }
+
+    /// Get the destination of the given variant, or the url of this share if there is no such variant.
+    pub fn get_destination(&self, variant: Option<usize>) -> &str {
+        variant
+            .and_then(|i| self.variants.get(i))
+            .map(|v| v.url.as_str())
+            .unwrap_or(&self.url)
+    }
+
+    /// Build the url a request should be redirected to, given the destination chosen for it (by a rule or variant)
+    /// and the trailing path `segments` and raw `query` of the request.
+    /// Segments fill any placeholders in the destination first, the remainder are passed through if the link allows it.
+    /// Returns None if the request carries path segments this link does not accept.
+    pub fn resolve_destination(
+        &self,
+        dest: &str,
+        segments: &[&str],
+        query: Option<&str>,
+    ) -> Result<Option<String>, UrlIDError> {
+        let (dest, segments) = match Template::parse(dest) {
+            Some(template) => {
+                let (dest, used) = template.fill(segments)?;
+                (dest, &segments[used..])
+            }
+            None => (dest.to_owned(), segments),
+        };
+        if !segments.is_empty() && !self.passthrough_path {
+            return Ok(None);
+        }
+        let query = query.filter(|_| self.passthrough_query);
+        passthrough::apply(&dest, segments, query, self.query_precedence).map(Some)
+    }
+
     /// Generates the unique identifier representing this shortened url. Can be chained with other requests, though this borrows mutably unlike others.
     pub fn generate_token(&self) -> String {
-        let token = base_10_to_61(self.id, ALPHABET);
-        normalize_length(token, TOKEN_MIN_LENGTH_CHARS, ALPHABET, DELIM_CHAR)
+        number_to_token(self.get_token())
     }
 
-    /// Get the shortened link associated with this URL. May create an error if the token has not been generated yet.
+    /// Get the shortened link associated with this URL, on the domain it is served from.
     pub fn get_shortened_link(&self) -> String {
-        format!("http://{}/{}", crate::SERVER_DOMAIN, self.generate_token())
+        let domain = self.domain.as_deref().unwrap_or(crate::SERVER_DOMAIN);
+        format!("http://{}/{}", domain, self.generate_token())
     }
 
     /// Get the ID associated with this shortened url.
     pub fn get_id(&self) -> &i64 {
         &self.id
     }
+
+    /// Get the number encoded in this url's token, which is unique among the urls served on its domain.
+    pub fn get_token(&self) -> i64 {
+        match self.token {
+            0 => self.id,
+            token => token,
+        }
+    }
 }
 
 impl std::convert::From<UrlIDError> for (rocket::http::Status, std::string::String) {
     fn from(err: UrlIDError) -> (rocket::http::Status, std::string::String) {
         // (rocket::http::Status::new(500), err.to_string())
+        metrics::record_error("UrlIDError", err.variant());
         match err {
             UrlIDError::ServerError(e) => (rocket::http::Status::new(500), e),
             UrlIDError::DatabaseError(e) => (rocket::http::Status::new(500), e),
-            UrlIDError::ParseFailure(e) => (rocket::http::Status::new(500), e),
+            UrlIDError::ParseFailure(e) => (rocket::http::Status::new(400), e),
+            UrlIDError::MissingParameter(e) => (rocket::http::Status::new(400), e),
             _ => (rocket::http::Status::new(400), "Bad Request".into()),
         }
     }
@@ -268,14 +1031,46 @@ impl crate::database::FromDatabase for UrlID {
     fn from_database(row: &rusqlite::Row<'_>) -> Result<UrlID, rusqlite::Error> {
         //SAFETY: These should be safe, as the types with unwraps are disallowed from being null in the schema of the db.
         Ok(UrlID {
-            id: row.get(0).unwrap(),
-            exp: row.get(1).unwrap(),
-            crt: row.get(2).unwrap(),
-            url: row.get(3).unwrap(),
+            id: row.get("id").unwrap(),
+            token: row.get("token")?,
+            exp: row.get("exp").unwrap(),
+            crt: row.get("crt").unwrap(),
+            nbf: row.get("nbf")?,
+            idle_ttl: row.get("idle_ttl")?,
+            last_access: row.get("last_access")?,
+            url: row.get("url").unwrap(),
+            owner: row.get("owner")?,
+            redirect_type: row.get("redirect_type")?,
+            passthrough_query: row.get("passthrough_query")?,
+            passthrough_path: row.get("passthrough_path")?,
+            query_precedence: row.get("query_precedence")?,
+            variants: row.get("variants")?,
+            sticky: row.get("sticky")?,
+            rules: row.get("rules")?,
+            fallback_url: row.get("fallback_url")?,
+            max_clicks: row.get("max_clicks")?,
+            disabled: row.get("disabled")?,
+            title: row.get("title")?,
+            notes: row.get("notes")?,
+            tags: row.get("tags")?,
+            deleted_at: row.get("deleted_at")?,
+            domain: row.get("domain")?,
+            workspace: row.get("workspace_id")?,
         })
     }
 }
 
+/// Fail parsing a share from request data, recording the error in the metrics.
+fn fail<'r>(
+    req: &'r rocket::request::Request<'_>,
+    status: Status,
+    err: UrlIDError,
+) -> data::Outcome<'r, UncommittedUrlID> {
+    metrics::record_error("UrlIDError", err.variant());
+    crate::logging::RequestContext::of(req).set_error(err.variant());
+    Failure((status, err))
+}
+
 #[rocket::async_trait]
 impl<'r> FromData<'r> for UncommittedUrlID {
     type Error = UrlIDError;
@@ -286,19 +1081,20 @@ impl<'r> FromData<'r> for UncommittedUrlID {
         // Ensure correct content type
         let share_ct = ContentType::new("application", "json");
         if req.content_type() != Some(&share_ct) {
-            return Failure((Status::UnsupportedMediaType, UrlIDError::ContentType));
+            return fail(req, Status::UnsupportedMediaType, UrlIDError::ContentType);
         }
 
-        let limit = 1024.bytes(); //Set the maximum size we'll unwrap
-                                  //Read the data
+        // Set the maximum size we'll unwrap, then read the data
+        let limit = req.limits().get("share").unwrap_or_else(|| 64.kibibytes());
         let string = match data.open(limit).into_string().await {
             Ok(string) if string.is_complete() => string.into_inner(),
-            Ok(_) => return Failure((Status::PayloadTooLarge, UrlIDError::TooLarge)),
+            Ok(_) => return fail(req, Status::PayloadTooLarge, UrlIDError::TooLarge),
             Err(e) => {
-                return Failure((
+                return fail(
+                    req,
                     Status::InternalServerError,
                     UrlIDError::ServerError(e.to_string()),
-                ))
+                )
             }
         };
 
@@ -308,13 +1104,57 @@ impl<'r> FromData<'r> for UncommittedUrlID {
         let mut share: UncommittedUrlID = match serde_json::from_str(string) {
             Ok(share) => share,
             Err(e) => {
-                return Failure((Status::BadRequest, UrlIDError::ParseFailure(e.to_string())))
+                return fail(
+                    req,
+                    Status::BadRequest,
+                    UrlIDError::ParseFailure(e.to_string()),
+                )
             }
         };
-        if share.exp.is_none() {
-            share.exp = Some(std::i64::MAX) // Note it's not very idiomatic to have this defined in multiple places (both here and default), might pay to wrap in enum then reuse?
+        if let Some(config) = req.rocket().state::<Config>() {
+            if let Err(e) = share.apply_ttl(config) {
+                return fail(req, Status::BadRequest, e);
+            }
         }
         share.crt = Some(get_time_seconds());
+        share.url_from_first_variant();
+        if let Err(e) = share.validate() {
+            return fail(req, Status::BadRequest, e);
+        }
         Success(share)
     }
 }
+
+#[test]
+fn test_share_limit() {
+    use rocket::http::ContentType;
+    let body = |len: usize| {
+        format!(
+            r#"{{"url":"https://a.example","notes":"{}"}}"#,
+            "x".repeat(len)
+        )
+    };
+    let (client, _dir) = crate::test_client();
+    let create = |body: String| {
+        client
+            .post("/shorten")
+            .header(ContentType::JSON)
+            .body(body)
+            .dispatch()
+            .status()
+    };
+    assert_eq!(create(body(4 * 1024)), Status::Ok);
+    assert_eq!(create(body(64 * 1024)), Status::PayloadTooLarge);
+
+    let (client, _dir) = crate::test_client_with(|f| f.merge(("limits.share", 2048)));
+    let create = |body: String| {
+        client
+            .post("/shorten")
+            .header(ContentType::JSON)
+            .body(body)
+            .dispatch()
+            .status()
+    };
+    assert_eq!(create(body(1024)), Status::Ok);
+    assert_eq!(create(body(4 * 1024)), Status::PayloadTooLarge);
+}
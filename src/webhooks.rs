@@ -0,0 +1,903 @@
+//! Webhooks notifying other services when shares are created, changed, deleted or clicked. Subscriptions are stored
+//! in the `webhooks` table, and each event queues a delivery in `webhook_deliveries` which a background worker sends,
+//! retrying failed deliveries with exponential backoff. Every delivery is signed with its subscription's secret.
+use crate::auth::{ApiKey, Principal};
+use crate::common::get_time_seconds;
+use crate::config::Config;
+use crate::database::{DatabaseError, FromDatabase, SharesDbConn};
+use crate::events::{Event, ShareEvent};
+use crate::outbound;
+use futures::stream::{self, StreamExt};
+use hmac::{Hmac, Mac};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket::tokio::{self, sync::Notify, time::Duration};
+use rocket::State;
+use rocket::{Orbit, Rocket, Shutdown};
+use rocket_sync_db_pools::rusqlite::{self, params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::sync::LazyLock;
+
+/// How long after a first failed attempt a delivery is retried if `webhook_retry_base` is not configured, in seconds.
+const DEFAULT_RETRY_BASE_SECONDS: i64 = 10;
+
+/// The longest a failed delivery waits before being retried, however many times it has failed.
+const MAX_RETRY_DELAY_SECONDS: i64 = 60 * 60;
+
+/// How many times a delivery is attempted if `webhook_max_attempts` is not configured.
+const DEFAULT_MAX_ATTEMPTS: u32 = 8;
+
+/// How long a receiver has to respond before the attempt is counted as failed.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The longest the worker sleeps between checks for due deliveries, in case it missed being woken.
+const POLL_INTERVAL_SECONDS: i64 = 30;
+
+/// How many due deliveries the worker reads from the database at once.
+const BATCH_SIZE: i64 = 20;
+
+/// How many deliveries the worker sends at once, so that slow receivers do not hold up the rest.
+const MAX_CONCURRENT_DELIVERIES: usize = 8;
+
+/// How many deliveries are listed if no limit is given.
+const DEFAULT_LOG_SIZE: i64 = 50;
+
+/// The most deliveries which may be listed at once, larger limits are reduced to this.
+const MAX_LOG_SIZE: i64 = 500;
+
+/// Wakes the delivery worker when new deliveries are queued.
+static QUEUED: LazyLock<Notify> = LazyLock::new(Notify::new);
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A webhook subscription, as stored in the database.
+#[derive(Debug, Clone, Serialize)]
+pub struct Webhook {
+    id: i64,
+    /// Where deliveries are POSTed.
+    url: String,
+    events: Vec<Event>,
+    /// Who created the webhook. Events are delivered for the shares they own.
+    owner: String,
+    /// Whether events are delivered for every share rather than only the owner's, which only admins may choose.
+    all_shares: bool,
+    crt: i64,
+    /// The key deliveries are signed with, only shown when the webhook is created.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret: Option<String>,
+}
+
+impl FromDatabase for Webhook {
+    type Error = rusqlite::Error;
+    fn from_database(row: &rusqlite::Row<'_>) -> Result<Webhook, rusqlite::Error> {
+        let events: String = row.get("events")?;
+        Ok(Webhook {
+            id: row.get("id")?,
+            url: row.get("url")?,
+            events: serde_json::from_str(&events).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(
+                    0,
+                    rusqlite::types::Type::Text,
+                    Box::new(e),
+                )
+            })?,
+            owner: row.get("owner")?,
+            all_shares: row.get("all_shares")?,
+            crt: row.get("crt")?,
+            secret: None,
+        })
+    }
+}
+
+/// A single delivery of an event to a webhook, as listed in its delivery log.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeliveryRecord {
+    id: i64,
+    event: String,
+    payload: serde_json::Value,
+    /// Either `pending`, `delivered` or `failed`, once every attempt has been used up.
+    status: String,
+    attempts: u32,
+    /// When a pending delivery will next be attempted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_attempt: Option<i64>,
+    /// The status code the receiver last responded with, if it responded.
+    response_code: Option<u16>,
+    /// Why the last attempt failed.
+    error: Option<String>,
+    crt: i64,
+    delivered_at: Option<i64>,
+}
+
+impl FromDatabase for DeliveryRecord {
+    type Error = rusqlite::Error;
+    fn from_database(row: &rusqlite::Row<'_>) -> Result<DeliveryRecord, rusqlite::Error> {
+        let status: String = row.get("status")?;
+        let payload: String = row.get("payload")?;
+        Ok(DeliveryRecord {
+            id: row.get("id")?,
+            event: row.get("event")?,
+            payload: serde_json::from_str(&payload).unwrap_or(serde_json::Value::String(payload)),
+            next_attempt: match status.as_str() {
+                "pending" => Some(row.get("next_attempt")?),
+                _ => None,
+            },
+            status,
+            attempts: row.get("attempts")?,
+            response_code: row.get("response_code")?,
+            error: row.get("error")?,
+            crt: row.get("crt")?,
+            delivered_at: row.get("delivered_at")?,
+        })
+    }
+}
+
+/// How failed deliveries are retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The delay before the first retry, in seconds, doubling with each further attempt.
+    base: i64,
+    max_attempts: u32,
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: Option<&Config>) -> Self {
+        RetryPolicy {
+            base: config
+                .and_then(|c| c.webhook_retry_base)
+                .map(|t| t.0)
+                .unwrap_or(DEFAULT_RETRY_BASE_SECONDS),
+            max_attempts: config
+                .and_then(|c| c.webhook_max_attempts)
+                .unwrap_or(DEFAULT_MAX_ATTEMPTS),
+        }
+    }
+
+    /// How long to wait before retrying a delivery which has failed `attempts` times, or None if it should be given up on.
+    fn retry_delay(&self, attempts: u32) -> Option<i64> {
+        if attempts >= self.max_attempts {
+            return None;
+        }
+        let factor = 1_i64 << attempts.saturating_sub(1).min(32);
+        Some(
+            self.base
+                .saturating_mul(factor)
+                .min(MAX_RETRY_DELAY_SECONDS),
+        )
+    }
+}
+
+/// Sign a delivery made at `timestamp`, returning the hex encoded HMAC-SHA256 of `<timestamp>.<body>` keyed with the secret.
+/// Receivers should recompute this to check a delivery is genuine, and reject old timestamps to prevent replays.
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("hmac accepts keys of any length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Queue a delivery of an event to every webhook subscribed to it, and wake the worker to send them.
+/// Failing to queue deliveries is logged rather than failing the request which caused the event.
+pub async fn emit(conn: &SharesDbConn, event: &ShareEvent) {
+    emit_all(conn, std::slice::from_ref(event)).await
+}
+
+/// As [`emit`], for several events queued together in one transaction.
+pub async fn emit_all(conn: &SharesDbConn, events: &[ShareEvent]) {
+    let mut queue = Vec::with_capacity(events.len());
+    for event in events {
+        match serde_json::to_string(event) {
+            Ok(payload) => queue.push((
+                event.event,
+                payload,
+                event.timestamp,
+                event.owner().map(str::to_owned),
+            )),
+            Err(e) => tracing::error!(error = %e, "failed to serialize event for webhooks"),
+        }
+    }
+    if queue.is_empty() {
+        return;
+    }
+    let queued = conn
+        .run_timed(move |c| -> Result<usize, rusqlite::Error> {
+            let tx = c.transaction()?;
+            let mut queued = 0;
+            {
+                let mut stmt = tx.prepare(
+                    "INSERT INTO webhook_deliveries (webhook_id, event, payload, next_attempt, crt)
+                    SELECT id, ?1, ?2, ?3, ?3 FROM webhooks
+                    WHERE (all_shares = 1 OR owner = ?4)
+                    AND EXISTS (SELECT 1 FROM json_each(webhooks.events) WHERE json_each.value = ?1);",
+                )?;
+                for (event, payload, now, owner) in queue {
+                    queued += stmt.execute(params![event.as_str(), payload, now, owner])?;
+                }
+            }
+            tx.commit()?;
+            Ok(queued)
+        })
+        .await;
+    match queued {
+        Ok(0) => {}
+        Ok(_) => QUEUED.notify_waiters(),
+        Err(e) => tracing::error!(error = %e, "failed to queue webhook deliveries"),
+    }
+}
+
+/// A delivery which is due to be attempted, alongside where to send it.
+struct DueDelivery {
+    id: i64,
+    url: String,
+    secret: String,
+    event: String,
+    payload: String,
+    attempts: u32,
+}
+
+/// Read the pending deliveries due to be attempted by `now`, oldest first.
+fn due_deliveries(c: &rusqlite::Connection, now: i64) -> Result<Vec<DueDelivery>, rusqlite::Error> {
+    c.prepare_cached(
+        "SELECT webhook_deliveries.id, webhooks.url, webhooks.secret, event, payload, attempts
+        FROM webhook_deliveries JOIN webhooks ON webhooks.id = webhook_deliveries.webhook_id
+        WHERE status = 'pending' AND next_attempt <= ?1 ORDER BY next_attempt, webhook_deliveries.id LIMIT ?2;",
+    )?
+    .query_map(params![now, BATCH_SIZE], |row| {
+        Ok(DueDelivery {
+            id: row.get(0)?,
+            url: row.get(1)?,
+            secret: row.get(2)?,
+            event: row.get(3)?,
+            payload: row.get(4)?,
+            attempts: row.get(5)?,
+        })
+    })?
+    .collect()
+}
+
+/// When the next pending delivery is due, or None if none are pending.
+fn next_due(c: &rusqlite::Connection) -> Result<Option<i64>, rusqlite::Error> {
+    c.query_row(
+        "SELECT MIN(next_attempt) FROM webhook_deliveries WHERE status = 'pending';",
+        [],
+        |row| row.get(0),
+    )
+}
+
+/// POST a delivery to its webhook, returning the status code of a successful response, or the status code (if the
+/// receiver responded at all) and reason the attempt failed.
+async fn send(
+    client: &outbound::Client,
+    delivery: &DueDelivery,
+    allow_private: bool,
+    now: i64,
+) -> Result<u16, (Option<u16>, String)> {
+    // Names are checked as the client resolves them, but addresses written into the url are not resolved.
+    if !allow_private {
+        let url = url::Url::parse(&delivery.url).map_err(|e| (None, e.to_string()))?;
+        outbound::check_literal(&url).map_err(|e| (None, e))?;
+    }
+    let request = hyper::Request::post(delivery.url.as_str())
+        .header("Content-Type", "application/json")
+        .header(
+            "User-Agent",
+            concat!("url-shortener/", env!("CARGO_PKG_VERSION")),
+        )
+        .header("X-Webhook-Event", delivery.event.as_str())
+        .header("X-Webhook-Delivery", delivery.id)
+        .header("X-Webhook-Timestamp", now)
+        .header(
+            "X-Webhook-Signature",
+            format!("sha256={}", sign(&delivery.secret, now, &delivery.payload)),
+        )
+        .body(hyper::Body::from(delivery.payload.clone()))
+        .map_err(|e| (None, e.to_string()))?;
+    match tokio::time::timeout(DELIVERY_TIMEOUT, client.request(request)).await {
+        Err(_) => Err((None, "timed out waiting for a response".into())),
+        Ok(Err(e)) => Err((None, e.to_string())),
+        Ok(Ok(res)) if res.status().is_success() => Ok(res.status().as_u16()),
+        Ok(Ok(res)) => Err((
+            Some(res.status().as_u16()),
+            format!("the receiver responded {}", res.status()),
+        )),
+    }
+}
+
+/// Record the result of attempting a delivery, scheduling a retry if it failed and attempts remain.
+fn record_attempt(
+    c: &rusqlite::Connection,
+    delivery: &DueDelivery,
+    result: Result<u16, (Option<u16>, String)>,
+    policy: RetryPolicy,
+    now: i64,
+) -> Result<(), rusqlite::Error> {
+    let attempts = delivery.attempts + 1;
+    let (status, next_attempt, response_code, error, delivered_at) = match result {
+        Ok(code) => ("delivered", now, Some(code), None, Some(now)),
+        Err((code, error)) => match policy.retry_delay(attempts) {
+            Some(delay) => ("pending", now + delay, code, Some(error), None),
+            None => ("failed", now, code, Some(error), None),
+        },
+    };
+    c.execute(
+        "UPDATE webhook_deliveries SET status = ?1, attempts = ?2, next_attempt = ?3, response_code = ?4, error = ?5,
+        delivered_at = ?6 WHERE id = ?7;",
+        params![
+            status,
+            attempts,
+            next_attempt,
+            response_code,
+            error,
+            delivered_at,
+            delivery.id
+        ],
+    )?;
+    Ok(())
+}
+
+/// Send due deliveries until shutdown, sleeping until the next is due or new deliveries are queued. Unless
+/// `allow_private`, deliveries to non-public addresses fail.
+async fn run(conn: SharesDbConn, policy: RetryPolicy, allow_private: bool, shutdown: Shutdown) {
+    let client = outbound::client(allow_private);
+    loop {
+        // Listen before reading the queue, so deliveries queued while it is being read still wake the worker.
+        let queued = QUEUED.notified();
+        tokio::pin!(queued);
+        queued.as_mut().enable();
+        let now = get_time_seconds();
+        let wait = match conn.run_timed(move |c| due_deliveries(c, now)).await {
+            Ok(due) if !due.is_empty() => {
+                stream::iter(due)
+                    .map(|delivery| async {
+                        let now = get_time_seconds();
+                        let result = send(&client, &delivery, allow_private, now).await;
+                        if let Err((_, e)) = &result {
+                            tracing::warn!(delivery = delivery.id, error = %e, "webhook delivery failed");
+                        }
+                        let recorded = conn
+                            .run_timed(move |c| record_attempt(c, &delivery, result, policy, now))
+                            .await;
+                        if let Err(e) = recorded {
+                            tracing::error!(error = %e, "failed to record webhook delivery attempt");
+                        }
+                    })
+                    .buffer_unordered(MAX_CONCURRENT_DELIVERIES)
+                    .collect::<()>()
+                    .await;
+                continue;
+            }
+            Ok(_) => match conn.run_timed(|c| next_due(c)).await {
+                Ok(Some(next)) => (next - now).clamp(0, POLL_INTERVAL_SECONDS),
+                _ => POLL_INTERVAL_SECONDS,
+            },
+            Err(e) => {
+                tracing::warn!(error = %e, "failed to read pending webhook deliveries");
+                POLL_INTERVAL_SECONDS
+            }
+        };
+        tokio::select! {
+            _ = &mut queued => {}
+            _ = tokio::time::sleep(Duration::from_secs(wait as u64)) => {}
+            _ = shutdown.clone() => return,
+        }
+    }
+}
+
+/// Starts the worker delivering webhooks once the server has launched, stopping it on shutdown.
+pub struct WebhookWorker;
+
+#[rocket::async_trait]
+impl Fairing for WebhookWorker {
+    fn info(&self) -> Info {
+        Info {
+            name: "Webhook Worker",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let conn = match SharesDbConn::get_one(rocket).await {
+            Some(conn) => conn,
+            None => {
+                tracing::error!(
+                    "failed to start the webhook worker, no database connection available"
+                );
+                return;
+            }
+        };
+        let policy = RetryPolicy::from_config(rocket.state::<Config>());
+        let allow_private = rocket
+            .state::<Config>()
+            .is_some_and(|c| c.allow_private_webhooks);
+        tokio::spawn(run(conn, policy, allow_private, rocket.shutdown()));
+    }
+}
+
+/// Find a webhook, provided the principal created it or is an admin.
+async fn find_managed(
+    conn: &SharesDbConn,
+    principal: &Principal,
+    id: i64,
+) -> Result<Webhook, (Status, String)> {
+    let webhook = conn
+        .run_timed(move |c| {
+            c.query_row(
+                "SELECT * FROM webhooks WHERE id = ?1;",
+                params![id],
+                Webhook::from_database,
+            )
+            .optional()
+        })
+        .await
+        .map_err(DatabaseError::from)?
+        .ok_or_else(|| {
+            (
+                Status::NotFound,
+                format!("no webhook exists with id {}", id),
+            )
+        })?;
+    if !principal.admin && webhook.owner != principal.owner {
+        return Err((
+            Status::Forbidden,
+            "the provided bearer token lacks permission".into(),
+        ));
+    }
+    Ok(webhook)
+}
+
+/// A webhook to create.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NewWebhook {
+    url: String,
+    events: Vec<Event>,
+    #[serde(default)]
+    secret: Option<String>,
+    #[serde(default)]
+    all_shares: bool,
+}
+
+impl NewWebhook {
+    /// Check the webhook can be delivered to, and the principal may subscribe to the shares it asks for. Unless
+    /// `allow_private`, the url must resolve only to public addresses.
+    async fn validate(
+        &self,
+        principal: &Principal,
+        allow_private: bool,
+    ) -> Result<(), (Status, String)> {
+        let url = url::Url::parse(&self.url).map_err(|e| {
+            (
+                Status::BadRequest,
+                format!("'{}' is not a valid url: {}", self.url, e),
+            )
+        })?;
+        if !matches!(url.scheme(), "https" | "http") {
+            return Err((
+                Status::BadRequest,
+                "webhooks may only be delivered over https or http".into(),
+            ));
+        }
+        if !allow_private {
+            outbound::check_public(&url)
+                .await
+                .map_err(|e| (Status::BadRequest, e))?;
+        }
+        if self.events.is_empty() {
+            return Err((
+                Status::BadRequest,
+                "a webhook must subscribe to at least one event".into(),
+            ));
+        }
+        if self.secret.as_deref().is_some_and(str::is_empty) {
+            return Err((
+                Status::BadRequest,
+                "a webhook secret may not be empty".into(),
+            ));
+        }
+        if self.all_shares && !principal.admin {
+            return Err((
+                Status::Forbidden,
+                "only admins may subscribe to events for every share".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Subscribe to events concerning the caller's shares, or every share if an admin asks for `all_shares`.
+/// The response includes the secret deliveries are signed with, generated if none is given, which is not shown again.
+/// ```JSON
+/// POST /api/webhooks
+/// {
+///     url: String (an https or http url deliveries are POSTed to, which must resolve to public addresses)
+///     events: ["share.created" | "share.updated" | "share.deleted" | "share.restored" | "share.clicked"]
+///     secret: String (optional)
+///     all_shares: Boolean (optional, admins only)
+/// }
+/// ```
+#[post("/webhooks", data = "<webhook>")]
+async fn create_webhook(
+    webhook: Json<NewWebhook>,
+    principal: Principal,
+    config: &State<Config>,
+    conn: SharesDbConn,
+) -> Result<(Status, Json<Webhook>), (Status, String)> {
+    let webhook = webhook.into_inner();
+    webhook
+        .validate(&principal, config.allow_private_webhooks)
+        .await?;
+    let events = serde_json::to_string(&webhook.events)
+        .map_err(|e| (Status::InternalServerError, e.to_string()))?;
+    let mut created = Webhook {
+        id: 0,
+        url: webhook.url,
+        events: webhook.events,
+        owner: principal.owner,
+        all_shares: webhook.all_shares,
+        crt: get_time_seconds(),
+        secret: Some(webhook.secret.unwrap_or_else(ApiKey::generate_secret)),
+    };
+    let row = created.clone();
+    created.id = conn
+        .run_timed(move |c| {
+            c.execute(
+                "INSERT INTO webhooks (url, events, secret, owner, all_shares, crt) VALUES (?1, ?2, ?3, ?4, ?5, ?6);",
+                params![row.url, events, row.secret, row.owner, row.all_shares, row.crt],
+            )?;
+            Ok::<_, rusqlite::Error>(c.last_insert_rowid())
+        })
+        .await
+        .map_err(DatabaseError::from)?;
+    Ok((Status::Created, Json(created)))
+}
+
+/// List the caller's webhooks, or every webhook for admins.
+#[get("/webhooks")]
+async fn list_webhooks(
+    principal: Principal,
+    conn: SharesDbConn,
+) -> Result<Json<Vec<Webhook>>, (Status, String)> {
+    let owner = (!principal.admin).then_some(principal.owner);
+    let webhooks = conn
+        .run_timed(move |c| {
+            c.prepare("SELECT * FROM webhooks WHERE ?1 IS NULL OR owner = ?1 ORDER BY id;")?
+                .query_map(params![owner], Webhook::from_database)?
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .await
+        .map_err(DatabaseError::from)?;
+    Ok(Json(webhooks))
+}
+
+/// Delete a webhook, abandoning any of its deliveries still pending.
+#[delete("/webhooks/<id>")]
+async fn delete_webhook(
+    id: i64,
+    principal: Principal,
+    conn: SharesDbConn,
+) -> Result<Status, (Status, String)> {
+    find_managed(&conn, &principal, id).await?;
+    conn.run_timed(move |c| {
+        let tx = c.transaction()?;
+        tx.execute(
+            "DELETE FROM webhook_deliveries WHERE webhook_id = ?1;",
+            params![id],
+        )?;
+        tx.execute("DELETE FROM webhooks WHERE id = ?1;", params![id])?;
+        tx.commit()
+    })
+    .await
+    .map_err(DatabaseError::from)?;
+    Ok(Status::NoContent)
+}
+
+/// List the most recent deliveries to a webhook, newest first, with the outcome of their last attempt.
+#[get("/webhooks/<id>/deliveries?<limit>")]
+async fn list_deliveries(
+    id: i64,
+    limit: Option<i64>,
+    principal: Principal,
+    conn: SharesDbConn,
+) -> Result<Json<Vec<DeliveryRecord>>, (Status, String)> {
+    find_managed(&conn, &principal, id).await?;
+    let limit = limit.unwrap_or(DEFAULT_LOG_SIZE).clamp(1, MAX_LOG_SIZE);
+    let deliveries = conn
+        .run_timed(move |c| {
+            c.prepare(
+                "SELECT * FROM webhook_deliveries WHERE webhook_id = ?1 ORDER BY id DESC LIMIT ?2;",
+            )?
+            .query_map(params![id, limit], DeliveryRecord::from_database)?
+            .collect::<Result<Vec<_>, _>>()
+        })
+        .await
+        .map_err(DatabaseError::from)?;
+    Ok(Json(deliveries))
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![
+        create_webhook,
+        list_webhooks,
+        delete_webhook,
+        list_deliveries
+    ]
+}
+
+#[test]
+fn test_retry_delay() {
+    let policy = RetryPolicy {
+        base: 10,
+        max_attempts: 20,
+    };
+    assert_eq!(policy.retry_delay(1), Some(10));
+    assert_eq!(policy.retry_delay(2), Some(20));
+    assert_eq!(policy.retry_delay(4), Some(80));
+    assert_eq!(policy.retry_delay(19), Some(MAX_RETRY_DELAY_SECONDS));
+    assert_eq!(policy.retry_delay(20), None);
+}
+
+#[rocket::async_test]
+async fn test_webhook_deliveries() {
+    use hyper::service::{make_service_fn, service_fn};
+    use rocket::http::{ContentType, Header};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // A stand-in receiver which fails the first delivery it is sent, and accepts every one after.
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let received = Arc::new(AtomicUsize::new(0));
+    let make_service = make_service_fn(move |_| {
+        let (tx, received) = (tx.clone(), received.clone());
+        async move {
+            Ok::<_, std::convert::Infallible>(service_fn(
+                move |req: hyper::Request<hyper::Body>| {
+                    let (tx, received) = (tx.clone(), received.clone());
+                    async move {
+                        let (parts, body) = req.into_parts();
+                        let body = hyper::body::to_bytes(body).await.unwrap();
+                        let header = |name: &str| parts.headers[name].to_str().unwrap().to_owned();
+                        tx.send((
+                            header("X-Webhook-Event"),
+                            header("X-Webhook-Timestamp"),
+                            header("X-Webhook-Signature"),
+                            String::from_utf8(body.to_vec()).unwrap(),
+                        ))
+                        .unwrap();
+                        let status = match received.fetch_add(1, Ordering::SeqCst) {
+                            0 => 500,
+                            _ => 200,
+                        };
+                        hyper::Response::builder()
+                            .status(status)
+                            .body(hyper::Body::empty())
+                    }
+                },
+            ))
+        }
+    });
+    let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+    let receiver = format!("http://{}/hooks", server.local_addr());
+    tokio::spawn(server);
+
+    let (client, _dir) = crate::test_async_client_with(|f| {
+        f.merge(("webhook_retry_base", 0))
+            .merge(("allow_private_webhooks", true))
+    })
+    .await;
+    let admin = || Header::new("Authorization", "Bearer admin");
+    let res = client
+        .post("/api/webhooks")
+        .header(ContentType::JSON)
+        .header(admin())
+        .body(r#"{"url":"ftp://hooks.example","events":["share.created"]}"#)
+        .dispatch()
+        .await;
+    assert_eq!(res.status(), Status::BadRequest);
+    let res = client
+        .post("/api/webhooks")
+        .header(ContentType::JSON)
+        .header(admin())
+        .body(format!(
+            r#"{{"url":"{}","events":["share.created"],"secret":"s3cret"}}"#,
+            receiver
+        ))
+        .dispatch()
+        .await;
+    assert_eq!(res.status(), Status::Created);
+    let webhook: serde_json::Value = res.into_json().await.unwrap();
+    assert_eq!(webhook["secret"], "s3cret");
+
+    let link = client
+        .post("/shorten")
+        .header(ContentType::JSON)
+        .header(admin())
+        .body(r#"{"url":"https://a.example"}"#)
+        .dispatch()
+        .await
+        .into_string()
+        .await
+        .unwrap();
+
+    let wait = Duration::from_secs(10);
+    let (_, _, _, failed) = tokio::time::timeout(wait, rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    let (event, timestamp, signature, body) = tokio::time::timeout(wait, rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(failed, body);
+    assert_eq!(event, "share.created");
+    assert_eq!(
+        signature,
+        format!(
+            "sha256={}",
+            sign("s3cret", timestamp.parse().unwrap(), &body)
+        )
+    );
+    let payload: serde_json::Value = serde_json::from_str(&body).unwrap();
+    let id_part = |link: &str| {
+        link.split(crate::url_id::DELIM_CHAR)
+            .next()
+            .unwrap()
+            .to_owned()
+    };
+    assert_eq!(
+        id_part(payload["share"]["link"].as_str().unwrap()),
+        id_part(&link)
+    );
+    assert_eq!(payload["share"]["url"], "https://a.example");
+
+    let mut deliveries = serde_json::Value::Null;
+    for _ in 0..100 {
+        deliveries = client
+            .get(format!("/api/webhooks/{}/deliveries", webhook["id"]))
+            .header(admin())
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        if deliveries[0]["status"] == "delivered" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(deliveries[0]["status"], "delivered");
+    assert_eq!(deliveries[0]["attempts"], 2);
+    assert_eq!(deliveries[0]["response_code"], 200);
+
+    let listed: serde_json::Value = client
+        .get("/api/webhooks")
+        .header(admin())
+        .dispatch()
+        .await
+        .into_json()
+        .await
+        .unwrap();
+    assert!(listed[0].get("secret").is_none());
+}
+
+#[rocket::async_test]
+async fn test_private_destinations() {
+    use rocket::http::{ContentType, Header};
+    let (client, dir) =
+        crate::test_async_client_with(|f| f.merge(("webhook_max_attempts", 1))).await;
+    let admin = || Header::new("Authorization", "Bearer admin");
+    for url in [
+        "http://127.0.0.1:8000/hooks",
+        "http://localhost/hooks",
+        "http://10.0.0.1/hooks",
+        "http://169.254.169.254/latest/meta-data",
+        "http://[::1]/hooks",
+    ] {
+        let res = client
+            .post("/api/webhooks")
+            .header(ContentType::JSON)
+            .header(admin())
+            .body(format!(r#"{{"url":"{}","events":["share.created"]}}"#, url))
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::BadRequest, "{}", url);
+    }
+
+    // A webhook stored before its address became private is still refused when delivering.
+    let c =
+        rocket_sync_db_pools::rusqlite::Connection::open(dir.path().join("database.db")).unwrap();
+    c.execute(
+        "INSERT INTO webhooks (url, events, secret, owner, all_shares, crt)
+        VALUES ('http://127.0.0.1:8000/hooks', '[\"share.created\"]', 's3cret', 'admin', 1, 0);",
+        [],
+    )
+    .unwrap();
+    client
+        .post("/shorten")
+        .header(ContentType::JSON)
+        .header(admin())
+        .body(r#"{"url":"https://a.example"}"#)
+        .dispatch()
+        .await;
+    let mut deliveries = serde_json::Value::Null;
+    for _ in 0..100 {
+        deliveries = client
+            .get("/api/webhooks/1/deliveries")
+            .header(admin())
+            .dispatch()
+            .await
+            .into_json()
+            .await
+            .unwrap();
+        if deliveries[0]["status"] == "failed" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(deliveries[0]["status"], "failed");
+    assert_eq!(deliveries[0]["error"], "127.0.0.1 is not a public address");
+}
+
+#[rocket::async_test]
+async fn test_concurrent_deliveries() {
+    use hyper::service::{make_service_fn, service_fn};
+    use rocket::http::{ContentType, Header};
+
+    // Receivers which respond after `delay`, announcing each delivery they are sent.
+    let receiver = |delay: Duration, tx: tokio::sync::mpsc::UnboundedSender<()>| {
+        let make_service = make_service_fn(move |_| {
+            let tx = tx.clone();
+            async move {
+                Ok::<_, std::convert::Infallible>(service_fn(move |_| {
+                    let tx = tx.clone();
+                    async move {
+                        tokio::time::sleep(delay).await;
+                        tx.send(()).unwrap();
+                        hyper::Response::builder().body(hyper::Body::empty())
+                    }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let url = format!("http://{}/hooks", server.local_addr());
+        tokio::spawn(server);
+        url
+    };
+    let (slow_tx, _slow_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (fast_tx, mut fast_rx) = tokio::sync::mpsc::unbounded_channel();
+    let slow = receiver(Duration::from_secs(5), slow_tx);
+    let fast = receiver(Duration::ZERO, fast_tx);
+
+    let (client, _dir) =
+        crate::test_async_client_with(|f| f.merge(("allow_private_webhooks", true))).await;
+    let admin = || Header::new("Authorization", "Bearer admin");
+    for url in [slow, fast] {
+        let res = client
+            .post("/api/webhooks")
+            .header(ContentType::JSON)
+            .header(admin())
+            .body(format!(r#"{{"url":"{}","events":["share.created"]}}"#, url))
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::Created);
+    }
+    client
+        .post("/shorten")
+        .header(ContentType::JSON)
+        .header(admin())
+        .body(r#"{"url":"https://a.example"}"#)
+        .dispatch()
+        .await;
+    // The slow receiver is sent its delivery first, but does not hold up the other.
+    tokio::time::timeout(Duration::from_secs(2), fast_rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+}
@@ -0,0 +1,601 @@
+//! Workspaces shared by a team. Each workspace has members, identified by the owner of their api keys, who each hold
+//! a role: viewers may read the workspace's shares, their tags and stats, editors may also create and change them, and
+//! admins may also manage the workspace's members. Shares created in a workspace belong to it rather than their owner.
+use crate::auth::Principal;
+use crate::common::get_time_seconds;
+use crate::database::{DatabaseError, FromDatabase, SharesDbConn};
+use rocket::http::Status;
+use rocket::serde::json::Json;
+use rocket_sync_db_pools::rusqlite::types::{
+    FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef,
+};
+use rocket_sync_db_pools::rusqlite::{self, params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The roles a member may hold in a workspace, each permitting everything the roles before it do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// May list and read the workspace's shares, and their stats and history.
+    Viewer,
+    /// May also create, change, delete and restore the workspace's shares.
+    Editor,
+    /// May also add, remove and change the roles of members.
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Editor => "editor",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl ToSql for Role {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for Role {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "viewer" => Ok(Role::Viewer),
+            "editor" => Ok(Role::Editor),
+            "admin" => Ok(Role::Admin),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// A workspace, as stored in the database.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Workspace {
+    pub id: i64,
+    pub name: String,
+    pub crt: i64,
+    /// The role the caller holds in the workspace, when listing workspaces.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<Role>,
+}
+
+impl FromDatabase for Workspace {
+    type Error = rusqlite::Error;
+    fn from_database(row: &rusqlite::Row<'_>) -> Result<Workspace, rusqlite::Error> {
+        Ok(Workspace {
+            id: row.get("id")?,
+            name: row.get("name")?,
+            crt: row.get("crt")?,
+            role: None,
+        })
+    }
+}
+
+/// A member of a workspace.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Member {
+    pub owner: String,
+    pub role: Role,
+}
+
+impl FromDatabase for Member {
+    type Error = rusqlite::Error;
+    fn from_database(row: &rusqlite::Row<'_>) -> Result<Member, rusqlite::Error> {
+        Ok(Member {
+            owner: row.get("owner")?,
+            role: row.get("role")?,
+        })
+    }
+}
+
+/// Find the role `owner` holds in each workspace they are a member of, by workspace id.
+pub async fn find_roles(
+    conn: &SharesDbConn,
+    owner: String,
+) -> Result<BTreeMap<i64, Role>, DatabaseError> {
+    let result = conn
+        .run_timed(move |c| {
+            c.prepare("SELECT workspace_id, role FROM workspace_members WHERE owner = ?1;")?
+                .query_map(params![owner], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<BTreeMap<_, _>, _>>()
+        })
+        .await?;
+    Ok(result)
+}
+
+/// Find a workspace, provided the principal holds at least the `required` role in it.
+async fn find_managed(
+    conn: &SharesDbConn,
+    principal: &Principal,
+    id: i64,
+    required: Role,
+) -> Result<Workspace, (Status, String)> {
+    let workspace = conn
+        .run_timed(move |c| {
+            c.query_row(
+                "SELECT * FROM workspaces WHERE id = ?1;",
+                params![id],
+                Workspace::from_database,
+            )
+            .optional()
+        })
+        .await
+        .map_err(DatabaseError::from)?
+        .ok_or_else(|| {
+            (
+                Status::NotFound,
+                format!("no workspace exists with id {}", id),
+            )
+        })?;
+    if !principal.holds(id, required) {
+        return Err((
+            Status::Forbidden,
+            "the provided bearer token lacks permission".into(),
+        ));
+    }
+    Ok(workspace)
+}
+
+/// A workspace to create.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NewWorkspace {
+    name: String,
+}
+
+/// Create a workspace, making the caller its first admin.
+/// ```JSON
+/// POST /api/workspaces
+/// {
+///     name: String (unique)
+/// }
+/// ```
+#[post("/workspaces", data = "<workspace>")]
+async fn create_workspace(
+    workspace: Json<NewWorkspace>,
+    principal: Principal,
+    conn: SharesDbConn,
+) -> Result<(Status, Json<Workspace>), (Status, String)> {
+    let name = workspace.into_inner().name.trim().to_owned();
+    if name.is_empty() {
+        return Err((Status::BadRequest, "a workspace requires a name".into()));
+    }
+    let mut created = Workspace {
+        id: 0,
+        name,
+        crt: get_time_seconds(),
+        role: Some(Role::Admin),
+    };
+    let row = created.clone();
+    let owner = principal.owner;
+    let id = conn
+        .run_timed(move |c| {
+            let tx = c.transaction()?;
+            let taken = tx
+                .query_row(
+                    "SELECT 1 FROM workspaces WHERE name = ?1;",
+                    params![row.name],
+                    |_| Ok(()),
+                )
+                .optional()?;
+            if taken.is_some() {
+                return Ok(None);
+            }
+            tx.execute(
+                "INSERT INTO workspaces (name, crt) VALUES (?1, ?2);",
+                params![row.name, row.crt],
+            )?;
+            let id = tx.last_insert_rowid();
+            tx.execute(
+                "INSERT INTO workspace_members (workspace_id, owner, role) VALUES (?1, ?2, ?3);",
+                params![id, owner, Role::Admin],
+            )?;
+            tx.commit()?;
+            Ok::<_, rusqlite::Error>(Some(id))
+        })
+        .await
+        .map_err(DatabaseError::from)?;
+    created.id = id.ok_or_else(|| {
+        (
+            Status::Conflict,
+            format!("a workspace named '{}' already exists", created.name),
+        )
+    })?;
+    Ok((Status::Created, Json(created)))
+}
+
+/// List the workspaces the caller is a member of, with the role they hold in each, or every workspace for admins.
+#[get("/workspaces")]
+async fn list_workspaces(
+    principal: Principal,
+    conn: SharesDbConn,
+) -> Result<Json<Vec<Workspace>>, (Status, String)> {
+    let workspaces: Vec<Workspace> = conn
+        .run_timed(|c| {
+            c.prepare("SELECT * FROM workspaces ORDER BY id;")?
+                .query_map([], Workspace::from_database)?
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .await
+        .map_err(DatabaseError::from)?;
+    Ok(Json(
+        workspaces
+            .into_iter()
+            .filter_map(|w| {
+                let role = principal.role_in(w.id)?;
+                Some(Workspace {
+                    role: Some(role),
+                    ..w
+                })
+            })
+            .collect(),
+    ))
+}
+
+/// List the members of a workspace the caller is a member of.
+#[get("/workspaces/<id>/members")]
+async fn list_members(
+    id: i64,
+    principal: Principal,
+    conn: SharesDbConn,
+) -> Result<Json<Vec<Member>>, (Status, String)> {
+    find_managed(&conn, &principal, id, Role::Viewer).await?;
+    let members = conn
+        .run_timed(move |c| {
+            c.prepare("SELECT * FROM workspace_members WHERE workspace_id = ?1 ORDER BY owner;")?
+                .query_map(params![id], Member::from_database)?
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .await
+        .map_err(DatabaseError::from)?;
+    Ok(Json(members))
+}
+
+/// Change the members of a workspace with `change`, refusing changes which would leave it without an admin.
+/// Returns how many members were changed, or None if the change was refused.
+async fn change_members<F>(
+    conn: &SharesDbConn,
+    id: i64,
+    change: F,
+) -> Result<Option<usize>, DatabaseError>
+where
+    F: FnOnce(&rusqlite::Connection) -> Result<usize, rusqlite::Error> + Send + 'static,
+{
+    let result = conn
+        .run_timed(move |c| {
+            let tx = c.transaction()?;
+            let changed = change(&tx)?;
+            let admins: i64 = tx.query_row(
+                "SELECT COUNT(*) FROM workspace_members WHERE workspace_id = ?1 AND role = ?2;",
+                params![id, Role::Admin],
+                |row| row.get(0),
+            )?;
+            if admins == 0 {
+                return Ok(None);
+            }
+            tx.commit()?;
+            Ok::<_, rusqlite::Error>(Some(changed))
+        })
+        .await?;
+    Ok(result)
+}
+
+/// The response when a change would leave a workspace without an admin.
+fn last_admin() -> (Status, String) {
+    (
+        Status::Conflict,
+        "a workspace must keep at least one admin".into(),
+    )
+}
+
+/// The role to give a member.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MemberRole {
+    role: Role,
+}
+
+/// Add a member to a workspace the caller is an admin of, or change the role of an existing member.
+/// ```JSON
+/// PUT /api/workspaces/<id>/members/<owner>
+/// {
+///     role: "viewer" | "editor" | "admin"
+/// }
+/// ```
+#[put("/workspaces/<id>/members/<owner>", data = "<member>")]
+async fn put_member(
+    id: i64,
+    owner: String,
+    member: Json<MemberRole>,
+    principal: Principal,
+    conn: SharesDbConn,
+) -> Result<Json<Member>, (Status, String)> {
+    find_managed(&conn, &principal, id, Role::Admin).await?;
+    let role = member.into_inner().role;
+    let row = owner.clone();
+    let changed = change_members(&conn, id, move |c| {
+        c.execute(
+            "INSERT OR REPLACE INTO workspace_members (workspace_id, owner, role) VALUES (?1, ?2, ?3);",
+            params![id, row, role],
+        )
+    })
+    .await?;
+    match changed {
+        Some(_) => Ok(Json(Member { owner, role })),
+        None => Err(last_admin()),
+    }
+}
+
+/// Remove a member from a workspace the caller is an admin of.
+#[delete("/workspaces/<id>/members/<owner>")]
+async fn remove_member(
+    id: i64,
+    owner: String,
+    principal: Principal,
+    conn: SharesDbConn,
+) -> Result<Status, (Status, String)> {
+    find_managed(&conn, &principal, id, Role::Admin).await?;
+    let row = owner.clone();
+    let removed = change_members(&conn, id, move |c| {
+        c.execute(
+            "DELETE FROM workspace_members WHERE workspace_id = ?1 AND owner = ?2;",
+            params![id, row],
+        )
+    })
+    .await?;
+    match removed {
+        Some(0) => Err((
+            Status::NotFound,
+            format!("'{}' is not a member of workspace {}", owner, id),
+        )),
+        Some(_) => Ok(Status::NoContent),
+        None => Err(last_admin()),
+    }
+}
+
+pub fn routes() -> Vec<rocket::Route> {
+    routes![
+        create_workspace,
+        list_workspaces,
+        list_members,
+        put_member,
+        remove_member
+    ]
+}
+
+#[test]
+fn test_workspace_roles() {
+    use rocket::http::{ContentType, Header, Method};
+    let (client, dir) = crate::test_client();
+    let c = rusqlite::Connection::open(dir.path().join("database.db")).unwrap();
+    crate::insert_api_keys(
+        &c,
+        &[
+            ("alice-key", "alice", false),
+            ("adam-key", "adam", false),
+            ("eddie-key", "eddie", false),
+            ("vera-key", "vera", false),
+            ("olga-key", "olga", false),
+        ],
+    );
+    let request = |method: Method, path: &str, key: &str, body: Option<&str>| {
+        let mut req = client
+            .req(method, path.to_owned())
+            .header(Header::new("Authorization", format!("Bearer {}", key)));
+        if let Some(body) = body {
+            req = req.header(ContentType::JSON).body(body);
+        }
+        req.dispatch()
+    };
+    let created: serde_json::Value = request(
+        Method::Post,
+        "/api/workspaces",
+        "alice-key",
+        Some(r#"{"name":"Marketing"}"#),
+    )
+    .into_json()
+    .unwrap();
+    let id = created["id"].as_i64().unwrap();
+    assert_eq!(created["role"], "admin");
+    for (owner, role) in [("adam", "admin"), ("eddie", "editor"), ("vera", "viewer")] {
+        let res = request(
+            Method::Put,
+            &format!("/api/workspaces/{}/members/{}", id, owner),
+            "alice-key",
+            Some(&format!(r#"{{"role":"{}"}}"#, role)),
+        );
+        assert_eq!(res.status(), Status::Ok);
+    }
+    let shorten = |key: &str| {
+        request(
+            Method::Post,
+            "/shorten",
+            key,
+            Some(&format!(
+                r#"{{"url":"https://a.example","title":"Launch","workspace":{}}}"#,
+                id
+            )),
+        )
+    };
+
+    // The status each route responds with, for a viewer, an editor, an admin of the workspace and an outsider.
+    use Status as S;
+    let routes: &[(Method, &str, Option<&str>, [Status; 4])] = &[
+        (
+            Method::Get,
+            "/api/shares?workspace={id}",
+            None,
+            [S::Ok, S::Ok, S::Ok, S::Forbidden],
+        ),
+        (
+            Method::Get,
+            "/api/search?q=launch&workspace={id}",
+            None,
+            [S::Ok, S::Ok, S::Ok, S::Forbidden],
+        ),
+        (
+            Method::Get,
+            "/api/workspaces/{id}/members",
+            None,
+            [S::Ok, S::Ok, S::Ok, S::Forbidden],
+        ),
+        (
+            Method::Get,
+            "/api/shares/{token}/stats",
+            None,
+            [S::Ok, S::Ok, S::Ok, S::Forbidden],
+        ),
+        (
+            Method::Get,
+            "/api/shares/{token}/history",
+            None,
+            [S::Ok, S::Ok, S::Ok, S::Forbidden],
+        ),
+        (
+            Method::Get,
+            "/api/shares/{token}/rules",
+            None,
+            [S::Ok, S::Ok, S::Ok, S::Forbidden],
+        ),
+        (
+            Method::Patch,
+            "/api/shares/{token}",
+            Some(r#"{"title":"Relaunch"}"#),
+            [S::Forbidden, S::Ok, S::Ok, S::Forbidden],
+        ),
+        (
+            Method::Put,
+            "/api/shares/{token}/rules",
+            Some("[]"),
+            [S::Forbidden, S::Ok, S::Ok, S::Forbidden],
+        ),
+        (
+            Method::Post,
+            "/api/shares/{token}/history/1/revert",
+            None,
+            [S::Forbidden, S::Ok, S::Ok, S::Forbidden],
+        ),
+        (
+            Method::Delete,
+            "/api/shares/{token}",
+            None,
+            [S::Forbidden, S::NoContent, S::NoContent, S::Forbidden],
+        ),
+        (
+            Method::Post,
+            "/api/shares/{token}/restore",
+            None,
+            [S::Forbidden, S::Ok, S::Ok, S::Forbidden],
+        ),
+        (
+            Method::Put,
+            "/api/workspaces/{id}/members/nina",
+            Some(r#"{"role":"viewer"}"#),
+            [S::Forbidden, S::Forbidden, S::Ok, S::Forbidden],
+        ),
+        (
+            Method::Delete,
+            "/api/workspaces/{id}/members/nina",
+            None,
+            [S::Forbidden, S::Forbidden, S::NoContent, S::Forbidden],
+        ),
+    ];
+    for (i, key) in ["vera-key", "eddie-key", "adam-key", "olga-key"]
+        .into_iter()
+        .enumerate()
+    {
+        let link = shorten("alice-key").into_string().unwrap();
+        let token = link.rsplit('/').next().unwrap();
+        let expected = match i {
+            0 | 3 => Status::Forbidden,
+            _ => Status::Ok,
+        };
+        assert_eq!(shorten(key).status(), expected, "{} creating a share", key);
+        for (method, path, body, statuses) in routes {
+            let path = path
+                .replace("{id}", &id.to_string())
+                .replace("{token}", token);
+            let res = request(*method, &path, key, *body);
+            assert_eq!(res.status(), statuses[i], "{} {} {}", key, method, path);
+        }
+    }
+    let res = request(
+        Method::Get,
+        &format!("/api/events?workspace={}", id),
+        "olga-key",
+        None,
+    );
+    assert_eq!(res.status(), Status::Forbidden);
+
+    // Shares in a workspace are listed with it, rather than with the shares of their owner.
+    let listing: serde_json::Value = request(Method::Get, "/api/shares", "alice-key", None)
+        .into_json()
+        .unwrap();
+    assert_eq!(listing["shares"].as_array().unwrap().len(), 0);
+    let listing: serde_json::Value = request(
+        Method::Get,
+        &format!("/api/shares?workspace={}&owner=eddie", id),
+        "vera-key",
+        None,
+    )
+    .into_json()
+    .unwrap();
+    assert_eq!(listing["shares"].as_array().unwrap().len(), 1);
+    assert_eq!(listing["shares"][0]["workspace"], id);
+
+    let workspaces: serde_json::Value = request(Method::Get, "/api/workspaces", "vera-key", None)
+        .into_json()
+        .unwrap();
+    assert_eq!(workspaces[0]["role"], "viewer");
+    let workspaces: serde_json::Value = request(Method::Get, "/api/workspaces", "olga-key", None)
+        .into_json()
+        .unwrap();
+    assert_eq!(workspaces.as_array().unwrap().len(), 0);
+}
+
+#[test]
+fn test_workspace_keeps_an_admin() {
+    use rocket::http::{ContentType, Header, Method};
+    let (client, dir) = crate::test_client();
+    let c = rusqlite::Connection::open(dir.path().join("database.db")).unwrap();
+    crate::insert_api_keys(&c, &[("alice-key", "alice", false)]);
+    let request = |method: Method, path: &str, body: Option<&str>| {
+        let mut req = client
+            .req(method, path.to_owned())
+            .header(Header::new("Authorization", "Bearer alice-key"));
+        if let Some(body) = body {
+            req = req.header(ContentType::JSON).body(body);
+        }
+        req.dispatch().status()
+    };
+    let workspace = Some(r#"{"name":"Sales"}"#);
+    assert_eq!(
+        request(Method::Post, "/api/workspaces", workspace),
+        Status::Created
+    );
+    assert_eq!(
+        request(Method::Post, "/api/workspaces", workspace),
+        Status::Conflict
+    );
+    let demote = Some(r#"{"role":"editor"}"#);
+    assert_eq!(
+        request(Method::Put, "/api/workspaces/1/members/alice", demote),
+        Status::Conflict
+    );
+    assert_eq!(
+        request(Method::Delete, "/api/workspaces/1/members/alice", None),
+        Status::Conflict
+    );
+    assert_eq!(
+        request(Method::Delete, "/api/workspaces/1/members/bob", None),
+        Status::NotFound
+    );
+    assert_eq!(
+        request(Method::Get, "/api/workspaces/2/members", None),
+        Status::NotFound
+    );
+}